@@ -0,0 +1,86 @@
+//
+// Benchmarks of the mempool tracker updates (adding transactions in batches, as the mempool
+// polling does, and removing them at once, as after a block), with various mempool sizes
+// (run with `cargo bench --features fixtures`)
+//
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+use addrindexrs::daemon::MempoolEntry;
+use addrindexrs::fixtures::transaction;
+use addrindexrs::mempool::{MempoolUpdate, Tracker};
+
+// Transaction counts of the benchmarked updates
+const TX_COUNTS: &[usize] = &[1000, 20_000];
+// Transactions added by each update (as fetched by the mempool polling)
+const BATCH_SIZE: usize = 1000;
+
+// Transactions spending distinct outputs of the same funding transaction
+fn transactions(count: usize) -> Vec<Transaction> {
+    let funding = Sha256dHash::hash(b"funding");
+    let script = Script::new();
+    (0..count)
+        .map(|vout| transaction(&[OutPoint::new(funding, vout as u32)], &[(&script, 1000)]))
+        .collect()
+}
+
+fn added(txs: &[Transaction]) -> MempoolUpdate {
+    let added = txs
+        .iter()
+        .map(|tx| (tx.clone(), MempoolEntry::new(1_000, 100)))
+        .collect();
+    MempoolUpdate::new(added, vec![])
+}
+
+fn tracker(txs: &[Transaction]) -> Tracker {
+    let mut tracker = Tracker::new();
+    for batch in added(txs).into_batches(BATCH_SIZE) {
+        tracker.apply(batch).unwrap();
+    }
+    tracker
+}
+
+fn bench_updates(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mempool_add");
+    for &count in TX_COUNTS {
+        let txs = transactions(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &txs, |b, txs| {
+            b.iter_batched(
+                || added(txs).into_batches(BATCH_SIZE),
+                |batches| {
+                    let mut tracker = Tracker::new();
+                    for batch in batches {
+                        tracker.apply(batch).unwrap();
+                    }
+                    tracker.update_histogram();
+                    tracker
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("mempool_remove");
+    for &count in TX_COUNTS {
+        let txs = transactions(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &txs, |b, txs| {
+            b.iter_batched(
+                || tracker(txs),
+                |mut tracker| {
+                    let removed = txs.iter().map(Transaction::txid).collect();
+                    tracker.apply(MempoolUpdate::new(vec![], removed)).unwrap();
+                    tracker
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_updates);
+criterion_main!(benches);
@@ -0,0 +1,161 @@
+//
+// Benchmarks of the row lookups of the queries (funding outputs and spending inputs, scanned
+// or from the spend rows, and the whole confirmed status), on the in-memory store of the tests
+// with script hashes of various transaction counts (run with `cargo bench --features fixtures`)
+//
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::collections::HashMap;
+
+use addrindexrs::fixtures::MemStore;
+use addrindexrs::index::{compute_script_hash, spend_row, TxInRow, TxOutRow, TxRow};
+use addrindexrs::query::{
+    lookup_confirmed_status, lookup_funding_outputs, lookup_indexed_spending_input,
+    lookup_spending_input, Txo,
+};
+use addrindexrs::store::WriteStore;
+use addrindexrs::util::Cancel;
+
+// Transaction counts of the benchmarked script hashes
+const TX_COUNTS: &[usize] = &[10, 100, 1000];
+// Transactions of the other script hashes (so that the lookups don't scan a tiny store)
+const OTHER_TXS: usize = 10_000;
+
+//
+// Indexed rows, and the heights of the blocks of the best chain
+//
+#[derive(Default)]
+struct Chain {
+    store: MemStore,
+    heights: HashMap<Sha256dHash, usize>,
+}
+
+impl Chain {
+    // Index a transaction (in its own block) funding `script`, and spending `spent` if any
+    fn add_tx(&mut self, seed: &str, i: usize, script: &Script, spent: Option<OutPoint>) {
+        let txid = Sha256dHash::hash(format!("{}-tx-{}", seed, i).as_bytes());
+        let blockhash = Sha256dHash::hash(format!("{}-block-{}", seed, i).as_bytes());
+        let output = TxOut {
+            value: 1000,
+            script_pubkey: script.clone(),
+        };
+        let mut rows = vec![TxOutRow::new(&txid, 0, &output).to_row()];
+        if let Some(previous_output) = spent {
+            let input = TxIn {
+                previous_output,
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            };
+            rows.push(TxInRow::new(&txid, &input).to_row());
+            rows.push(spend_row(&previous_output, &txid, &blockhash));
+        }
+        rows.push(TxRow::new(&txid, &blockhash).to_row());
+        self.store.write(rows).unwrap();
+        self.heights.insert(blockhash, self.heights.len());
+    }
+
+    // Each transaction of the script hash spends the output of the previous one
+    fn add_script_hash(&mut self, seed: &str, tx_count: usize) -> Vec<u8> {
+        let script = Script::from(Sha256dHash::hash(seed.as_bytes())[..].to_vec());
+        let mut spent = None;
+        for i in 0..tx_count {
+            self.add_tx(seed, i, &script, spent);
+            let txid = Sha256dHash::hash(format!("{}-tx-{}", seed, i).as_bytes());
+            spent = Some(OutPoint { txid, vout: 0 });
+        }
+        compute_script_hash(&script[..]).to_vec()
+    }
+
+    fn block_height(&self, blockhash: &Sha256dHash) -> Option<usize> {
+        self.heights.get(blockhash).copied()
+    }
+}
+
+fn funding_outputs(chain: &Chain, script_hash: &[u8]) -> Vec<Txo> {
+    let block_height = |blockhash: &Sha256dHash| chain.block_height(blockhash);
+    lookup_funding_outputs(&chain.store, script_hash, usize::MAX, &block_height).unwrap()
+}
+
+fn bench_lookups(c: &mut Criterion) {
+    let mut chain = Chain::default();
+    for i in 0..OTHER_TXS {
+        chain.add_script_hash(&format!("other-{}", i), 1);
+    }
+    let script_hashes: Vec<(usize, Vec<u8>)> = TX_COUNTS
+        .iter()
+        .map(|&count| (count, chain.add_script_hash(&format!("address-{}", count), count)))
+        .collect();
+    let store = &chain.store;
+
+    let mut group = c.benchmark_group("funding_outputs");
+    for (count, script_hash) in &script_hashes {
+        group.bench_with_input(BenchmarkId::from_parameter(count), script_hash, |b, sh| {
+            b.iter(|| funding_outputs(&chain, sh))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("spending_inputs");
+    for (count, script_hash) in &script_hashes {
+        let txos = funding_outputs(&chain, script_hash);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &txos, |b, txos| {
+            let block_height = |blockhash: &Sha256dHash| chain.block_height(blockhash);
+            b.iter(|| {
+                txos.iter()
+                    .filter_map(|txo| {
+                        lookup_spending_input(store, txo, usize::MAX, &block_height).unwrap()
+                    })
+                    .count()
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("indexed_spending_inputs");
+    for (count, script_hash) in &script_hashes {
+        let txos = funding_outputs(&chain, script_hash);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &txos, |b, txos| {
+            let block_height = |blockhash: &Sha256dHash| chain.block_height(blockhash);
+            b.iter(|| {
+                txos.iter()
+                    .filter_map(|txo| {
+                        lookup_indexed_spending_input(store, txo, usize::MAX, &block_height)
+                            .unwrap()
+                    })
+                    .count()
+            })
+        });
+    }
+    group.finish();
+
+    // confirmed status (as `Query::status`), with the spending inputs scanned or indexed
+    for (name, indexed_spends) in [("status", false), ("indexed_status", true)] {
+        let mut group = c.benchmark_group(name);
+        for (count, script_hash) in &script_hashes {
+            group.bench_with_input(BenchmarkId::from_parameter(count), script_hash, |b, sh| {
+                let block_height = |blockhash: &Sha256dHash| chain.block_height(blockhash);
+                let cancel = Cancel::new();
+                b.iter(|| {
+                    let status = lookup_confirmed_status(
+                        store,
+                        sh,
+                        usize::MAX,
+                        &block_height,
+                        indexed_spends,
+                        0,
+                        &cancel,
+                    );
+                    status.unwrap().1.len()
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_lookups);
+criterion_main!(benches);
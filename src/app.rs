@@ -1,16 +1,20 @@
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
 
-use crate::{daemon, errors::*, index, signal::Waiter, store};
+use crate::store::{self, WriteStore};
+use crate::{daemon, errors::*, index, signal::Waiter};
 
 //
 // Application
 //
 pub struct App {
-    store: store::DBStore,
+    store: RwLock<store::DBStore>,
     index: index::Index,
     daemon: daemon::Daemon,
     tip: Mutex<Sha256dHash>,
+    // time of the last indexed block, reset once flushed
+    unflushed_since: Mutex<Option<Instant>>,
 }
 
 impl App {
@@ -20,20 +24,21 @@ impl App {
         daemon: daemon::Daemon
     ) -> Result<Arc<App>> {
         Ok(Arc::new(App {
-            store,
+            store: RwLock::new(store),
             index,
             daemon: daemon.reconnect()?,
             tip: Mutex::new(Sha256dHash::default()),
+            unflushed_since: Mutex::new(None),
         }))
     }
 
-    fn write_store(&self) -> &impl store::WriteStore {
-        &self.store
+    fn write_store(&self) -> RwLockReadGuard<'_, store::DBStore> {
+        self.store.read().unwrap()
     }
 
     // TODO: use index for queries.
-    pub fn read_store(&self) -> &dyn store::ReadStore {
-        &self.store
+    pub fn read_store(&self) -> RwLockReadGuard<'_, store::DBStore> {
+        self.store.read().unwrap()
     }
 
     pub fn index(&self) -> &index::Index {
@@ -48,8 +53,45 @@ impl App {
         let mut tip = self.tip.lock().expect("failed to lock tip");
         let new_block = *tip != self.daemon().getbestblockhash()?;
         if new_block {
-            *tip = self.index().update(self.write_store(), &signal)?;
+            *tip = self.index().update(&*self.write_store(), &signal)?;
+            *self.unflushed_since.lock().unwrap() = Some(Instant::now());
         }
         Ok(new_block)
     }
+
+    /// Whether the indexed tip is the daemon's best block.
+    pub fn is_synced(&self) -> Result<bool> {
+        let tip = self.tip.lock().expect("failed to lock tip");
+        Ok(*tip == self.daemon().getbestblockhash()?)
+    }
+
+    /// Flushes the store once no new block has been indexed for `idle`.
+    pub fn flush_if_idle(&self, idle: Duration) -> Result<()> {
+        let mut unflushed_since = self.unflushed_since.lock().unwrap();
+        match *unflushed_since {
+            Some(t) if t.elapsed() >= idle => {
+                debug!("flushing DB after {:?} without new blocks", idle);
+                self.write_store().flush()?;
+                *unflushed_since = None;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Starts serving queries from a freshly built store (the stale one is removed).
+    pub fn swap_store(&self, store: store::DBStore) -> Result<()> {
+        let mut tip = self.tip.lock().expect("failed to lock tip");
+        let stale = {
+            let mut current = self.store.write().unwrap();
+            info!("swapping DB at {:?} with {:?}", current.path(), store.path());
+            self.index().reload(&store)?;
+            std::mem::replace(&mut *current, store)
+        };
+        let stale_path = stale.path().to_path_buf();
+        drop(stale);
+        // the new store may lag behind the stale one, force an update
+        *tip = Sha256dHash::default();
+        store::destroy(&stale_path)
+    }
 }
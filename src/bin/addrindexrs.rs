@@ -1,11 +1,15 @@
 extern crate addrindexrs;
 
+#[macro_use]
 extern crate error_chain;
 #[macro_use]
 extern crate log;
 
 use error_chain::ChainedError;
-use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::Arc;
 use std::time::Duration;
@@ -14,21 +18,146 @@ use addrindexrs::{
     app::App,
     bulk,
     cache::BlockTxIDsCache,
-    config::Config,
+    config::{Config, SyncOptions},
     daemon::Daemon,
     errors::*,
-    index::Index,
+    index::{
+        export_headers, has_spend_rows, import_headers, init_spend_rows, repair_last_indexed,
+        Index,
+    },
     query::Query,
     rpc::RPC,
     signal::Waiter,
-    store::{full_compaction, is_fully_compacted, DBStore},
+    store::{
+        destroy, full_compaction, has_current_version, is_fully_compacted, promote_reindex,
+        reindex_path, skip_full_compaction, DBStore,
+    },
+    util::{spawn_thread, Channel, HeaderEntry},
 };
 
+fn initial_sync(
+    daemon: &Daemon,
+    signal: &Waiter,
+    store: DBStore,
+    index: &Index,
+    options: SyncOptions,
+) -> Result<DBStore> {
+    let compact = |store| {
+        if options.skip_full_compaction {
+            skip_full_compaction(store)
+        } else {
+            full_compaction(store)
+        }
+    };
+    let store = if is_fully_compacted(&store)? {
+        // initial import and full compaction are over
+        store
+    } else {
+        // resume an interrupted import after its last complete block
+        if repair_last_indexed(&store)?.is_some() {
+            index.reload(&store)?;
+        }
+        let store = if options.jsonrpc_import {
+            // slower: uses JSONRPC for fetching blocks
+            index.update(&store, signal)?;
+            store
+        } else {
+            // faster, but uses more memory
+            let filter = index.script_hash_filter().clone();
+            bulk::index_blk_files(
+                daemon,
+                options.bulk_index_threads,
+                signal,
+                store,
+                filter,
+                index.optional_rows(),
+                options.verify_blk_files,
+            )?
+        };
+        // both import modes must leave the same index behind: the block header index
+        // is read back from the 'L' row, and checkpointed at the imported tip.
+        index.reload(&store)?;
+        index.write_checkpoint(&store)?;
+        compact(store)?
+    }
+    .enable_compaction()?; // enable auto compactions before starting incremental index updates.
+    Ok(store)
+}
 
+// Serve a static index (e.g. a read replica of the one updated by another indexer)
+// without writing to it: no index updates, no mempool tracking
+fn serve_read_only(config: &Config, daemon: Daemon, signal: &Waiter) -> Result<()> {
+    if config.reindex || config.import_headers.is_some() {
+        bail!("--read-only can't be used with --reindex or --import-headers");
+    }
+    let store = DBStore::open_read_only(&config.db_path)?;
+    if !has_current_version(&store)? {
+        bail!("DB at {:?} has an outdated schema, it must be reindexed", config.db_path);
+    }
+    if !is_fully_compacted(&store)? {
+        bail!("DB at {:?} isn't completely indexed yet", config.db_path);
+    }
+    if let Some(path) = &config.export_headers {
+        let count = export_headers(&store, path)?;
+        info!("exported {} headers to {:?}", count, path);
+        return Ok(());
+    }
+    let index = Index::load(
+        &store,
+        &daemon,
+        config.index_batch_size,
+        config.index_batch_bytes,
+        config.index_filters,
+        config.index_outputs,
+        config.index_script_hash_filter(),
+    )?
+    .with_witness_commitments(config.index_witness_commitments)
+    .with_spends(config.index_spends && has_spend_rows(&store)?);
+    let app = App::new(store, index, daemon)?;
+    let query = Query::new(
+        app,
+        100,
+        config.min_confirmations,
+        config.served_script_hashes.clone(),
+        /*track_mempool=*/ false,
+        config.mempool_batch_size,
+        config.max_concurrent_queries,
+        config.snapshot_reads,
+    );
+    let _server = RPC::start(config.indexer_rpc_addrs(), query, config.rpc_options());
+    loop {
+        if let Err(err) = signal.wait(Duration::from_secs(5)) {
+            info!("stopping server: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// Signals the end of the initial sync to the orchestration (e.g. waiting for the file to exist)
+// (written to a temporary file first, so that the file never exists with a partial content)
+fn write_ready_file(path: &Path, tip: &HeaderEntry) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let content = format!("{} {}\n", tip.height(), tip.hash());
+    fs::write(&tmp_path, content).chain_err(|| format!("failed to write {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).chain_err(|| format!("failed to rename {:?}", tmp_path))
+}
 
 fn run_server(config: &Config) -> Result<()> {
     let signal = Waiter::start();
-    let blocktxids_cache = Arc::new(BlockTxIDsCache::new(config.blocktxids_cache_size));
+    if let Some(path) = &config.ready_file {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                bail!("failed to remove {:?}: {}", path, e)
+            }
+            _ => (),
+        }
+    }
+    let blocktxids_cache = Arc::new(BlockTxIDsCache::new(
+        config.blocktxids_cache_size,
+        config.blocktxids_cache_entry_size,
+    ));
 
     let daemon_rpc = config.daemon_rpc_host.as_str().to_owned() + ":" + &config.daemon_rpc_port.to_string();
 
@@ -42,41 +171,148 @@ fn run_server(config: &Config) -> Result<()> {
         blocktxids_cache,
     )?;
 
+    if config.read_only {
+        return serve_read_only(config, daemon, &signal);
+    }
+
+    // Complete a reindex built while serving stale reads during a previous run.
+    promote_reindex(&config.db_path)?;
+
+    let mut store = DBStore::open(&config.db_path, config.memory_budget)?;
+    let current_version = has_current_version(&store)?;
+    if !current_version && !config.reindex {
+        bail!("DB at {:?} has an outdated schema, run with --reindex", config.db_path);
+    }
+    // the stale reads need the current schema
+    let stale_reads = config.reindex
+        && config.serve_stale_reads
+        && current_version
+        && is_fully_compacted(&store)?;
+    if config.reindex && !stale_reads {
+        drop(store);
+        destroy(&config.db_path)?;
+        store = DBStore::open(&config.db_path, config.memory_budget)?;
+    }
+
+    if let Some(path) = &config.export_headers {
+        let count = export_headers(&store, path)?;
+        info!("exported {} headers to {:?}", count, path);
+        return Ok(());
+    }
+    if let Some(path) = &config.import_headers {
+        let count = import_headers(&store, path)?;
+        info!("imported {} headers from {:?}", count, path);
+    }
+
     // Perform initial indexing from local blk*.dat block files.
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ config.jsonrpc_import);
-    let index = Index::load(&store, &daemon, config.index_batch_size)?;
+    let index = Index::load(
+        &store,
+        &daemon,
+        config.index_batch_size,
+        config.index_batch_bytes,
+        config.index_filters,
+        config.index_outputs,
+        config.index_script_hash_filter(),
+    )?
+    .with_prefetch(config.index_prefetch())
+    .with_tip_retries(config.index_tip_retries)
+    .with_witness_commitments(config.index_witness_commitments)
+    .with_spends(init_spend_rows(&store, config.index_spends)?);
+    let store = initial_sync(&daemon, &signal, store, &index, config.sync_options())?;
+    // The daemon's chain may have reorged past the indexed tip while we were stopped.
+    index.rollback_diverged(&store)?;
 
-    let store = if is_fully_compacted(&store) {
-        // initial import and full compaction are over
-        store
-    } else if config.jsonrpc_import {
-        // slower: uses JSONRPC for fetching blocks
-        index.update(&store, &signal)?;
-        full_compaction(store)
-    } else {
-        // faster, but uses more memory
-        let store = bulk::index_blk_files(&daemon, config.bulk_index_threads, &signal, store)?;
-        let store = full_compaction(store);
-        // make sure the block header index is up-to-date
-        index.reload(&store);
-        store
+    // Keep serving the existing index while a new one is built next to it.
+    let reindexed = Channel::unbounded();
+    if stale_reads {
+        let path = reindex_path(&config.db_path);
+        info!("reindexing at {:?} while serving stale reads", path);
+        let daemon = daemon.reconnect()?;
+        let signal = signal.clone();
+        let sender = reindexed.sender();
+        let (sync_options, index_batch_size, index_batch_bytes) = (
+            config.sync_options(),
+            config.index_batch_size,
+            config.index_batch_bytes,
+        );
+        let (index_filters, index_outputs) = (config.index_filters, config.index_outputs);
+        let script_hash_filter = config.index_script_hash_filter();
+        let (prefetch, tip_retries, index_witness_commitments, index_spends) = (
+            config.index_prefetch(),
+            config.index_tip_retries,
+            config.index_witness_commitments,
+            config.index_spends,
+        );
+        spawn_thread("reindex", move || {
+            let reindex = || -> Result<DBStore> {
+                let store = DBStore::open(&path, sync_options.memory_budget)?;
+                let index = Index::load(
+                    &store,
+                    &daemon,
+                    index_batch_size,
+                    index_batch_bytes,
+                    index_filters,
+                    index_outputs,
+                    script_hash_filter,
+                )?
+                .with_prefetch(prefetch)
+                .with_tip_retries(tip_retries)
+                .with_witness_commitments(index_witness_commitments)
+                .with_spends(init_spend_rows(&store, index_spends)?);
+                initial_sync(&daemon, &signal, store, &index, sync_options)
+            };
+            let _ = sender.send(reindex());
+        });
     }
-    .enable_compaction(); // enable auto compactions before starting incremental index updates.
 
     let app = App::new(store, index, daemon)?;
-    let query = Query::new(app.clone(), 100);
+    let query = Query::new(
+        app.clone(),
+        100,
+        config.min_confirmations,
+        config.served_script_hashes.clone(),
+        !config.no_mempool,
+        config.mempool_batch_size,
+        config.max_concurrent_queries,
+        config.snapshot_reads,
+    );
 
     let mut server = None; // Indexer RPC server
+    let mut synced = false;
     loop {
+        match reindexed.receiver().try_recv() {
+            Ok(Ok(store)) => app.swap_store(store)?,
+            // keep serving the existing index
+            Ok(Err(e)) => {
+                error!("reindexing failed: {}", e.display_chain());
+                destroy(&reindex_path(&config.db_path))?;
+            }
+            Err(_) => (),
+        }
         app.update(&signal)?;
-        query.update_mempool()?;
-        server.get_or_insert_with(|| {
+        if !synced && app.is_synced()? {
+            synced = true;
+            let tip = app.index().best_header().chain_err(|| "no indexed headers")?;
+            info!("indexing complete: indexed tip is {} at height {}", tip.hash(), tip.height());
+            if let Some(path) = &config.ready_file {
+                write_ready_file(path, &tip)?;
+            }
+        }
+        if let Some(idle) = config.flush_idle {
+            app.flush_if_idle(idle)?;
+        }
+        let mempool_changed = !config.no_mempool && query.update_mempool()?;
+        let server = server.get_or_insert_with(|| {
             RPC::start(
-                SocketAddr::new(IpAddr::V4(config.indexer_rpc_host), config.indexer_rpc_port),
+                config.indexer_rpc_addrs(),
                 query.clone(),
+                config.rpc_options(),
             )
         });
-        if let Err(err) = signal.wait(Duration::from_secs(5)) {
+        if mempool_changed {
+            server.notify_mempool();
+        }
+        if let Err(err) = signal.wait(config.poll_interval) {
             info!("stopping servertest: {}", err);
             process::exit(1);
         }
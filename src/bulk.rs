@@ -1,9 +1,10 @@
-use bitcoin::blockdata::block::Block;
-use bitcoin::consensus::encode::{deserialize, Decodable};
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::{deserialize, Decodable, VarInt};
 use bitcoin::util::hash::BitcoinHash;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use libc;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
@@ -15,10 +16,13 @@ use std::thread;
 
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::index::{index_block, last_indexed_block, read_indexed_blockhashes};
+use crate::index::{
+    index_block, last_indexed_block, output_rows, read_imported_headers, read_indexed_blockhashes,
+    spend_rows, OptionalRows, ScriptHashFilter,
+};
 use crate::signal::Waiter;
 use crate::store::{DBStore, Row, WriteStore};
-use crate::util::{spawn_thread, HeaderList, SyncChannel};
+use crate::util::{spawn_thread, HeaderEntry, HeaderList, SyncChannel};
 
 //
 // Blockchain parser (bulk mode)
@@ -27,17 +31,36 @@ struct Parser {
     magic: u32,
     current_headers: HeaderList,
     indexed_blockhashes: Mutex<HashSet<Sha256dHash>>,
+    script_hash_filter: ScriptHashFilter,
+    optional_rows: OptionalRows,
+    // used for refetching corrupted blocks (if verification is enabled)
+    verifier: Option<Daemon>,
+    signal: Waiter,
 }
 
 impl Parser {
     fn new(
         daemon: &Daemon,
         indexed_blockhashes: HashSet<Sha256dHash>,
+        imported_headers: HeaderList,
+        script_hash_filter: ScriptHashFilter,
+        optional_rows: OptionalRows,
+        verify: bool,
+        signal: &Waiter,
     ) -> Result<Arc<Parser>> {
+        let verifier = if verify {
+            Some(daemon.reconnect()?)
+        } else {
+            None
+        };
         Ok(Arc::new(Parser {
             magic: daemon.magic(),
-            current_headers: load_headers(daemon)?,
+            current_headers: load_headers(daemon, imported_headers)?,
             indexed_blockhashes: Mutex::new(indexed_blockhashes),
+            script_hash_filter,
+            optional_rows,
+            verifier,
+            signal: signal.clone(),
         }))
     }
 
@@ -59,19 +82,48 @@ impl Parser {
         Ok(blob)
     }
 
+    // Checks that the block links to the header chain and matches its Merkle root
+    fn is_valid(&self, block: &Block, header: &HeaderEntry) -> bool {
+        let expected_prev = match header.height() {
+            0 => Sha256dHash::default(),
+            height => *self
+                .current_headers
+                .header_by_height(height - 1)
+                .expect("missing previous header")
+                .hash(),
+        };
+        block.header.prev_blockhash == expected_prev && block.check_merkle_root()
+    }
+
     fn index_blkfile(&self, blob: Vec<u8>) -> Result<Vec<Row>> {
-        let blocks = parse_blocks(blob, self.magic)?;
+        let blocks = parse_blocks(blob, self.magic, &self.signal)?;
 
         let mut rows = Vec::<Row>::new();
-        for block in blocks {
+        for mut block in blocks {
             let blockhash = block.bitcoin_hash();
-            if let Some(_header) = self.current_headers.header_by_blockhash(&blockhash) {
+            if let Some(header) = self.current_headers.header_by_blockhash(&blockhash) {
+                if let Some(daemon) = &self.verifier {
+                    if !self.is_valid(&block, header) {
+                        warn!(
+                            "invalid block {} at height {}, refetching it",
+                            blockhash,
+                            header.height()
+                        );
+                        block = daemon.getblock(&blockhash)?;
+                    }
+                }
                 if self.indexed_blockhashes
                     .lock()
                     .expect("indexed_blockhashes")
                     .insert(blockhash)
                 {
-                    rows.extend(index_block(&block));
+                    rows.extend(index_block(&block, &self.script_hash_filter));
+                    if self.optional_rows.outputs {
+                        rows.extend(output_rows(&block, &self.script_hash_filter));
+                    }
+                    if self.optional_rows.spends {
+                        rows.extend(spend_rows(&block));
+                    }
                 }
             }
         }
@@ -84,7 +136,8 @@ impl Parser {
 //
 // Parse the bitcoin blocks
 //
-fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
+// (the signal is polled before parsing each block, not only once per blk*.dat file)
+fn parse_blocks(blob: Vec<u8>, magic: u32, signal: &Waiter) -> Result<Vec<Block>> {
     let mut cursor = Cursor::new(&blob);
     let mut blocks = vec![];
     let max_pos = blob.len() as u64;
@@ -105,6 +158,10 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
         let block_size = u32::consensus_decode(&mut cursor).chain_err(|| "no block size")?;
         let start = cursor.position();
         let end = start + block_size as u64;
+        if end > max_pos {
+            warn!("skipping truncated block at {}..{} ({} bytes available)", start, end, max_pos);
+            break;
+        }
 
         // If Core's WriteBlockToDisk ftell fails, only the magic bytes and size will be written
         // and the block body won't be written to the blk*.dat file.
@@ -120,22 +177,55 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
             Err(_) => break, // EOF
         }
 
-        let block: Block = deserialize(&blob[start as usize..end as usize])
-            .chain_err(|| format!("failed to parse block at {}..{}", start, end))?;
-
-        blocks.push(block);
+        signal
+            .poll()
+            .chain_err(|| "stopping bulk indexing due to signal")?;
+        // a malformed block is skipped (it will be fetched via JSONRPC after the bulk import)
+        match deserialize::<Block>(&blob[start as usize..end as usize]) {
+            Ok(block) => blocks.push(block),
+            Err(err) => warn!(
+                "skipping unparseable {}: {}",
+                describe_block(&blob[start as usize..end as usize]),
+                err
+            ),
+        }
         cursor.set_position(end as u64);
     }
 
     Ok(blocks)
 }
 
+// Hash of a block that failed to parse, and its first unparseable transaction
+// (its header and preceding transactions are parsed separately)
+fn describe_block(raw: &[u8]) -> String {
+    let mut cursor = Cursor::new(raw);
+    let blockhash = match BlockHeader::consensus_decode(&mut cursor) {
+        Ok(header) => header.bitcoin_hash(),
+        Err(_) => return "block with an unparseable header".to_string(),
+    };
+    let count = match VarInt::consensus_decode(&mut cursor) {
+        Ok(count) => count.0,
+        Err(_) => return format!("block {} (unparseable transactions count)", blockhash),
+    };
+    let mut last_txid = None;
+    for index in 0..count {
+        match Transaction::consensus_decode(&mut cursor) {
+            Ok(txn) => last_txid = Some(txn.txid()),
+            Err(_) => {
+                let after = last_txid.map_or(String::new(), |txid| format!(", after {}", txid));
+                return format!("block {} (transaction #{}{})", blockhash, index, after);
+            }
+        }
+    }
+    format!("block {}", blockhash)
+}
+
 //
 // Retrieve the block headers
 //
-fn load_headers(daemon: &Daemon) -> Result<HeaderList> {
+// (only the headers missing from the imported ones are downloaded)
+fn load_headers(daemon: &Daemon, mut headers: HeaderList) -> Result<HeaderList> {
     let tip = daemon.getbestblockhash()?;
-    let mut headers = HeaderList::empty();
     let new_headers = headers.order(daemon.get_new_headers(&headers, &tip)?);
     headers.apply(new_headers, tip);
     Ok(headers)
@@ -163,43 +253,85 @@ fn set_open_files_limit(limit: libc::rlim_t) {
 
 
 type JoinHandle = thread::JoinHandle<Result<()>>;
-type BlobReceiver = Arc<Mutex<Receiver<(Vec<u8>, PathBuf)>>>;
+type BlobReceiver = Arc<Mutex<Receiver<(usize, Vec<u8>, PathBuf)>>>;
+type RowsSender = SyncSender<Result<(usize, Vec<Row>, PathBuf)>>;
 
 //
-// 
+// Reader of the blk*.dat files
+// (at most `window` files are read ahead of the last written one, and it stops early if
+// the writer or the indexers are gone, e.g. after a failure)
 //
-fn start_reader(blk_files: Vec<PathBuf>, parser: Arc<Parser>) -> (BlobReceiver, JoinHandle) {
+fn start_reader(
+    blk_files: Vec<PathBuf>,
+    parser: Arc<Parser>,
+    window: SyncSender<()>,
+) -> (BlobReceiver, JoinHandle) {
     let chan = SyncChannel::new(0);
     let blobs = chan.sender();
     let handle = spawn_thread("bulk_read", move || -> Result<()> {
-        for path in blk_files {
-            blobs
-                .send((parser.read_blkfile(&path)?, path))
-                .expect("failed to send blk*.dat contents");
+        for (index, path) in blk_files.into_iter().enumerate() {
+            if window.send(()).is_err() {
+                break; // the writer stopped
+            }
+            let blob = parser.read_blkfile(&path)?;
+            if blobs.send((index, blob, path)).is_err() {
+                break; // the indexers stopped
+            }
         }
         Ok(())
     });
     (Arc::new(Mutex::new(chan.into_receiver())), handle)
 }
 
+//
+// Buffer of the indexed blk*.dat files, releasing them in the files' order
+// (so that the blocks are written in the same order, whichever indexer is faster)
+//
+struct Reorder<T> {
+    next: usize,
+    pending: BTreeMap<usize, T>,
+}
+
+impl<T> Reorder<T> {
+    fn new() -> Reorder<T> {
+        Reorder {
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    // The items that can be released after the one at `index`
+    fn push(&mut self, index: usize, item: T) -> Vec<T> {
+        self.pending.insert(index, item);
+        let mut ready = vec![];
+        while let Some(item) = self.pending.remove(&self.next) {
+            ready.push(item);
+            self.next += 1;
+        }
+        ready
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
 //
 // Bulk indexing of blocks
+// (a failure is sent to the writer, so that it stops instead of waiting for the file)
 //
-fn start_indexer(
-    blobs: BlobReceiver,
-    parser: Arc<Parser>,
-    writer: SyncSender<(Vec<Row>, PathBuf)>,
-) -> JoinHandle {
+fn start_indexer(blobs: BlobReceiver, parser: Arc<Parser>, writer: RowsSender) -> JoinHandle {
     spawn_thread("bulk_index", move || -> Result<()> {
         loop {
             let msg = blobs.lock().unwrap().recv();
-            if let Ok((blob, path)) = msg {
+            if let Ok((index, blob, path)) = msg {
                 let rows = parser
                     .index_blkfile(blob)
-                    .chain_err(|| format!("failed to index {:?}", path))?;
-                writer
-                    .send((rows, path))
-                    .expect("failed to send indexed rows")
+                    .chain_err(|| format!("failed to index {:?}", path));
+                let failed = rows.is_err();
+                if writer.send(rows.map(|rows| (index, rows, path))).is_err() || failed {
+                    break; // the writer stopped
+                }
             } else {
                 debug!("no more blocks to index");
                 break;
@@ -217,6 +349,9 @@ pub fn index_blk_files(
     index_threads: usize,
     signal: &Waiter,
     store: DBStore,
+    script_hash_filter: ScriptHashFilter,
+    optional_rows: OptionalRows,
+    verify: bool,
 ) -> Result<DBStore> {
 
     set_open_files_limit(2048); // twice the default `ulimit -n` value
@@ -224,40 +359,56 @@ pub fn index_blk_files(
     let blk_files = daemon.list_blk_files()?;
     info!("indexing {} blk*.dat files", blk_files.len());
 
-    let indexed_blockhashes = read_indexed_blockhashes(&store);
+    let indexed_blockhashes = read_indexed_blockhashes(&store)?;
     debug!("found {} indexed blocks", indexed_blockhashes.len());
 
-    let parser = Parser::new(daemon, indexed_blockhashes)?;
-    let (blobs, reader) = start_reader(blk_files, parser.clone());
+    let imported_headers = read_imported_headers(&store)?;
+    let parser = Parser::new(
+        daemon,
+        indexed_blockhashes,
+        imported_headers,
+        script_hash_filter,
+        optional_rows,
+        verify,
+        signal,
+    )?;
+    // the files indexed ahead of a slower one wait for it (up to twice the indexers' count)
+    let window = SyncChannel::new(2 * index_threads.max(1));
+    let (blobs, reader) = start_reader(blk_files, parser.clone(), window.sender());
     let rows_chan = SyncChannel::new(0);
 
     let indexers: Vec<JoinHandle> = (0..index_threads)
         .map(|_| start_indexer(blobs.clone(), parser.clone(), rows_chan.sender()))
         .collect();
+    drop(blobs); // so that the reader stops once all the indexers are gone
 
     let signal = signal.clone();
 
     spawn_thread("bulk_writer", move || -> Result<DBStore> {
-        for (rows, path) in rows_chan.into_receiver() {
-            trace!("indexed {:?}: {} rows", path, rows.len());
-            store.write(rows);
+        let mut reorder = Reorder::new();
+        for indexed in rows_chan.into_receiver() {
+            let (index, rows, path) = indexed?; // the other threads stop once it's dropped
+            for (rows, path) in reorder.push(index, (rows, path)) {
+                trace!("indexed {:?}: {} rows", path, rows.len());
+                store.write(rows)?;
+                window.receiver().recv().expect("missing blk*.dat slot");
+            }
             signal
                 .poll()
                 .chain_err(|| "stopping bulk indexing due to signal")?;
         }
-
         reader
             .join()
             .expect("reader panicked")
-            .expect("reader failed");
-
-        indexers.into_iter().for_each(|i| {
-            i.join()
-                .expect("indexer panicked")
-                .expect("indexing failed")
-        });
+            .chain_err(|| "failed to read blk*.dat files")?;
+        for indexer in indexers {
+            indexer.join().expect("indexer panicked")?;
+        }
+        if !reorder.is_empty() {
+            bail!("some blk*.dat files were not written");
+        }
 
-        store.write(vec![parser.last_indexed_row()]);
+        store.write(vec![parser.last_indexed_row()])?;
         Ok(store)
     })
     .join()
@@ -275,7 +426,7 @@ mod tests {
     fn test_incomplete_block_parsing() {
         let magic = 0x0709110b;
         let raw_blocks = hex_decode(fixture("incomplete_block.hex")).unwrap();
-        let blocks = parse_blocks(raw_blocks, magic).unwrap();
+        let blocks = parse_blocks(raw_blocks, magic, &Waiter::start()).unwrap();
         assert_eq!(blocks.len(), 2);
         assert_eq!(
             blocks[1].bitcoin_hash().into_inner().to_vec(),
@@ -283,6 +434,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_malformed_block_parsing() {
+        let magic: u32 = 0x0709110b;
+        let mut raw_blocks = magic.to_le_bytes().to_vec();
+        raw_blocks.extend(&8u32.to_le_bytes());
+        raw_blocks.extend(&[0xff; 8]); // not a valid block
+        raw_blocks.extend(hex_decode(fixture("incomplete_block.hex")).unwrap());
+        let blocks = parse_blocks(raw_blocks, magic, &Waiter::start()).unwrap();
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_describe_block() {
+        use crate::fixtures::{chain, transaction};
+        use bitcoin::blockdata::script::Script;
+        use bitcoin::consensus::encode::serialize;
+
+        let script = Script::new();
+        let txdata = vec![transaction(&[], &[(&script, 1)]), transaction(&[], &[(&script, 2)])];
+        let block = &chain(vec![txdata])[0];
+        let blockhash = block.bitcoin_hash();
+        let raw = serialize(block);
+        assert_eq!(describe_block(&raw), format!("block {}", blockhash));
+
+        let truncated = &raw[..raw.len() - 1]; // the second transaction is cut
+        assert_eq!(
+            describe_block(truncated),
+            format!("block {} (transaction #1, after {})", blockhash, block.txdata[0].txid())
+        );
+        assert_eq!(describe_block(&raw[..40]), "block with an unparseable header");
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut reorder = Reorder::new();
+        assert_eq!(reorder.push(1, "b"), Vec::<&str>::new());
+        assert_eq!(reorder.push(2, "c"), Vec::<&str>::new());
+        assert_eq!(reorder.push(0, "a"), vec!["a", "b", "c"]);
+        assert_eq!(reorder.push(3, "d"), vec!["d"]);
+        assert!(reorder.is_empty());
+    }
+
     pub fn fixture(filename: &str) -> String {
         let path = Path::new("src")
             .join("tests")
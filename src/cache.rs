@@ -12,14 +12,16 @@ struct SizedLruCache<K, V> {
     map: LruCache<K, (V, usize)>,
     bytes_usage: usize,
     bytes_capacity: usize,
+    max_entry_size: usize, // larger entries aren't cached
 }
 
 impl<K: Hash + Eq, V> SizedLruCache<K, V> {
-    fn new(bytes_capacity: usize) -> SizedLruCache<K, V> {
+    fn new(bytes_capacity: usize, max_entry_size: usize) -> SizedLruCache<K, V> {
         SizedLruCache {
             map: LruCache::unbounded(),
             bytes_usage: 0,
             bytes_capacity,
+            max_entry_size,
         }
     }
 
@@ -31,7 +33,7 @@ impl<K: Hash + Eq, V> SizedLruCache<K, V> {
     }
 
     fn put(&mut self, key: K, value: V, byte_size: usize) {
-        if byte_size > self.bytes_capacity {
+        if byte_size > self.bytes_capacity || byte_size > self.max_entry_size {
             return;
         }
         if let Some((_, popped_size)) = self.map.put(key, (value, byte_size)) {
@@ -56,9 +58,9 @@ pub struct BlockTxIDsCache {
 }
 
 impl BlockTxIDsCache {
-    pub fn new(bytes_capacity: usize) -> BlockTxIDsCache {
+    pub fn new(bytes_capacity: usize, max_entry_size: usize) -> BlockTxIDsCache {
         BlockTxIDsCache {
-            map: Mutex::new(SizedLruCache::new(bytes_capacity)),
+            map: Mutex::new(SizedLruCache::new(bytes_capacity, max_entry_size)),
         }
     }
 
@@ -91,7 +93,7 @@ mod tests {
 
     #[test]
     fn test_sized_lru_cache_hit_and_miss() {
-        let mut cache = SizedLruCache::<i8, i32>::new(100,);
+        let mut cache = SizedLruCache::<i8, i32>::new(100, 60);
 
         assert_eq!(cache.get(&1), None); // no such key
 
@@ -114,6 +116,11 @@ mod tests {
         assert_eq!(cache.get(&2), Some(&20));
         assert_eq!(cache.get(&3), Some(&33));
         assert_eq!(cache.get(&9), None);
+
+        cache.put(8, 80, 70); // larger than max entry size, don't drop the cache
+        assert_eq!(cache.get(&2), Some(&20));
+        assert_eq!(cache.get(&3), Some(&33));
+        assert_eq!(cache.get(&8), None);
     }
 
     fn gen_hash(seed: u8) -> Sha256dHash {
@@ -135,7 +142,7 @@ mod tests {
         };
 
         // 200 bytes ~ 32 (bytes/hash) * (1 key hash + 2 value hashes) * 2 txns
-        let cache = BlockTxIDsCache::new(200);
+        let cache = BlockTxIDsCache::new(200, 200);
 
         // cache miss
         let result = cache.get_or_else(&block1, &miss_func).unwrap();
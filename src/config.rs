@@ -2,17 +2,22 @@ use bitcoin::network::constants::Network;
 use dirs::home_dir;
 use num_cpus;
 use std::convert::TryInto;
+use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs;
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{LevelFilter, Log, Metadata, Record};
 use stderrlog;
 
 use crate::daemon::CookieGetter;
 use crate::errors::*;
+use crate::index::ScriptHashFilter;
+use crate::rpc;
 
 //
 // Default IP address of the RPC server
@@ -85,6 +90,74 @@ impl ::configure_me::parse_arg::ParseArgFromStr for BitcoinNetwork {
     }
 }
 
+impl FromStr for rpc::ErrorDetails {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "full" => Ok(rpc::ErrorDetails::Full),
+            "message" => Ok(rpc::ErrorDetails::Message),
+            "none" => Ok(rpc::ErrorDetails::None),
+            _ => Err(format!("unknown error details level {}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for rpc::ErrorDetails {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> std::fmt::Result {
+        write!(writer, "either 'full', 'message' or 'none'")
+    }
+}
+
+impl FromStr for rpc::TlsReply {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "none" => Ok(rpc::TlsReply::None),
+            "alert" => Ok(rpc::TlsReply::Alert),
+            "error" => Ok(rpc::TlsReply::Error),
+            _ => Err(format!("unknown TLS handshake reply {}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for rpc::TlsReply {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> std::fmt::Result {
+        write!(writer, "either 'none', 'alert' or 'error'")
+    }
+}
+
+//
+// Format of the timestamp prepended to the log lines
+//
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampFormat {
+    None,
+    Rfc3339,     // e.g. 2024-01-14T15:11:50.781+00:00
+    EpochMillis, // milliseconds since the UNIX epoch
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "none" => Ok(TimestampFormat::None),
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "epoch-millis" => Ok(TimestampFormat::EpochMillis),
+            _ => Err(format!("unknown timestamp format {}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for TimestampFormat {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> std::fmt::Result {
+        write!(writer, "either 'none', 'rfc3339' or 'epoch-millis'")
+    }
+}
+
 impl Into<Network> for BitcoinNetwork {
     fn into(self) -> Network {
         self.0
@@ -98,18 +171,96 @@ impl Into<Network> for BitcoinNetwork {
 pub struct Config {
     // See below for the documentation of each field:
     pub log: stderrlog::StdErrLog,
+    pub instance_name: Option<String>,
     pub network_type: Network,
     pub db_path: PathBuf,
     pub daemon_dir: PathBuf,
     pub daemon_rpc_host: String,
     pub daemon_rpc_port: u16,
     pub cookie: Option<String>,
+    pub cookie_env: Option<String>,
+    pub cookie_file: Option<PathBuf>,
     pub indexer_rpc_host: Ipv4Addr,
     pub indexer_rpc_port: u16,
+    pub indexer_rpc_extra_addr: Option<SocketAddr>,
     pub jsonrpc_import: bool,
     pub index_batch_size: usize,
+    pub index_batch_bytes: Option<usize>,
+    pub index_tip_retries: usize,
+    pub memory_budget: Option<usize>,
     pub bulk_index_threads: usize,
+    pub verify_blk_files: bool,
+    pub skip_full_compaction: bool,
     pub blocktxids_cache_size: usize,
+    pub blocktxids_cache_entry_size: usize,
+    pub export_headers: Option<PathBuf>,
+    pub import_headers: Option<PathBuf>,
+    pub ready_file: Option<PathBuf>,
+    pub reindex: bool,
+    pub read_only: bool,
+    pub serve_stale_reads: bool,
+    pub min_confirmations: usize,
+    pub no_mempool: bool,
+    pub mempool_batch_size: usize,
+    pub poll_interval: Duration,
+    pub flush_idle: Option<Duration>,
+    pub rpc_idle_timeout: Option<Duration>,
+    pub rpc_threads: usize,
+    pub max_subscriptions: usize,
+    pub max_block_headers: usize,
+    pub max_concurrent_queries: Option<usize>,
+    pub snapshot_reads: bool,
+    pub disconnect_on_unknown_method: bool,
+    pub disconnect_on_bad_request: bool,
+    pub rpc_error_codes: bool,
+    pub rpc_error_details: rpc::ErrorDetails,
+    pub rpc_tls_reply: rpc::TlsReply,
+    pub index_filters: bool,
+    pub index_outputs: bool,
+    pub index_witness_commitments: bool,
+    pub index_spends: bool,
+    pub served_script_hashes: ScriptHashFilter,
+    pub index_served_script_hashes_only: bool,
+}
+
+//
+// Options of the initial index import
+//
+#[derive(Clone, Copy, Debug)]
+pub struct SyncOptions {
+    pub jsonrpc_import: bool,
+    pub memory_budget: Option<usize>,
+    pub bulk_index_threads: usize,
+    pub verify_blk_files: bool,
+    pub skip_full_compaction: bool,
+}
+
+/// Parses a comma-separated list of hex strings (exits on failure)
+fn parse_hex_list(value: &str) -> Vec<Vec<u8>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            hex::decode(item).unwrap_or_else(|err| {
+                eprintln!("Error: invalid hex string {:?}: {}", item, err);
+                std::process::exit(1)
+            })
+        })
+        .collect()
+}
+
+/// Parses a file of hex strings, one per line, ignoring the '#' comments (exits on failure)
+fn read_hex_file(path: &Path) -> Vec<Vec<u8>> {
+    let contents = fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("Error: failed to read {:?}: {}", path, err);
+        std::process::exit(1)
+    });
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap())
+        .flat_map(parse_hex_list)
+        .collect()
 }
 
 /// Returns default daemon directory
@@ -122,6 +273,16 @@ fn default_daemon_dir() -> PathBuf {
     home
 }
 
+/// Returns the subdirectory of the daemon directory used by the network
+/// (where bitcoind keeps its blocks and cookie)
+pub fn network_daemon_dir(daemon_dir: &Path, network: Network) -> PathBuf {
+    match network {
+        Network::Bitcoin => daemon_dir.to_path_buf(),
+        Network::Testnet => daemon_dir.join("testnet3"),
+        Network::Regtest => daemon_dir.join("regtest"),
+    }
+}
+
 impl Config {
     /// Parses args, env vars, config files and post-processes them
     pub fn from_args() -> Config {
@@ -173,11 +334,7 @@ impl Config {
             .unwrap_or(DEFAULT_SERVER_ADDRESS.into());
         let indexer_rpc_port = config.indexer_rpc_port.unwrap_or(default_indexer_port);
 
-        match config.network {
-            Network::Bitcoin => (),
-            Network::Testnet => config.daemon_dir.push("testnet3"),
-            Network::Regtest => config.daemon_dir.push("regtest"),
-        }
+        config.daemon_dir = network_daemon_dir(&config.daemon_dir, config.network);
 
         let mut log = stderrlog::new();
         log.verbosity(
@@ -187,13 +344,34 @@ impl Config {
                 .expect("Overflow: Running addrindexrs on less than 32 bit devices is unsupported"),
         );
 
-        log.timestamp(if config.timestamp {
-            stderrlog::Timestamp::Millisecond
-        } else {
-            stderrlog::Timestamp::Off
+        let timestamp_format = match (config.timestamp_format, config.timestamp) {
+            (Some(format), _) => format,
+            (None, true) => TimestampFormat::Rfc3339,
+            (None, false) => TimestampFormat::None,
+        };
+        log.timestamp(match timestamp_format {
+            TimestampFormat::Rfc3339 => stderrlog::Timestamp::Millisecond,
+            TimestampFormat::None | TimestampFormat::EpochMillis => stderrlog::Timestamp::Off,
         });
 
-        log.init().unwrap_or_else(|err| {
+        let epoch_millis = timestamp_format == TimestampFormat::EpochMillis;
+        let init = if config.instance_name.is_some() || epoch_millis {
+            log::set_max_level(match config.verbose {
+                0 => LevelFilter::Error,
+                1 => LevelFilter::Warn,
+                2 => LevelFilter::Info,
+                3 => LevelFilter::Debug,
+                _ => LevelFilter::Trace,
+            });
+            log::set_boxed_logger(Box::new(CustomLog {
+                name: config.instance_name.clone(),
+                epoch_millis,
+                inner: log.clone(),
+            }))
+        } else {
+            log.init()
+        };
+        init.unwrap_or_else(|err| {
             eprintln!("Error: logging initialization failed: {}", err);
             std::process::exit(1)
         });
@@ -204,10 +382,53 @@ impl Config {
             config.bulk_index_threads = num_cpus::get();
         }
 
+        let mut served_prefixes = config
+            .served_script_hash_prefixes
+            .as_ref()
+            .map(|prefixes| parse_hex_list(prefixes))
+            .unwrap_or_default();
+        if let Some(path) = &config.served_script_hash_prefixes_file {
+            served_prefixes.extend(read_hex_file(path));
+        }
+        let served_script_hashes = ScriptHashFilter::new(served_prefixes);
+
+        let cookie_sources = [
+            config.cookie.is_some(),
+            config.cookie_env.is_some(),
+            config.cookie_file.is_some(),
+        ];
+        if cookie_sources.iter().filter(|&&set| set).count() > 1 {
+            eprintln!("Error: only one of --cookie, --cookie-env and --cookie-file can be used");
+            std::process::exit(1)
+        }
+
+        // the filters need the spent outputs, which are only fetched by the JSONRPC import
+        if config.index_filters && !config.jsonrpc_import {
+            eprintln!("Error: --index-filters can only be used with --jsonrpc-import");
+            std::process::exit(1)
+        }
+
         const MB: f32 = (1 << 20) as f32;
 
+        // Split of a memory budget: a quarter for the DB buffers, a quarter for the two
+        // JSONRPC batches in memory (the fetched and the indexed ones), and half for the
+        // bulk indexing threads (each one with up to two 128MB blk*.dat files and their rows)
+        let memory_budget = match (config.memory_budget_mb * MB) as usize {
+            0 => None,
+            bytes => Some(bytes),
+        };
+        let mut index_batch_bytes = match (config.index_batch_size_mb * MB) as usize {
+            0 => None,
+            bytes => Some(bytes),
+        };
+        if let Some(budget) = memory_budget {
+            config.bulk_index_threads = config.bulk_index_threads.min(budget >> 30).max(1);
+            index_batch_bytes = Some(index_batch_bytes.unwrap_or(usize::MAX).min(budget / 8));
+        }
+
         let config = Config {
             log,
+            instance_name: config.instance_name,
             network_type: config.network,
             db_path: config.db_dir,
             daemon_dir: config.daemon_dir,
@@ -215,25 +436,127 @@ impl Config {
             daemon_rpc_port,
             indexer_rpc_host,
             indexer_rpc_port,
+            indexer_rpc_extra_addr: config.indexer_rpc_extra_addr,
             cookie: config.cookie,
+            cookie_env: config.cookie_env,
+            cookie_file: config.cookie_file,
             jsonrpc_import: config.jsonrpc_import,
             index_batch_size: config.index_batch_size,
+            index_batch_bytes,
+            index_tip_retries: config.index_tip_retries,
+            memory_budget,
             bulk_index_threads: config.bulk_index_threads,
+            verify_blk_files: config.verify_blk_files,
+            skip_full_compaction: config.skip_full_compaction,
             blocktxids_cache_size: (config.blocktxids_cache_size_mb * MB) as usize,
+            blocktxids_cache_entry_size: (config.blocktxids_cache_entry_size_mb * MB) as usize,
+            export_headers: config.export_headers,
+            import_headers: config.import_headers,
+            ready_file: config.ready_file,
+            reindex: config.reindex,
+            read_only: config.read_only,
+            serve_stale_reads: config.serve_stale_reads,
+            min_confirmations: config.min_confirmations,
+            no_mempool: config.no_mempool,
+            mempool_batch_size: config.mempool_batch_size,
+            poll_interval: Duration::from_secs_f32(config.poll_interval_secs.max(0.1)),
+            flush_idle: match config.flush_idle_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            rpc_idle_timeout: match config.rpc_idle_timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            rpc_threads: config.rpc_threads,
+            max_subscriptions: config.max_subscriptions,
+            max_block_headers: config.max_block_headers,
+            max_concurrent_queries: match config.max_concurrent_queries {
+                0 => None,
+                count => Some(count),
+            },
+            snapshot_reads: config.snapshot_reads,
+            disconnect_on_unknown_method: config.disconnect_on_unknown_method,
+            disconnect_on_bad_request: config.disconnect_on_bad_request,
+            rpc_error_codes: config.rpc_error_codes,
+            rpc_error_details: config.rpc_error_details,
+            rpc_tls_reply: config.rpc_tls_reply,
+            index_filters: config.index_filters,
+            index_outputs: config.index_outputs,
+            index_witness_commitments: config.index_witness_commitments,
+            index_spends: config.index_spends,
+            served_script_hashes,
+            index_served_script_hashes_only: config.index_served_script_hashes_only,
         };
 
         eprintln!("{:#?}", config);
         config
     }
 
+    // Addresses the indexer RPC server listens on
+    pub fn indexer_rpc_addrs(&self) -> Vec<SocketAddr> {
+        let main = SocketAddr::new(IpAddr::V4(self.indexer_rpc_host), self.indexer_rpc_port);
+        std::iter::once(main)
+            .chain(self.indexer_rpc_extra_addr)
+            .collect()
+    }
+
+    pub fn sync_options(&self) -> SyncOptions {
+        SyncOptions {
+            jsonrpc_import: self.jsonrpc_import,
+            memory_budget: self.memory_budget,
+            bulk_index_threads: self.bulk_index_threads,
+            verify_blk_files: self.verify_blk_files,
+            skip_full_compaction: self.skip_full_compaction,
+        }
+    }
+
+    // Batches of blocks fetched ahead of the indexed one (none within a memory budget)
+    pub fn index_prefetch(&self) -> usize {
+        match self.memory_budget {
+            Some(_) => 0,
+            None => 1,
+        }
+    }
+
+    pub fn rpc_options(&self) -> rpc::Options {
+        rpc::Options {
+            threads: self.rpc_threads,
+            disconnect_on_unknown_method: self.disconnect_on_unknown_method,
+            disconnect_on_bad_request: self.disconnect_on_bad_request,
+            instance_name: self.instance_name.clone(),
+            max_subscriptions: self.max_subscriptions,
+            max_headers: self.max_block_headers,
+            rpc_error_codes: self.rpc_error_codes,
+            error_details: self.rpc_error_details,
+            tls_reply: self.rpc_tls_reply,
+            idle_timeout: self.rpc_idle_timeout,
+        }
+    }
+
+    // Filter of the script hashes to index
+    pub fn index_script_hash_filter(&self) -> ScriptHashFilter {
+        if self.index_served_script_hashes_only {
+            self.served_script_hashes.clone()
+        } else {
+            ScriptHashFilter::default()
+        }
+    }
+
+    // The one of --cookie, --cookie-env and --cookie-file (or else bitcoind's cookie file)
     pub fn cookie_getter(&self) -> Arc<dyn CookieGetter> {
         if let Some(ref value) = self.cookie {
             Arc::new(StaticCookie {
                 value: value.as_bytes().to_vec(),
             })
+        } else if let Some(ref name) = self.cookie_env {
+            Arc::new(EnvCookie { name: name.clone() })
         } else {
             Arc::new(CookieFile {
-                daemon_dir: self.daemon_dir.clone(),
+                path: match self.cookie_file {
+                    Some(ref path) => path.clone(),
+                    None => self.daemon_dir.join(".cookie"),
+                },
             })
         }
     }
@@ -252,16 +575,139 @@ impl CookieGetter for StaticCookie {
     }
 }
 
+// (re-read on each connection, since it may change, e.g. when bitcoind restarts)
 struct CookieFile {
-    daemon_dir: PathBuf,
+    path: PathBuf,
 }
 
 impl CookieGetter for CookieFile {
     fn get(&self) -> Result<Vec<u8>> {
-        let path = self.daemon_dir.join(".cookie");
-        let contents = fs::read(&path).chain_err(|| {
-            ErrorKind::Connection(format!("failed to read cookie from {:?}", path))
+        let contents = fs::read(&self.path).chain_err(|| {
+            ErrorKind::Connection(format!("failed to read cookie from {:?}", self.path))
         })?;
-        Ok(contents)
+        Ok(trim_cookie(&contents).to_vec())
+    }
+}
+
+// Drops the trailing whitespace of a cookie (e.g. of a secrets file ending with a newline)
+fn trim_cookie(value: &[u8]) -> &[u8] {
+    let len = value
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |last| last + 1);
+    &value[..len]
+}
+
+struct EnvCookie {
+    name: String,
+}
+
+impl CookieGetter for EnvCookie {
+    fn get(&self) -> Result<Vec<u8>> {
+        let value = env::var(&self.name).chain_err(|| {
+            ErrorKind::Connection(format!("failed to read cookie from ${}", self.name))
+        })?;
+        Ok(trim_cookie(value.as_bytes()).to_vec())
+    }
+}
+
+//
+// Logger prepending the instance name to the log lines
+// (and writing them with an epoch timestamp, which isn't supported by stderrlog)
+//
+struct CustomLog {
+    name: Option<String>,
+    epoch_millis: bool,
+    inner: stderrlog::StdErrLog,
+}
+
+impl Log for CustomLog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let prefix = match &self.name {
+            Some(name) => format!("[{}] ", name),
+            None => String::new(),
+        };
+        if self.epoch_millis {
+            let millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis())
+                .unwrap_or(0);
+            eprintln!("{} - {} - {}{}", millis, record.level(), prefix, record.args());
+            return;
+        }
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("{}{}", prefix, record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        )
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trim_cookie() {
+        assert_eq!(trim_cookie(b"user:pass\n"), b"user:pass");
+        assert_eq!(trim_cookie(b"user:pass \r\n\t"), b"user:pass");
+        assert_eq!(trim_cookie(b" user:pass"), b" user:pass");
+        assert_eq!(trim_cookie(b"\n"), b"");
+    }
+
+    #[test]
+    fn test_network_daemon_dir() {
+        let dir = Path::new("/home/user/.bitcoin");
+        assert_eq!(network_daemon_dir(dir, Network::Bitcoin), dir);
+        assert_eq!(network_daemon_dir(dir, Network::Testnet), dir.join("testnet3"));
+        assert_eq!(network_daemon_dir(dir, Network::Regtest), dir.join("regtest"));
+    }
+
+    #[test]
+    fn test_read_hex_file() {
+        let path = env::temp_dir().join(format!("prefixes-{}", std::process::id()));
+        fs::write(&path, "# served prefixes\n00ff\n\n  ab, cd # two more\n").unwrap();
+        let prefixes = read_hex_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(prefixes, vec![vec![0x00, 0xff], vec![0xab], vec![0xcd]]);
+    }
+
+    #[test]
+    fn test_env_cookie() {
+        let name = format!("ADDRINDEXRS_TEST_COOKIE_{}", std::process::id());
+        let cookie = EnvCookie { name: name.clone() };
+        assert!(cookie.get().is_err());
+        env::set_var(&name, "user:pass\n");
+        assert_eq!(cookie.get().unwrap(), b"user:pass");
+        env::set_var(&name, "user:rotated"); // read again on each connection
+        assert_eq!(cookie.get().unwrap(), b"user:rotated");
+        env::remove_var(&name);
+    }
+
+    #[test]
+    fn test_cookie_file() {
+        let path = env::temp_dir().join(format!("cookie-{}", std::process::id()));
+        let cookie = CookieFile { path: path.clone() };
+        assert!(cookie.get().is_err());
+        fs::write(&path, "user:pass\n").unwrap();
+        assert_eq!(cookie.get().unwrap(), b"user:pass");
+        fs::write(&path, "user:rotated").unwrap(); // read again on each connection
+        assert_eq!(cookie.get().unwrap(), b"user:rotated");
+        fs::remove_file(&path).unwrap();
     }
 }
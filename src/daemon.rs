@@ -55,6 +55,49 @@ fn tx_from_value(value: Value) -> Result<Transaction> {
     Ok(deserialize(&tx_bytes).chain_err(|| format!("failed to parse tx {}", tx_hex))?)
 }
 
+//
+// Fee and virtual size of a mempool transaction
+//
+#[derive(Clone, Debug)]
+pub struct MempoolEntry {
+    fee: u64,   // in satoshis
+    vsize: u64, // in virtual bytes
+}
+
+impl MempoolEntry {
+    pub fn new(fee: u64, vsize: u64) -> MempoolEntry {
+        MempoolEntry { fee, vsize }
+    }
+
+    pub fn fee(&self) -> u64 {
+        self.fee
+    }
+
+    pub fn vsize(&self) -> u64 {
+        self.vsize
+    }
+
+    pub fn fee_per_vbyte(&self) -> f32 {
+        self.fee as f32 / self.vsize.max(1) as f32
+    }
+}
+
+fn mempool_entry_from_value(value: Value) -> Result<MempoolEntry> {
+    // "size" and "fee" are replaced by "vsize" and "fees" in newer bitcoind releases
+    let vsize = value
+        .get("vsize")
+        .or_else(|| value.get("size"))
+        .and_then(Value::as_u64)
+        .chain_err(|| "missing vsize")?;
+    let fee = value
+        .get("fees")
+        .and_then(|fees| fees.get("base"))
+        .or_else(|| value.get("fee"))
+        .and_then(Value::as_f64)
+        .chain_err(|| "missing fee")?;
+    Ok(MempoolEntry::new((fee * 1e8).round() as u64, vsize))
+}
+
 /// Parse JSONRPC error code, if exists.
 fn parse_error_code(err: &Value) -> Option<i64> {
     if err.is_null() {
@@ -76,6 +119,34 @@ fn check_error_code(reply_obj: &Map<String, Value>, method: &str) -> Result<()>
     Ok(())
 }
 
+// RPC_INVALID_ADDRESS_OR_KEY, e.g. for a transaction that left the mempool meanwhile
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+
+// Parse the replies of a batch, each one on its own: with `skip_missing`, the ones failing
+// with RPC_INVALID_ADDRESS_OR_KEY are None (instead of failing the whole batch)
+fn parse_batch_replies(
+    mut replies: Value,
+    method: &str,
+    expected_id: u64,
+    skip_missing: bool,
+) -> Result<Vec<Option<Value>>> {
+    let replies = match replies.as_array_mut() {
+        Some(replies) => replies,
+        None => bail!("non-array replies: {:?}", replies),
+    };
+    let mut results = vec![];
+    for reply in replies {
+        let code = reply.get("error").and_then(parse_error_code);
+        if skip_missing && code == Some(RPC_INVALID_ADDRESS_OR_KEY) {
+            debug!("{} RPC error: {}", method, reply["error"]);
+            results.push(None);
+            continue;
+        }
+        results.push(Some(parse_jsonrpc_reply(reply.take(), method, expected_id)?));
+    }
+    Ok(results)
+}
+
 fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Result<Value> {
     if let Some(reply_obj) = reply.as_object_mut() {
         check_error_code(reply_obj, method)?;
@@ -115,14 +186,46 @@ struct NetworkInfo {
     subversion: String,
 }
 
+// Oldest bitcoind supporting `getrawtransaction` with a blockhash argument
+const MIN_SUPPORTED_VERSION: u64 = 16_00_00;
+// Newest bitcoind release known to work with the RPC usage below (27.x)
+const MAX_KNOWN_VERSION: u64 = 27_99_99;
+
+fn check_version(info: &NetworkInfo) -> Result<()> {
+    if info.version < MIN_SUPPORTED_VERSION {
+        bail!(
+            "{} is not supported - please use bitcoind 0.16+",
+            info.subversion,
+        )
+    }
+    if info.version > MAX_KNOWN_VERSION {
+        warn!(
+            "{} is newer than the known working releases - RPC incompatibilities may occur",
+            info.subversion,
+        );
+    }
+    Ok(())
+}
+
 pub trait CookieGetter: Send + Sync {
     fn get(&self) -> Result<Vec<u8>>;
 }
 
+// bitcoind rotates its cookie file on restart: retry (with the new cookie) if it has changed
+fn unauthorized(cookie_getter: &dyn CookieGetter, used: &Option<Vec<u8>>) -> ErrorKind {
+    match cookie_getter.get() {
+        Ok(ref cookie) if Some(cookie) != used.as_ref() => {
+            ErrorKind::Connection("daemon cookie has changed".to_owned())
+        }
+        _ => ErrorKind::Msg("daemon authentication failed (check cookie or credentials)".to_owned()),
+    }
+}
+
 struct Connection {
     tx: TcpStream,
     rx: Lines<BufReader<TcpStream>>,
     cookie_getter: Arc<dyn CookieGetter>,
+    cookie: Option<Vec<u8>>, // used by the last request
     addr: SocketAddr,
     signal: Waiter,
 }
@@ -155,6 +258,7 @@ impl Connection {
             tx: conn,
             rx: reader.lines(),
             cookie_getter,
+            cookie: None,
             addr,
             signal,
         })
@@ -165,13 +269,14 @@ impl Connection {
     }
 
     fn send(&mut self, request: &str) -> Result<()> {
-        let cookie = &self.cookie_getter.get()?;
+        let cookie = self.cookie_getter.get()?;
         let msg = format!(
             "POST / HTTP/1.1\nAuthorization: Basic {}\nContent-Length: {}\n\n{}",
-            base64::encode(cookie),
+            base64::encode(&cookie),
             request.len(),
             request,
         );
+        self.cookie = Some(cookie);
         self.tx.write_all(msg.as_bytes()).chain_err(|| {
             ErrorKind::Connection("disconnected from daemon while sending".to_owned())
         })
@@ -195,6 +300,10 @@ impl Connection {
         for line in iter {
             let line = line.chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
             if line.is_empty() {
+                if status == "HTTP/1.1 401 Unauthorized" {
+                    // the reply has no content
+                    bail!(unauthorized(&*self.cookie_getter, &self.cookie));
+                }
                 in_header = false; // next line should contain the actual response.
             } else if in_header {
                 let parts: Vec<&str> = line.splitn(2, ": ").collect();
@@ -294,12 +403,7 @@ impl Daemon {
 
         let network_info = daemon.getnetworkinfo()?;
         info!("{:?}", network_info);
-        if network_info.version < 16_00_00 {
-            bail!(
-                "{} is not supported - please use bitcoind 0.16+",
-                network_info.subversion,
-            )
-        }
+        check_version(&network_info)?;
 
         let blockchain_info = daemon.getblockchaininfo()?;
         info!("{:?}", blockchain_info);
@@ -345,6 +449,10 @@ impl Daemon {
         self.network.magic()
     }
 
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
     fn call_jsonrpc(&self, request: &Value) -> Result<Value> {
         let mut conn = self.conn.lock().unwrap();
         let request = request.to_string();
@@ -354,26 +462,29 @@ impl Daemon {
         Ok(result)
     }
 
-    fn handle_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+    fn handle_request_batch(
+        &self,
+        method: &str,
+        params_list: &[Value],
+        skip_missing: bool,
+    ) -> Result<Vec<Option<Value>>> {
         let id = self.message_id.next();
         let reqs = params_list
             .iter()
             .map(|params| json!({"method": method, "params": params, "id": id}))
             .collect();
-        let mut results = vec![];
-        let mut replies = self.call_jsonrpc(&reqs)?;
-        if let Some(replies_vec) = replies.as_array_mut() {
-            for reply in replies_vec {
-                results.push(parse_jsonrpc_reply(reply.take(), method, id)?)
-            }
-            return Ok(results);
-        }
-        bail!("non-array replies: {:?}", replies);
+        let replies = self.call_jsonrpc(&reqs)?;
+        parse_batch_replies(replies, method, id, skip_missing)
     }
 
-    fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+    fn retry_request_batch(
+        &self,
+        method: &str,
+        params_list: &[Value],
+        skip_missing: bool,
+    ) -> Result<Vec<Option<Value>>> {
         loop {
-            match self.handle_request_batch(method, params_list) {
+            match self.handle_request_batch(method, params_list, skip_missing) {
                 Err(Error(ErrorKind::Connection(msg), _)) => {
                     warn!("reconnecting to bitcoind: {}", msg);
                     self.signal.wait(Duration::from_secs(3))?;
@@ -387,13 +498,23 @@ impl Daemon {
     }
 
     fn request(&self, method: &str, params: Value) -> Result<Value> {
-        let mut values = self.retry_request_batch(method, &[params])?;
+        let mut values = self.requests(method, &[params])?;
         assert_eq!(values.len(), 1);
         Ok(values.remove(0))
     }
 
     fn requests(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
-        self.retry_request_batch(method, params_list)
+        let values = self.retry_request_batch(method, params_list, false)?;
+        Ok(values.into_iter().map(|value| value.unwrap()).collect()) // none is skipped
+    }
+
+    // Same as `requests`, with None for the missing items (e.g. evicted mempool transactions)
+    fn requests_skipping_missing(
+        &self,
+        method: &str,
+        params_list: &[Value],
+    ) -> Result<Vec<Option<Value>>> {
+        self.retry_request_batch(method, params_list, true)
     }
 
     // bitcoind JSONRPC API:
@@ -416,6 +537,17 @@ impl Daemon {
         parse_hash(&self.request("getbestblockhash", json!([]))?).chain_err(|| "invalid blockhash")
     }
 
+    // Height and header of the daemon's best block
+    pub fn get_best_block(&self) -> Result<(usize, BlockHeader)> {
+        let info = self.getblockchaininfo()?;
+        let blockhash = parse_hash(&json!(info.bestblockhash)).chain_err(|| "invalid blockhash")?;
+        Ok((info.blocks as usize, self.getblockheader(&blockhash)?))
+    }
+
+    pub fn getblockhash(&self, height: usize) -> Result<Sha256dHash> {
+        parse_hash(&self.request("getblockhash", json!([height]))?)
+    }
+
     pub fn getblockheader(&self, blockhash: &Sha256dHash) -> Result<BlockHeader> {
         header_from_value(self.request(
             "getblockheader",
@@ -445,6 +577,12 @@ impl Daemon {
         Ok(block)
     }
 
+    // Serialized block (as hex)
+    pub fn getblock_raw(&self, blockhash: &Sha256dHash) -> Result<String> {
+        let value = self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ false]))?;
+        Ok(value.as_str().chain_err(|| "non-string block")?.to_owned())
+    }
+
     fn load_blocktxids(&self, blockhash: &Sha256dHash) -> Result<Vec<Sha256dHash>> {
         self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ 1]))?
             .get("tx")
@@ -505,15 +643,33 @@ impl Daemon {
             .map(|txhash| json!([txhash.to_hex(), /*verbose=*/ false]))
             .collect();
 
-        let values = self.requests("getrawtransaction", &params_list)?;
+        let values = self.requests_skipping_missing("getrawtransaction", &params_list)?;
         let mut txs = vec![];
-        for value in values {
-            txs.push(tx_from_value(value)?);
+        for (txhash, value) in txhashes.iter().zip(values) {
+            match value {
+                Some(value) => txs.push(tx_from_value(value)?),
+                None => warn!("daemon did not return tx {}", txhash),
+            }
         }
-        assert_eq!(txhashes.len(), txs.len());
         Ok(txs)
     }
 
+    // Mempool entries of the given transactions (None for the ones not in the mempool anymore)
+    pub fn getmempoolentries(
+        &self,
+        txhashes: &[&Sha256dHash],
+    ) -> Result<Vec<Option<MempoolEntry>>> {
+        let params_list: Vec<Value> = txhashes
+            .iter()
+            .map(|txhash| json!([txhash.to_hex()]))
+            .collect();
+        let values = self.requests_skipping_missing("getmempoolentry", &params_list)?;
+        values
+            .into_iter()
+            .map(|value| value.map(mempool_entry_from_value).transpose())
+            .collect()
+    }
+
     pub fn getmempooltxids(&self) -> Result<HashSet<Sha256dHash>> {
         let txids: Value = self.request("getrawmempool", json!([/*verbose=*/ false]))?;
         let mut result = HashSet::new();
@@ -583,3 +739,66 @@ impl Daemon {
         Ok(new_headers)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_version, parse_batch_replies, unauthorized, CookieGetter, NetworkInfo};
+    use crate::errors::*;
+
+    fn info(version: u64) -> NetworkInfo {
+        NetworkInfo {
+            version,
+            subversion: format!("/Satoshi:{}/", version),
+        }
+    }
+
+    #[test]
+    fn test_check_version() {
+        assert!(check_version(&info(15_02_00)).is_err());
+        assert!(check_version(&info(16_00_00)).is_ok());
+        assert!(check_version(&info(26_99_00)).is_ok());
+        // newer releases only log a warning
+        assert!(check_version(&info(99_00_00)).is_ok());
+    }
+
+    #[test]
+    fn test_batch_replies() {
+        let missing = json!({"code": -5, "message": "No such mempool transaction"});
+        let replies = || {
+            json!([
+                {"id": 7, "result": "00", "error": null},
+                {"id": 7, "result": null, "error": missing},
+                {"id": 7, "result": "01", "error": null},
+            ])
+        };
+        let results = parse_batch_replies(replies(), "getrawtransaction", 7, true).unwrap();
+        assert_eq!(results, vec![Some(json!("00")), None, Some(json!("01"))]);
+        // the missing item fails the batch when it's required
+        assert!(parse_batch_replies(replies(), "getrawtransaction", 7, false).is_err());
+
+        // only the missing items are skipped
+        let replies = json!([{"id": 7, "result": null, "error": {"code": -1, "message": "?"}}]);
+        assert!(parse_batch_replies(replies, "getrawtransaction", 7, true).is_err());
+    }
+
+    struct TestCookie(&'static [u8]);
+
+    impl CookieGetter for TestCookie {
+        fn get(&self) -> Result<Vec<u8>> {
+            Ok(self.0.to_vec())
+        }
+    }
+
+    #[test]
+    fn test_unauthorized() {
+        let used = Some(b"user:old".to_vec());
+        match unauthorized(&TestCookie(b"user:new"), &used) {
+            ErrorKind::Connection(_) => (),
+            kind => panic!("rotated cookie should be retried: {}", kind),
+        }
+        match unauthorized(&TestCookie(b"user:old"), &used) {
+            ErrorKind::Msg(_) => (),
+            kind => panic!("unchanged cookie should fail: {}", kind),
+        }
+    }
+}
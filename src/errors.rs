@@ -9,6 +9,16 @@ error_chain! {
             display("Connection error: {}", msg)
         }
 
+        InvalidRequest(msg: String) {
+            description("Invalid request")
+            display("{}", msg)
+        }
+
+        Cancelled {
+            description("Cancelled query")
+            display("Query cancelled")
+        }
+
         Interrupt(sig: i32) {
             description("Interruption by external signal")
             display("Interrupted by signal {}", sig)
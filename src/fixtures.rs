@@ -0,0 +1,142 @@
+// Fixtures of the unit tests (and of the benchmarks, with the `fixtures` feature): an in-memory
+// store, and builders of blocks and transactions
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::util::hash::BitcoinHash;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::errors::*;
+use crate::index::{index_block, ScriptHashFilter};
+use crate::store::{ReadStore, Row, WriteStore};
+use crate::util::Bytes;
+
+//
+// Store of the rows in memory (instead of a RocksDB)
+//
+#[derive(Default)]
+pub struct MemStore {
+    rows: Mutex<BTreeMap<Bytes, Bytes>>,
+}
+
+impl MemStore {
+    pub fn rows(&self) -> BTreeMap<Bytes, Bytes> {
+        self.rows.lock().unwrap().clone()
+    }
+}
+
+impl ReadStore for MemStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        Ok(self.rows.lock().unwrap().get(key).cloned())
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Row>> {
+        let rows = self.rows.lock().unwrap();
+        Ok(rows
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Row {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect())
+    }
+}
+
+impl WriteStore for MemStore {
+    fn write<I: IntoIterator<Item = Row>>(&self, rows: I) -> Result<()> {
+        let mut map = self.rows.lock().unwrap();
+        map.extend(rows.into_iter().map(Row::into_pair));
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Transaction spending `spent` outputs, with outputs of the given scripts and values
+pub fn transaction(spent: &[OutPoint], outputs: &[(&Script, u64)]) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: 0,
+        input: spent
+            .iter()
+            .map(|outpoint| TxIn {
+                previous_output: *outpoint,
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            })
+            .collect(),
+        output: outputs
+            .iter()
+            .map(|(script, value)| TxOut {
+                value: *value,
+                script_pubkey: (*script).clone(),
+            })
+            .collect(),
+    }
+}
+
+// Chain of blocks from the genesis one, the i-th one with the transactions `txdata[i]`
+pub fn chain(txdata: Vec<Vec<Transaction>>) -> Vec<Block> {
+    let mut prev_blockhash = Sha256dHash::default();
+    txdata
+        .into_iter()
+        .enumerate()
+        .map(|(nonce, txdata)| {
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: Sha256dHash::default(),
+                time: nonce as u32,
+                bits: 0,
+                nonce: nonce as u32,
+            };
+            prev_blockhash = header.bitcoin_hash();
+            Block { header, txdata }
+        })
+        .collect()
+}
+
+// Chain of headers from the genesis one, with the given times and difficulty bits
+pub fn headers(times: &[u32], bits: u32) -> Vec<BlockHeader> {
+    let mut prev_blockhash = Sha256dHash::default();
+    times
+        .iter()
+        .map(|&time| {
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: Sha256dHash::default(),
+                time,
+                bits,
+                nonce: 0,
+            };
+            prev_blockhash = header.bitcoin_hash();
+            header
+        })
+        .collect()
+}
+
+// Chain of `count` blocks without transactions
+pub fn blocks(count: usize) -> Vec<Block> {
+    chain(vec![vec![]; count])
+}
+
+// Store of the indexed rows of a chain of blocks, and the heights of its blocks
+pub fn indexed_chain(blocks: &[Block]) -> (MemStore, HashMap<Sha256dHash, usize>) {
+    let store = MemStore::default();
+    for block in blocks {
+        store.write(index_block(block, &ScriptHashFilter::default())).unwrap();
+    }
+    let heights = blocks
+        .iter()
+        .enumerate()
+        .map(|(height, block)| (block.bitcoin_hash(), height))
+        .collect();
+    (store, heights)
+}
@@ -1,13 +1,20 @@
 use bincode;
 use bitcoin::blockdata::block::{Block, BlockHeader};
-use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
-use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::consensus::encode::{deserialize, serialize, Encodable};
+use bitcoin::util::bip158::{self, BlockFilter};
 use bitcoin::util::hash::BitcoinHash;
+use bitcoin::util::uint::Uint256;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use std::collections::HashSet;
-use std::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 
 use crate::daemon::Daemon;
 use crate::errors::*;
@@ -34,7 +41,7 @@ pub struct TxInKey {
 //
 #[derive(Serialize, Deserialize)]
 pub struct TxInRow {
-    key: TxInKey,
+    pub key: TxInKey,
     pub txid_prefix: HashPrefix,
 }
 
@@ -51,14 +58,23 @@ impl TxInRow {
     }
 
     pub fn filter(txid: &Sha256dHash, vout: usize) -> Bytes {
+        TxInRow::filter_prefix(hash_prefix(&txid[..]), vout)
+    }
+
+    pub fn filter_prefix(txid_prefix: HashPrefix, vout: usize) -> Bytes {
         bincode::serialize(&TxInKey {
             code: b'I',
-            prev_txid_prefix: hash_prefix(&txid[..]),
+            prev_txid_prefix: txid_prefix,
             prev_vout: vout as u16,
         })
         .unwrap()
     }
 
+    // Inputs spending any output of the transactions with the given txid prefix
+    pub fn filter_txid_prefix(txid_prefix: HashPrefix) -> Bytes {
+        [b"I", &txid_prefix[..]].concat()
+    }
+
     pub fn to_row(&self) -> Row {
         Row {
             key: bincode::serialize(&self).unwrap(),
@@ -66,8 +82,8 @@ impl TxInRow {
         }
     }
 
-    pub fn from_row(row: &Row) -> TxInRow {
-        bincode::deserialize(&row.key).expect("failed to parse TxInRow")
+    pub fn from_row(row: &Row) -> Result<TxInRow> {
+        bincode::deserialize(&row.key).chain_err(|| "failed to parse TxInRow")
     }
 }
 
@@ -117,8 +133,8 @@ impl TxOutRow {
         }
     }
 
-    pub fn from_row(row: &Row) -> TxOutRow {
-        bincode::deserialize(&row.key).expect("failed to parse TxOutRow")
+    pub fn from_row(row: &Row) -> Result<TxOutRow> {
+        bincode::deserialize(&row.key).chain_err(|| "failed to parse TxOutRow")
     }
 }
 
@@ -133,6 +149,8 @@ pub struct TxKey {
 
 //
 // Row storing a transaction
+// (the block hash is also appended to the key, so that a txid included in several blocks
+// - i.e. the duplicate coinbase transactions allowed before BIP30 - has a row per block)
 //
 #[derive(Serialize, Deserialize)]
 pub struct TxRow {
@@ -161,16 +179,13 @@ impl TxRow {
 
     pub fn to_row(&self) -> Row {
         Row {
-            key: bincode::serialize(&self.key).unwrap(),
-            value: bincode::serialize(&self.block_hash).unwrap(),
+            key: bincode::serialize(&self).unwrap(),
+            value: vec![],
         }
     }
 
-    pub fn from_row(row: &Row) -> TxRow {
-        TxRow {
-            key:bincode::deserialize(&row.key).expect("failed to parse TxRow"),
-            block_hash:bincode::deserialize(&row.value).expect("failed to parse TxRow")
-        }
+    pub fn from_row(row: &Row) -> Result<TxRow> {
+        bincode::deserialize(&row.key).chain_err(|| "failed to parse TxRow")
     }
 }
 
@@ -183,6 +198,102 @@ struct BlockKey {
     hash: FullHash,
 }
 
+//
+// Key of a row storing the BIP158 filter of a block
+//
+#[derive(Serialize, Deserialize)]
+struct BlockFilterKey {
+    code: u8,
+    hash: FullHash,
+}
+
+pub fn block_filter_key(blockhash: &Sha256dHash) -> Bytes {
+    bincode::serialize(&BlockFilterKey {
+        code: b'G',
+        hash: full_hash(&blockhash[..]),
+    })
+    .unwrap()
+}
+
+//
+// Key of a row storing the witness commitment of a block (BIP141)
+//
+#[derive(Serialize, Deserialize)]
+struct WitnessCommitmentKey {
+    code: u8,
+    hash: FullHash,
+}
+
+pub fn witness_commitment_key(blockhash: &Sha256dHash) -> Bytes {
+    bincode::serialize(&WitnessCommitmentKey {
+        code: b'W',
+        hash: full_hash(&blockhash[..]),
+    })
+    .unwrap()
+}
+
+//
+// Key of a row storing an output (its value and scriptPubKey)
+//
+#[derive(Serialize, Deserialize)]
+struct OutputKey {
+    code: u8,
+    txid: FullHash,
+    vout: u16,
+}
+
+pub fn output_key(txid: &Sha256dHash, vout: usize) -> Bytes {
+    bincode::serialize(&OutputKey {
+        code: b'P',
+        txid: full_hash(&txid[..]),
+        vout: vout as u16,
+    })
+    .unwrap()
+}
+
+//
+// Key of a row storing the input spending an output (see `spend_rows`)
+//
+#[derive(Serialize, Deserialize)]
+struct SpendKey {
+    code: u8,
+    txid: FullHash,
+    vout: u16,
+}
+
+pub fn spend_key(txid: &Sha256dHash, vout: usize) -> Bytes {
+    bincode::serialize(&SpendKey {
+        code: b'X',
+        txid: full_hash(&txid[..]),
+        vout: vout as u16,
+    })
+    .unwrap()
+}
+
+//
+// Value of a spend row: the spending transaction, and its block
+//
+#[derive(Serialize, Deserialize)]
+pub struct Spend {
+    pub txid: FullHash,
+    pub blockhash: FullHash,
+}
+
+pub fn block_key(blockhash: &Sha256dHash) -> Bytes {
+    bincode::serialize(&BlockKey {
+        code: b'B',
+        hash: full_hash(&blockhash[..]),
+    })
+    .unwrap()
+}
+
+// Hash and header of a block row
+fn parse_block_row(row: &Row) -> Result<(Sha256dHash, BlockHeader)> {
+    let key: BlockKey = bincode::deserialize(&row.key).chain_err(|| "failed to parse block key")?;
+    let header = deserialize(&row.value).chain_err(|| "failed to parse block header")?;
+    Ok((Sha256dHash::from_inner(key.hash), header))
+}
+
 //
 // Compute the script hash of a scriptpubkey
 //
@@ -194,15 +305,57 @@ pub fn compute_script_hash(data: &[u8]) -> FullHash {
     hash
 }
 
+//
+// Allowlist of script hash prefixes
+// (an empty allowlist allows all the script hashes)
+//
+#[derive(Clone, Debug, Default)]
+pub struct ScriptHashFilter {
+    prefixes: Vec<Bytes>,
+}
+
+impl ScriptHashFilter {
+    pub fn new(prefixes: Vec<Bytes>) -> ScriptHashFilter {
+        ScriptHashFilter { prefixes }
+    }
+
+    pub fn allows(&self, script_hash: &[u8]) -> bool {
+        self.prefixes.is_empty()
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| script_hash.starts_with(prefix))
+    }
+
+    fn allows_script(&self, script: &Script) -> bool {
+        self.prefixes.is_empty() || self.allows(&compute_script_hash(&script[..]))
+    }
+}
+
+// Output indexes are stored as `u16` (larger ones would be truncated)
+fn is_indexable_vout(txid: &Sha256dHash, vout: usize) -> bool {
+    if vout > u16::MAX as usize {
+        warn!("skipping output {}:{} (index is too large to be indexed)", txid, vout);
+        return false;
+    }
+    true
+}
+
 //
 // Index a transaction
+// (outputs not allowed by the filter, or not representable in the index, are skipped)
 //
-pub fn index_transaction<'a>(txn: &'a Transaction, blockhash: &Sha256dHash) -> impl 'a + Iterator<Item = Row> {
+pub fn index_transaction<'a>(
+    txn: &'a Transaction,
+    blockhash: &Sha256dHash,
+    filter: &'a ScriptHashFilter,
+) -> impl 'a + Iterator<Item = Row> {
     let null_hash = Sha256dHash::default();
     let txid: Sha256dHash = txn.txid();
 
     let inputs = txn.input.iter().filter_map(move |input| {
-        if input.previous_output.txid == null_hash {
+        let prevout = &input.previous_output;
+        if prevout.txid == null_hash || !is_indexable_vout(&prevout.txid, prevout.vout as usize) {
             None
         } else {
             Some(TxInRow::new(&txid, &input).to_row())
@@ -213,6 +366,8 @@ pub fn index_transaction<'a>(txn: &'a Transaction, blockhash: &Sha256dHash) -> i
         .output
         .iter()
         .enumerate()
+        .filter(move |(vout, _)| is_indexable_vout(&txid, *vout))
+        .filter(move |(_, output)| filter.allows_script(&output.script_pubkey))
         .map(move |(vout, output)| TxOutRow::new(&txid, vout as u32, &output).to_row());
 
     inputs
@@ -223,24 +378,187 @@ pub fn index_transaction<'a>(txn: &'a Transaction, blockhash: &Sha256dHash) -> i
 //
 // Index a block
 //
-pub fn index_block<'a>(block: &'a Block) -> impl 'a + Iterator<Item = Row> {
+pub fn index_block<'a>(
+    block: &'a Block,
+    filter: &'a ScriptHashFilter,
+) -> impl 'a + Iterator<Item = Row> {
     let blockhash = block.bitcoin_hash();
     // Persist block hash and header
     let row = Row {
-        key: bincode::serialize(&BlockKey {
-            code: b'B',
-            hash: full_hash(&blockhash[..]),
-        })
-        .unwrap(),
+        key: block_key(&blockhash),
         value: serialize(&block.header),
     };
     block
         .txdata
         .iter()
-        .flat_map(move |txn| index_transaction(&txn, &blockhash))
+        .flat_map(move |txn| index_transaction(&txn, &blockhash, filter))
         .chain(std::iter::once(row))
 }
 
+// Header of the coinbase output committing to the witnesses of the block (BIP141):
+// OP_RETURN, a 36 bytes push, and the 0xaa21a9ed tag (followed by the 32 bytes commitment)
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// Witness commitment of a block (the one of its coinbase's last matching output, if any)
+pub fn witness_commitment(block: &Block) -> Option<Bytes> {
+    let coinbase = block.txdata.first()?;
+    coinbase
+        .output
+        .iter()
+        .rev()
+        .map(|output| output.script_pubkey.as_bytes())
+        .find(|script| script.len() >= 38 && script.starts_with(&WITNESS_COMMITMENT_HEADER))
+        .map(|script| script[6..38].to_vec())
+}
+
+//
+// Store the witness commitment of a block (optional, blocks without one have no row)
+//
+pub fn witness_commitment_row(block: &Block) -> Option<Row> {
+    witness_commitment(block).map(|commitment| Row {
+        key: witness_commitment_key(&block.bitcoin_hash()),
+        value: commitment,
+    })
+}
+
+//
+// Store the outputs of a block (optional, allows serving them without the daemon)
+// (only the outputs having a TxOutRow are stored)
+//
+pub fn output_rows<'a>(
+    block: &'a Block,
+    filter: &'a ScriptHashFilter,
+) -> impl 'a + Iterator<Item = Row> {
+    block.txdata.iter().flat_map(move |txn| {
+        let txid = txn.txid();
+        txn.output
+            .iter()
+            .enumerate()
+            .filter(move |(vout, _)| *vout <= u16::MAX as usize)
+            .filter(move |(_, output)| filter.allows_script(&output.script_pubkey))
+            .map(move |(vout, output)| Row {
+                key: output_key(&txid, vout),
+                value: serialize(output),
+            })
+    })
+}
+
+//
+// Store the input spending each output (optional, allows finding it without a scan)
+// (unlike the TxInRows, the spent outputs are fully identified, up to the `u16` index)
+//
+pub fn spend_rows(block: &Block) -> impl '_ + Iterator<Item = Row> {
+    let blockhash = block.bitcoin_hash();
+    let null_hash = Sha256dHash::default();
+    block.txdata.iter().flat_map(move |txn| {
+        let txid = txn.txid();
+        txn.input
+            .iter()
+            .map(|input| input.previous_output)
+            .filter(move |prevout| prevout.txid != null_hash)
+            .filter(|prevout| is_indexable_vout(&prevout.txid, prevout.vout as usize))
+            .map(move |prevout| spend_row(&prevout, &txid, &blockhash))
+    })
+}
+
+pub fn spend_row(prevout: &OutPoint, txid: &Sha256dHash, blockhash: &Sha256dHash) -> Row {
+    let spend = Spend {
+        txid: full_hash(&txid[..]),
+        blockhash: full_hash(&blockhash[..]),
+    };
+    Row {
+        key: spend_key(&prevout.txid, prevout.vout as usize),
+        value: bincode::serialize(&spend).unwrap(),
+    }
+}
+
+// Marks whether all the blocks of a DB have their spend rows (i.e. an output without one is
+// unspent), or some were indexed without them
+fn spend_rows_marker(complete: bool) -> Row {
+    Row {
+        key: b"X".to_vec(),
+        value: vec![complete as u8],
+    }
+}
+
+pub fn has_spend_rows(store: &dyn ReadStore) -> Result<bool> {
+    let marker = spend_rows_marker(true);
+    Ok(store.get(&marker.key)? == Some(marker.value))
+}
+
+//
+// Whether the spend rows are stored and used, according to the marker (updated on startup):
+// they are only complete if written since the first indexed block, and while enabled since
+// then (otherwise, the index must be rebuilt with `--reindex`)
+//
+pub fn init_spend_rows<S: ReadStore + WriteStore>(store: &S, enabled: bool) -> Result<bool> {
+    let complete = has_spend_rows(store)?;
+    if !enabled {
+        if complete {
+            // the blocks indexed from now on won't have them
+            store.write(vec![spend_rows_marker(false)])?;
+            store.flush()?;
+        }
+        return Ok(false);
+    }
+    if complete {
+        return Ok(true);
+    }
+    let marked = store.get(&spend_rows_marker(false).key)?.is_some();
+    if marked || read_last_indexed(store)?.is_some() || !store.scan(b"B")?.is_empty() {
+        warn!("blocks were indexed without spend rows, reindex to enable them");
+        return Ok(false);
+    }
+    store.write(vec![spend_rows_marker(true)])?;
+    store.flush()?;
+    Ok(true)
+}
+
+//
+// Compute the BIP158 basic filter of a block
+// (the scripts of the spent outputs are retrieved from the daemon)
+//
+pub fn block_filter_row(block: &Block, daemon: &Daemon) -> Result<Row> {
+    let mut scripts = HashMap::<OutPoint, Script>::new();
+    let mut add_outputs = |txn: &Transaction| {
+        let txid = txn.txid();
+        for (vout, output) in txn.output.iter().enumerate() {
+            let outpoint = OutPoint {
+                txid,
+                vout: vout as u32,
+            };
+            scripts.insert(outpoint, output.script_pubkey.clone());
+        }
+    };
+    for txn in &block.txdata {
+        add_outputs(txn);
+    }
+    let missing: HashSet<Sha256dHash> = block
+        .txdata
+        .iter()
+        .skip(1) // coinbase
+        .flat_map(|txn| txn.input.iter().map(|input| input.previous_output.txid))
+        .collect();
+    let txids: Vec<&Sha256dHash> = missing.iter().collect();
+    for txn in daemon
+        .gettransactions(&txids)
+        .chain_err(|| "failed to get spent transactions")?
+    {
+        add_outputs(&txn);
+    }
+    let filter = BlockFilter::new_script_filter(block, |outpoint| {
+        scripts
+            .get(outpoint)
+            .cloned()
+            .ok_or(bip158::Error::UtxoMissing(*outpoint))
+    })
+    .chain_err(|| format!("failed to compute filter of block {}", block.bitcoin_hash()))?;
+    Ok(Row {
+        key: block_filter_key(&block.bitcoin_hash()),
+        value: filter.content,
+    })
+}
+
 //
 // Retrieve the last indexed block
 //
@@ -253,38 +571,254 @@ pub fn last_indexed_block(blockhash: &Sha256dHash) -> Row {
 }
 
 //
-// Retrieve the hashes of all the indexed blocks
+// Read the last indexed block (None if no block was indexed yet)
 //
-pub fn read_indexed_blockhashes(store: &dyn ReadStore) -> HashSet<Sha256dHash> {
-    let mut result = HashSet::new();
-    for row in store.scan(b"B") {
-        let key: BlockKey = bincode::deserialize(&row.key).unwrap();
-        result.insert(deserialize(&key.hash).unwrap());
+pub fn read_last_indexed(store: &dyn ReadStore) -> Result<Option<Sha256dHash>> {
+    match store.get(b"L")? {
+        Some(value) => Ok(Some(deserialize(&value).chain_err(|| "invalid last indexed block")?)),
+        None => Ok(None),
     }
-    result
 }
 
 //
-// Retrieve the headers of all the indexed blocks
+// Advance the last indexed block over the blocks indexed after it
+// (e.g. when an initial import was interrupted before updating the 'L' row)
+//
+pub fn repair_last_indexed<S: ReadStore + WriteStore>(store: &S) -> Result<Option<Sha256dHash>> {
+    let latest_blockhash = read_last_indexed(store)?.unwrap_or_default();
+    let mut children = HashMap::<Sha256dHash, Vec<Sha256dHash>>::new();
+    for row in store.scan(b"B")? {
+        let (blockhash, header) = parse_block_row(&row)?;
+        children.entry(header.prev_blockhash).or_default().push(blockhash);
+    }
+
+    let mut blockhash = latest_blockhash;
+    while let Some(next) = children.get(&blockhash) {
+        if next.len() > 1 {
+            warn!("{} indexed blocks follow {}, stopping there", next.len(), blockhash);
+            break;
+        }
+        blockhash = next[0];
+    }
+    if blockhash == latest_blockhash {
+        return Ok(None);
+    }
+    warn!(
+        "last indexed block is {}, advancing it to {} (indexed after it)",
+        latest_blockhash, blockhash
+    );
+    store.write(vec![last_indexed_block(&blockhash)])?;
+    store.flush()?;
+    Ok(Some(blockhash))
+}
+
 //
-fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
-    let latest_blockhash: Sha256dHash = match store.get(b"L") {
-        // latest blockheader persisted in the DB.
-        Some(row) => deserialize(&row).unwrap(),
-        None => Sha256dHash::default(),
+// Height of the last indexed header on the daemon's best chain, if the indexed tip isn't
+// (`blockhash` returns the hash of the daemon's block at a height, up to `best_height`)
+//
+fn diverged_ancestor(
+    headers: &HeaderList,
+    best_height: usize,
+    blockhash: impl Fn(usize) -> Result<Sha256dHash>,
+) -> Result<Option<usize>> {
+    let indexed_height = match headers.len() {
+        0 => return Ok(None),
+        len => len - 1,
     };
-    trace!("lastest indexed blockhash: {}", latest_blockhash);
+    // the daemon may be behind the index (e.g. while it's syncing)
+    let mut height = indexed_height.min(best_height);
+    let mut diverged = false;
+    loop {
+        let indexed = headers.header_by_height(height).map(|entry| *entry.hash());
+        if indexed == Some(blockhash(height)?) {
+            return Ok(if diverged { Some(height) } else { None });
+        }
+        if height == 0 {
+            bail!("the indexed genesis block isn't the daemon's one");
+        }
+        diverged = true;
+        height -= 1;
+    }
+}
+
+//
+// Checkpoint of the indexed headers chain
+// (allows to skip the scan of all the block rows on startup)
+//
+const HEADER_LEN: usize = 80;
+// The checkpoint is refreshed every CHECKPOINT_INTERVAL blocks, and stored in chunks of as
+// many headers (so that a refresh only writes the chunks that changed)
+const CHECKPOINT_INTERVAL: usize = 1000;
+
+fn serialize_headers<'a>(entries: impl Iterator<Item = &'a HeaderEntry>) -> Bytes {
+    entries.flat_map(|entry| serialize(entry.header())).collect()
+}
+
+fn checkpoint_key(chunk: usize) -> Bytes {
+    [&b"C"[..], &(chunk as u32).to_be_bytes()].concat()
+}
 
+// Rows of the checkpoint chunks that differ from the stored ones: a stored chunk with the same
+// last header has the same headers before it (as they form a chain), and so do its previous ones
+pub fn checkpoint_rows(store: &dyn ReadStore, headers: &HeaderList) -> Result<Vec<Row>> {
+    let entries: Vec<&HeaderEntry> = headers.iter().collect();
+    let mut rows = vec![];
+    for (chunk, entries) in entries.chunks(CHECKPOINT_INTERVAL).enumerate().rev() {
+        let row = Row {
+            key: checkpoint_key(chunk),
+            value: serialize_headers(entries.iter().copied()),
+        };
+        if store.get(&row.key)?.as_ref() == Some(&row.value) {
+            break;
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+// Parses serialized headers, making sure they form a chain starting at the genesis block
+fn parse_header_chain(value: &[u8]) -> Result<Vec<BlockHeader>> {
+    let mut headers = vec![];
+    extend_header_chain(&mut headers, value)?;
+    Ok(headers)
+}
+
+// Parses serialized headers, making sure they extend the given chain
+fn extend_header_chain(headers: &mut Vec<BlockHeader>, value: &[u8]) -> Result<()> {
+    let chunks = value.chunks_exact(HEADER_LEN);
+    if !chunks.remainder().is_empty() {
+        bail!("invalid headers size: {}", value.len());
+    }
+    let mut new_headers = vec![];
+    let mut blockhash = headers.last().map(BitcoinHash::bitcoin_hash).unwrap_or_default();
+    for (offset, chunk) in chunks.enumerate() {
+        let height = headers.len() + offset;
+        let header: BlockHeader =
+            deserialize(chunk).chain_err(|| format!("invalid header at height {}", height))?;
+        if header.prev_blockhash != blockhash {
+            bail!("headers are not a chain at height {}", height);
+        }
+        blockhash = header.bitcoin_hash();
+        new_headers.push(header);
+    }
+    headers.extend(new_headers);
+    Ok(())
+}
+
+//
+// Export and import of the header chain
+// (imported headers only seed the headers download, the blocks still need to be indexed)
+//
+pub fn export_headers(store: &dyn ReadStore, path: &Path) -> Result<usize> {
+    let headers = read_indexed_headers(store)?;
+    let value = serialize_headers(headers.iter());
+    fs::write(path, value).chain_err(|| format!("failed to write {:?}", path))?;
+    Ok(headers.len())
+}
+
+pub fn import_headers(store: &(impl ReadStore + WriteStore), path: &Path) -> Result<usize> {
+    if store.get(b"L")?.is_some() {
+        bail!("headers can only be imported into an empty DB");
+    }
+    let value = fs::read(path).chain_err(|| format!("failed to read {:?}", path))?;
+    let count = parse_header_chain(&value)
+        .chain_err(|| format!("invalid headers in {:?}", path))?
+        .len();
+    store.write(vec![Row {
+        key: b"S".to_vec(),
+        value,
+    }])?;
+    store.flush()?;
+    Ok(count)
+}
+
+pub fn read_imported_headers(store: &dyn ReadStore) -> Result<HeaderList> {
+    let mut result = HeaderList::empty();
+    let headers = match store.get(b"S")? {
+        Some(value) => parse_header_chain(&value).chain_err(|| "invalid imported headers")?,
+        None => vec![],
+    };
+    if let Some(tip) = headers.last().map(BitcoinHash::bitcoin_hash) {
+        let entries = result.order(headers);
+        result.apply(entries, tip);
+    }
+    Ok(result)
+}
+
+//
+// Retrieve the headers of the indexed blocks from the checkpoint
+// (and the blocks indexed after it), if there is a usable one
+//
+fn read_checkpoint_headers(
+    store: &dyn ReadStore,
+    latest_blockhash: &Sha256dHash,
+) -> Result<Option<Vec<BlockHeader>>> {
+    let mut headers = vec![];
+    for (chunk, row) in store.scan(b"C")?.iter().enumerate() {
+        // the chunks past a reorg to a shorter chain are stale, and don't extend the chain
+        if row.key != checkpoint_key(chunk) {
+            warn!("missing header checkpoint chunk {}", chunk);
+            break;
+        }
+        if let Err(e) = extend_header_chain(&mut headers, &row.value) {
+            warn!("invalid header checkpoint chunk {}: {}", chunk, e);
+            break;
+        }
+    }
+    if headers.is_empty() {
+        return Ok(None);
+    }
+    let heights: HashMap<Sha256dHash, usize> = headers
+        .iter()
+        .enumerate()
+        .map(|(height, header)| (header.bitcoin_hash(), height))
+        .collect();
+    let null_hash = Sha256dHash::default();
+
+    // Walk back from the latest indexed block until the checkpoint is reached
+    let mut new_headers = vec![];
+    let mut blockhash = *latest_blockhash;
+    let height = loop {
+        if let Some(height) = heights.get(&blockhash) {
+            break *height;
+        }
+        if blockhash == null_hash {
+            return Ok(None);
+        }
+        let header: BlockHeader = match store.get(&block_key(&blockhash))? {
+            Some(value) => deserialize(&value).chain_err(|| "failed to parse block header")?,
+            None => return Ok(None),
+        };
+        blockhash = header.prev_blockhash;
+        new_headers.push(header);
+    };
+    debug!(
+        "loaded {} headers from checkpoint ({} indexed after it)",
+        height + 1,
+        new_headers.len()
+    );
+    headers.truncate(height + 1);
+    new_headers.reverse();
+    headers.extend(new_headers);
+    Ok(Some(headers))
+}
+
+//
+// Retrieve the headers of the indexed blocks by scanning all the block rows
+//
+fn scan_indexed_headers(
+    store: &dyn ReadStore,
+    latest_blockhash: &Sha256dHash,
+) -> Result<Vec<BlockHeader>> {
     let mut map = HeaderMap::new();
-    for row in store.scan(b"B") {
-        let key: BlockKey = bincode::deserialize(&row.key).unwrap();
-        let header: BlockHeader = deserialize(&row.value).unwrap();
-        map.insert(deserialize(&key.hash).unwrap(), header);
+    for row in store.scan(b"B")? {
+        let (blockhash, header) = parse_block_row(&row)?;
+        map.insert(blockhash, header);
     }
 
     let mut headers = vec![];
     let null_hash = Sha256dHash::default();
-    let mut blockhash = latest_blockhash;
+    let mut blockhash = *latest_blockhash;
 
     while blockhash != null_hash {
         let header = map
@@ -295,6 +829,36 @@ fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
     }
 
     headers.reverse();
+    Ok(headers)
+}
+
+//
+// Retrieve the hashes of all the indexed blocks
+//
+pub fn read_indexed_blockhashes(store: &dyn ReadStore) -> Result<HashSet<Sha256dHash>> {
+    let mut result = HashSet::new();
+    for row in store.scan(b"B")? {
+        result.insert(parse_block_row(&row)?.0);
+    }
+    Ok(result)
+}
+
+//
+// Retrieve the headers of all the indexed blocks
+//
+fn read_indexed_headers(store: &dyn ReadStore) -> Result<HeaderList> {
+    // latest blockheader persisted in the DB.
+    let latest_blockhash = read_last_indexed(store)?.unwrap_or_default();
+    trace!("lastest indexed blockhash: {}", latest_blockhash);
+
+    let headers = match read_checkpoint_headers(store, &latest_blockhash)? {
+        Some(headers) => headers,
+        None => {
+            debug!("no usable header checkpoint, scanning all indexed headers");
+            scan_indexed_headers(store, &latest_blockhash)?
+        }
+    };
+    let null_hash = Sha256dHash::default();
 
     assert_eq!(
         headers
@@ -315,17 +879,54 @@ fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
     let mut result = HeaderList::empty();
     let entries = result.order(headers);
     result.apply(entries, latest_blockhash);
-    result
+    Ok(result)
 }
 
 //
 // Indexer
 //
+// Number of blocks to fetch next, so that blocks as large as `blocks` take up to `max_bytes`
+fn bounded_batch_size(blocks: &[Block], max_bytes: usize, max_count: usize) -> usize {
+    let bytes: usize = blocks
+        .iter()
+        .map(|block| block.consensus_encode(io::sink()).unwrap())
+        .sum();
+    let block_bytes = (bytes / blocks.len().max(1)).max(1);
+    (max_bytes / block_bytes).max(1).min(max_count)
+}
+
+// Headers, and the result of `f`, with no update of the headers in between
+// (the next update copies them if the snapshot is still in use)
+fn snapshot_headers<T>(
+    headers: &RwLock<Arc<HeaderList>>,
+    f: impl FnOnce() -> T,
+) -> (Arc<HeaderList>, T) {
+    let headers = headers.read().unwrap();
+    let result = f();
+    (Arc::clone(&headers), result)
+}
+
 pub struct Index {
-    // TODO: store also latest snapshot.
-    headers: RwLock<HeaderList>,
+    headers: RwLock<Arc<HeaderList>>, // copied on update while a snapshot of them is in use
     daemon: Daemon,
     batch_size: usize,
+    batch_bytes: Option<usize>,
+    prefetch: usize, // number of batches fetched ahead of the indexed one
+    tip_retries: usize,
+    index_filters: bool,
+    index_outputs: bool,
+    index_witness_commitments: bool,
+    index_spends: bool,
+    script_hash_filter: ScriptHashFilter,
+}
+
+//
+// Optional rows stored along with the indexed blocks (by both import modes)
+//
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OptionalRows {
+    pub outputs: bool, // see `output_rows`
+    pub spends: bool,  // see `spend_rows`
 }
 
 impl Index {
@@ -333,18 +934,91 @@ impl Index {
         store: &dyn ReadStore,
         daemon: &Daemon,
         batch_size: usize,
+        batch_bytes: Option<usize>,
+        index_filters: bool,
+        index_outputs: bool,
+        script_hash_filter: ScriptHashFilter,
     ) -> Result<Index> {
-        let headers = read_indexed_headers(store);
+        let headers = read_indexed_headers(store)?;
         Ok(Index {
-            headers: RwLock::new(headers),
+            headers: RwLock::new(Arc::new(headers)),
             daemon: daemon.reconnect()?,
             batch_size,
+            batch_bytes,
+            prefetch: 1,
+            tip_retries: 0,
+            index_filters,
+            index_outputs,
+            index_witness_commitments: false,
+            index_spends: false,
+            script_hash_filter,
         })
     }
 
-    pub fn reload(&self, store: &dyn ReadStore) {
-        let mut headers = self.headers.write().unwrap();
-        *headers = read_indexed_headers(store);
+    /// Sets the number of batches of blocks fetched while the previous one is indexed
+    /// (0 to fetch them one at a time, e.g. to bound the memory usage).
+    pub fn with_prefetch(mut self, batches: usize) -> Self {
+        self.prefetch = batches;
+        self
+    }
+
+    /// Sets the number of times an update continues with the blocks found meanwhile (e.g.
+    /// during a catch-up with fast block production), instead of leaving them to the next one.
+    pub fn with_tip_retries(mut self, retries: usize) -> Self {
+        self.tip_retries = retries;
+        self
+    }
+
+    /// Sets whether the witness commitments of the blocks indexed via JSONRPC are stored.
+    pub fn with_witness_commitments(mut self, enabled: bool) -> Self {
+        self.index_witness_commitments = enabled;
+        self
+    }
+
+    /// Sets whether the spend rows are stored and used for the lookups (see
+    /// `init_spend_rows`, which tells whether they are complete).
+    pub fn with_spends(mut self, enabled: bool) -> Self {
+        self.index_spends = enabled;
+        self
+    }
+
+    pub fn script_hash_filter(&self) -> &ScriptHashFilter {
+        &self.script_hash_filter
+    }
+
+    pub fn index_outputs(&self) -> bool {
+        self.index_outputs
+    }
+
+    pub fn index_filters(&self) -> bool {
+        self.index_filters
+    }
+
+    pub fn index_witness_commitments(&self) -> bool {
+        self.index_witness_commitments
+    }
+
+    pub fn index_spends(&self) -> bool {
+        self.index_spends
+    }
+
+    pub fn optional_rows(&self) -> OptionalRows {
+        OptionalRows {
+            outputs: self.index_outputs,
+            spends: self.index_spends,
+        }
+    }
+
+    pub fn reload(&self, store: &dyn ReadStore) -> Result<()> {
+        let headers = read_indexed_headers(store)?;
+        *self.headers.write().unwrap() = Arc::new(headers);
+        Ok(())
+    }
+
+    /// Snapshot of the headers, along with the result of `f` (e.g. a DB snapshot) taken under
+    /// the same lock, so that no update of the headers happens in between.
+    pub fn headers_snapshot<T>(&self, f: impl FnOnce() -> T) -> (Arc<HeaderList>, T) {
+        snapshot_headers(&self.headers, f)
     }
 
     pub fn best_header(&self) -> Option<HeaderEntry> {
@@ -352,6 +1026,17 @@ impl Index {
         headers.header_by_blockhash(&headers.tip()).cloned()
     }
 
+    // Best header and the total work of the chain up to it
+    pub fn best_chainwork(&self) -> Option<(HeaderEntry, Uint256)> {
+        let headers = self.headers.read().unwrap();
+        let best = headers.header_by_blockhash(&headers.tip())?;
+        Some((best.clone(), headers.chainwork()))
+    }
+
+    pub fn median_time_past(&self, height: usize) -> Option<u32> {
+        self.headers.read().unwrap().median_time_past(height)
+    }
+
     pub fn get_header(&self, height: usize) -> Option<HeaderEntry> {
         self.headers
             .read()
@@ -360,6 +1045,30 @@ impl Index {
             .cloned()
     }
 
+    // Headers at heights [start, end) (stops at the tip)
+    pub fn get_headers(&self, start: usize, end: usize) -> Vec<HeaderEntry> {
+        let headers = self.headers.read().unwrap();
+        (start..end)
+            .map_while(|height| headers.header_by_height(height).cloned())
+            .collect()
+    }
+
+    // Check that the headers form a chain from the genesis block (returns their number)
+    pub fn check_headers(&self) -> Result<usize> {
+        let headers = self.headers.read().unwrap();
+        let mut prev_blockhash = Sha256dHash::default();
+        for (height, entry) in headers.iter().enumerate() {
+            if entry.height() != height {
+                bail!("header {} at height {} instead of {}", entry.hash(), entry.height(), height);
+            }
+            if entry.header().prev_blockhash != prev_blockhash {
+                bail!("header {} at height {} follows {}", entry.hash(), height, prev_blockhash);
+            }
+            prev_blockhash = *entry.hash();
+        }
+        Ok(headers.len())
+    }
+
     pub fn get_header_by_block_hash(&self, block_hash: Sha256dHash) -> Option<HeaderEntry> {
         self.headers
             .read()
@@ -368,7 +1077,28 @@ impl Index {
             .cloned()
     }
 
-    pub fn update(&self, store: &impl WriteStore, waiter: &Waiter) -> Result<Sha256dHash> {
+    pub fn update(
+        &self,
+        store: &(impl ReadStore + WriteStore),
+        waiter: &Waiter,
+    ) -> Result<Sha256dHash> {
+        let mut tip = self.update_once(store, waiter)?;
+        // the daemon's tip may have advanced while indexing: index the new blocks right away
+        for _ in 0..self.tip_retries {
+            if self.daemon.getbestblockhash()? == tip {
+                break;
+            }
+            debug!("best block changed while indexing up to {}, continuing", tip);
+            tip = self.update_once(store, waiter)?;
+        }
+        Ok(tip)
+    }
+
+    fn update_once(
+        &self,
+        store: &(impl ReadStore + WriteStore),
+        waiter: &Waiter,
+    ) -> Result<Sha256dHash> {
         let daemon = self.daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
 
@@ -381,47 +1111,467 @@ impl Index {
             info!("{:?} ({} left to index)", latest_header, new_headers.len());
         };
 
-        let chan = SyncChannel::new(1);
+        let chan = SyncChannel::new(self.prefetch);
         let sender = chan.sender();
         let blockhashes: Vec<Sha256dHash> = new_headers.iter().map(|h| *h.hash()).collect();
-        let batch_size = self.batch_size;
+        let (batch_size, batch_bytes) = (self.batch_size, self.batch_bytes);
+        let index_filters = self.index_filters;
 
         let fetcher = spawn_thread("fetcher", move || {
-            for chunk in blockhashes.chunks(batch_size) {
-                sender
-                    .send(daemon.getblocks(&chunk))
-                    .expect("failed sending blocks to be indexed");
+            // start small if batches are bounded by size, until block sizes are known
+            let mut count = if batch_bytes.is_some() { 1 } else { batch_size };
+            let mut remaining = &blockhashes[..];
+            while !remaining.is_empty() {
+                let (chunk, rest) = remaining.split_at(count.min(remaining.len()));
+                remaining = rest;
+                let batch = daemon.getblocks(&chunk).and_then(|blocks| {
+                    let mut filter_rows = vec![];
+                    if index_filters {
+                        for block in &blocks {
+                            filter_rows.push(block_filter_row(block, &daemon)?);
+                        }
+                    }
+                    Ok((blocks, filter_rows))
+                });
+                if let (Some(max_bytes), Ok((blocks, _))) = (batch_bytes, &batch) {
+                    count = bounded_batch_size(blocks, max_bytes, batch_size);
+                }
+                let failed = batch.is_err();
+                if sender.send(batch).is_err() || failed {
+                    return; // the indexing stopped (or will stop on the error)
+                }
             }
             sender
-                .send(Ok(vec![]))
+                .send(Ok((vec![], vec![])))
                 .expect("failed sending explicit end of stream");
         });
 
         loop {
             waiter.poll()?;
 
-            let batch = chan
+            let fetched = chan
                 .receiver()
                 .recv()
-                .expect("block fetch exited prematurely")?;
+                .expect("block fetch exited prematurely");
+            let (batch, filter_rows) = match fetched {
+                Ok(fetched) => fetched,
+                // the requested blocks may have left the best chain (e.g. "Block not found on
+                // disk" during a reorg): keep the indexed headers, and retry on the next update
+                Err(e) if self.daemon.getbestblockhash()? != tip => {
+                    warn!("best chain changed while fetching blocks, retrying: {}", e);
+                    fetcher.join().expect("block fetcher failed");
+                    return Ok(self.headers.read().unwrap().tip());
+                }
+                Err(e) => return Err(e),
+            };
 
             if batch.is_empty() {
                 break;
             }
 
-            let rows_iter = batch.iter().flat_map(|block| {
-                let blockhash = block.bitcoin_hash();
-                info!("indexing block {}", blockhash);
-                index_block(block).chain(std::iter::once(last_indexed_block(&blockhash)))
-            });
+            let mut stored_outputs = vec![];
+            if self.index_outputs {
+                for block in &batch {
+                    stored_outputs.extend(output_rows(block, &self.script_hash_filter));
+                }
+            }
+            let mut spends = vec![];
+            if self.index_spends {
+                spends.extend(batch.iter().flat_map(spend_rows));
+            }
+            let mut commitment_rows = vec![];
+            if self.index_witness_commitments {
+                commitment_rows.extend(batch.iter().filter_map(witness_commitment_row));
+            }
+            let rows_iter = batch
+                .iter()
+                .flat_map(|block| {
+                    let blockhash = block.bitcoin_hash();
+                    info!("indexing block {}", blockhash);
+                    index_block(block, &self.script_hash_filter)
+                        .chain(std::iter::once(last_indexed_block(&blockhash)))
+                })
+                .chain(filter_rows)
+                .chain(stored_outputs)
+                .chain(spends)
+                .chain(commitment_rows);
 
-            store.write(rows_iter);
+            store.write(rows_iter)?;
         }
 
-        store.flush(); // make sure no row is left behind
         fetcher.join().expect("block fetcher failed");
-        self.headers.write().unwrap().apply(new_headers, tip);
-        assert_eq!(tip, self.headers.read().unwrap().tip());
+        let checkpoint = new_headers
+            .iter()
+            .any(|h| h.height() % CHECKPOINT_INTERVAL == 0);
+        {
+            // the indexed headers may have changed meanwhile (e.g. by a concurrent update or
+            // rollback): keep them, and retry on the next update instead of crashing
+            let mut headers = self.headers.write().unwrap();
+            if !headers.connects(&new_headers) {
+                warn!("indexed headers changed while indexing up to {}, retrying", tip);
+                return Ok(headers.tip());
+            }
+            Arc::make_mut(&mut headers).apply(new_headers, tip);
+        }
+        if checkpoint {
+            self.write_checkpoint(store)?;
+        }
+        store.flush()?; // make sure no row is left behind
         Ok(tip)
     }
+
+    /// Rolls the indexed headers back to the last one on the daemon's best chain, if the
+    /// indexed tip isn't on it (e.g. after a deep reorg while the indexer was stopped), so that
+    /// the abandoned blocks aren't served. Returns the number of rolled back headers.
+    pub fn rollback_diverged(&self, store: &impl WriteStore) -> Result<usize> {
+        let mut headers = self.headers.write().unwrap();
+        let (best_height, _) = self.daemon.get_best_block()?;
+        let height = match diverged_ancestor(&headers, best_height, |height| {
+            self.daemon.getblockhash(height)
+        })? {
+            Some(height) => height,
+            None => return Ok(0),
+        };
+        let ancestor = *headers.header_by_height(height).unwrap().hash();
+        let count = headers.len() - 1 - height;
+        warn!(
+            "indexed tip {} isn't on the best chain, rolling {} blocks back to {}",
+            headers.tip(),
+            count,
+            ancestor
+        );
+        Arc::make_mut(&mut headers).apply(vec![], ancestor);
+        store.write(vec![last_indexed_block(&ancestor)])?;
+        store.flush()?;
+        Ok(count)
+    }
+
+    pub fn write_checkpoint(&self, store: &(impl ReadStore + WriteStore)) -> Result<()> {
+        let headers = self.headers.read().unwrap();
+        let rows = checkpoint_rows(store, &headers)?;
+        debug!(
+            "writing header checkpoint at height {} ({} chunks)",
+            headers.len() as isize - 1,
+            rows.len()
+        );
+        store.write(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{blocks, chain, transaction, MemStore};
+
+    #[test]
+    fn test_skip_large_vouts() {
+        let txn = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut::default(); u16::MAX as usize + 2],
+        };
+        let filter = ScriptHashFilter::default();
+        let rows: Vec<Row> = index_transaction(&txn, &Sha256dHash::default(), &filter).collect();
+        let outputs = rows.iter().filter(|row| row.key[0] == b'O').count();
+        assert_eq!(outputs, u16::MAX as usize + 1);
+    }
+
+    #[test]
+    fn test_output_rows() {
+        let txn = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 1000,
+                    script_pubkey: Script::from(vec![0x51]), // OP_TRUE
+                },
+                TxOut::default(),
+            ],
+        };
+        let mut block = blocks(1).remove(0);
+        block.txdata.push(txn.clone());
+        let store = MemStore::default();
+        store.write(output_rows(&block, &ScriptHashFilter::default())).unwrap();
+        let stored = store.get(&output_key(&txn.txid(), 0)).unwrap().unwrap();
+        let stored: TxOut = deserialize(&stored).unwrap();
+        assert_eq!(stored, txn.output[0]);
+        assert!(store.get(&output_key(&txn.txid(), 2)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_init_spend_rows() {
+        let store = MemStore::default();
+        assert!(!has_spend_rows(&store).unwrap());
+        assert!(init_spend_rows(&store, true).unwrap());
+        assert!(has_spend_rows(&store).unwrap());
+        assert!(init_spend_rows(&store, true).unwrap());
+
+        // a run without them leaves the blocks it indexes without spend rows
+        assert!(!init_spend_rows(&store, false).unwrap());
+        assert!(!has_spend_rows(&store).unwrap());
+        assert!(!init_spend_rows(&store, true).unwrap());
+        assert!(!has_spend_rows(&store).unwrap());
+
+        // the blocks indexed without them would miss their spend rows
+        let store = MemStore::default();
+        store.write(index_block(&blocks(1)[0], &ScriptHashFilter::default())).unwrap();
+        assert!(!init_spend_rows(&store, true).unwrap());
+        assert!(!has_spend_rows(&store).unwrap());
+    }
+
+    #[test]
+    fn test_witness_commitment() {
+        let commitment = vec![7u8; 32];
+        let script = Script::from([&WITNESS_COMMITMENT_HEADER[..], &commitment[..]].concat());
+        let other = Script::from(vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed]); // too short
+        let coinbase = transaction(&[], &[(&script, 0), (&other, 0)]);
+        let blocks = chain(vec![vec![coinbase], vec![transaction(&[], &[(&other, 0)])], vec![]]);
+        assert_eq!(witness_commitment(&blocks[0]), Some(commitment.clone()));
+        assert_eq!(witness_commitment(&blocks[1]), None);
+        assert_eq!(witness_commitment(&blocks[2]), None);
+
+        let row = witness_commitment_row(&blocks[0]).unwrap();
+        assert_eq!(row.key, witness_commitment_key(&blocks[0].bitcoin_hash()));
+        assert_eq!(row.value, commitment);
+        assert!(witness_commitment_row(&blocks[1]).is_none());
+    }
+
+    #[test]
+    fn test_bounded_batch_size() {
+        let blocks = blocks(10); // 81 bytes each (header and empty tx count)
+        assert_eq!(bounded_batch_size(&blocks, 810, 100), 10);
+        assert_eq!(bounded_batch_size(&blocks, 810, 5), 5);
+        assert_eq!(bounded_batch_size(&blocks, 10, 100), 1);
+        assert_eq!(bounded_batch_size(&[], 810, 100), 100);
+    }
+
+    #[test]
+    fn test_repair_last_indexed() {
+        let store = MemStore::default();
+        let blocks = blocks(5);
+        assert_eq!(repair_last_indexed(&store).unwrap(), None);
+
+        // an interrupted import, missing the 4th block
+        for block in blocks.iter().filter(|block| block.header.nonce != 3) {
+            store.write(index_block(block, &ScriptHashFilter::default())).unwrap();
+        }
+        assert_eq!(repair_last_indexed(&store).unwrap(), Some(blocks[2].bitcoin_hash()));
+        assert_eq!(repair_last_indexed(&store).unwrap(), None);
+        assert_eq!(read_indexed_headers(&store).unwrap().len(), 3);
+
+        store.write(index_block(&blocks[3], &ScriptHashFilter::default())).unwrap();
+        assert_eq!(repair_last_indexed(&store).unwrap(), Some(blocks[4].bitcoin_hash()));
+        assert_eq!(read_indexed_headers(&store).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_script_types() {
+        let scripts = [
+            // P2PKH (of the genesis block coinbase address)
+            "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac",
+            // P2SH
+            "a914748284390f9e263a4b766a75d0633c50426eb87587",
+            // P2WPKH
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+            // P2WSH
+            "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+            // P2TR
+            "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c",
+            // bare 1-of-1 multisig
+            "51210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179851ae",
+            // OP_RETURN
+            "6a0443545250",
+        ];
+        let txn = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: scripts
+                .iter()
+                .map(|script| TxOut {
+                    value: 0,
+                    script_pubkey: Script::from(hex::decode(script).unwrap()),
+                })
+                .collect(),
+        };
+        let store = MemStore::default();
+        let filter = ScriptHashFilter::default();
+        store.write(index_transaction(&txn, &Sha256dHash::default(), &filter)).unwrap();
+        let txid_prefix = hash_prefix(&txn.txid()[..]);
+        for (vout, output) in txn.output.iter().enumerate() {
+            let script_hash = compute_script_hash(&output.script_pubkey[..]);
+            let rows = store.scan(&TxOutRow::filter(&script_hash)).unwrap();
+            assert_eq!(rows.len(), 1, "{}", scripts[vout]);
+            let row = TxOutRow::from_row(&rows[0]).unwrap();
+            assert_eq!(row.txid_prefix, txid_prefix);
+            assert_eq!(row.vout as usize, vout);
+        }
+        // script hashes are the SHA256 of the whole scriptPubKey (as in Electrum's protocol)
+        assert_eq!(
+            hex::encode(compute_script_hash(&txn.output[0].script_pubkey[..])),
+            "6191c3b590bfcfa0475e877c302da1e323497acf3b42c08d8fa28e364edf018b"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_txids() {
+        use bitcoin_hashes::hex::FromHex;
+        let store = MemStore::default();
+        let blockhashes: Vec<Sha256dHash> = blocks(3).iter().map(|b| b.bitcoin_hash()).collect();
+        // known duplicate coinbase transactions (see BIP30)
+        let txids = [
+            "d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599",
+            "e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468",
+        ];
+        for txid in &txids {
+            let txid = Sha256dHash::from_hex(txid).unwrap();
+            store.write(vec![
+                TxRow::new(&txid, &blockhashes[0]).to_row(),
+                TxRow::new(&txid, &blockhashes[1]).to_row(),
+            ]).unwrap();
+        }
+        for txid in &txids {
+            let txid = Sha256dHash::from_hex(txid).unwrap();
+            let rows: Vec<TxRow> = store
+                .scan(&TxRow::filter_full(&txid))
+                .unwrap()
+                .iter()
+                .map(|row| TxRow::from_row(row).unwrap())
+                .collect();
+            assert!(rows.iter().all(|row| row.key.txid == full_hash(&txid[..])));
+            let mut found: Vec<FullHash> = rows.iter().map(|row| row.block_hash).collect();
+            found.sort();
+            let mut expected: Vec<FullHash> =
+                blockhashes[..2].iter().map(|hash| full_hash(&hash[..])).collect();
+            expected.sort();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_diverged_ancestor() {
+        use bitcoin_hashes::Hash;
+
+        let blocks = blocks(5);
+        let mut headers = HeaderList::empty();
+        let entries = headers.order(blocks.iter().map(|block| block.header).collect());
+        headers.apply(entries, blocks[4].bitcoin_hash());
+
+        // the daemon's chain forks after the 3rd block
+        let fork = |height: usize| {
+            Ok(if height <= 2 {
+                blocks[height].bitcoin_hash()
+            } else {
+                Sha256dHash::hash(&[height as u8])
+            })
+        };
+        assert_eq!(diverged_ancestor(&headers, 4, fork).unwrap(), Some(2));
+        assert_eq!(diverged_ancestor(&headers, 6, fork).unwrap(), Some(2));
+
+        // a daemon behind the index hasn't diverged
+        let same = |height: usize| Ok(blocks[height].bitcoin_hash());
+        assert_eq!(diverged_ancestor(&headers, 4, same).unwrap(), None);
+        assert_eq!(diverged_ancestor(&headers, 1, same).unwrap(), None);
+        assert_eq!(diverged_ancestor(&HeaderList::empty(), 4, same).unwrap(), None);
+
+        let other = |height: usize| Ok(Sha256dHash::hash(&[height as u8]));
+        assert!(diverged_ancestor(&headers, 4, other).is_err());
+    }
+
+    #[test]
+    fn test_headers_snapshot() {
+        let blocks = blocks(4);
+        let store = MemStore::default();
+        let mut list = HeaderList::empty();
+        for block in &blocks[..3] {
+            store.write(index_block(block, &ScriptHashFilter::default())).unwrap();
+        }
+        let entries = list.order(blocks[..3].iter().map(|block| block.header).collect());
+        list.apply(entries, blocks[2].bitcoin_hash());
+        let headers = RwLock::new(Arc::new(list));
+
+        let (snapshot, rows) = snapshot_headers(&headers, || store.rows());
+        // a block is indexed after the snapshot, then the headers are rolled back
+        store.write(index_block(&blocks[3], &ScriptHashFilter::default())).unwrap();
+        {
+            let mut headers = headers.write().unwrap();
+            let entries = headers.order(vec![blocks[3].header]);
+            Arc::make_mut(&mut headers).apply(entries, blocks[3].bitcoin_hash());
+        }
+        assert_eq!(headers.read().unwrap().tip(), blocks[3].bitcoin_hash());
+        Arc::make_mut(&mut headers.write().unwrap()).apply(vec![], blocks[1].bitcoin_hash());
+
+        assert_eq!(snapshot.tip(), blocks[2].bitcoin_hash());
+        assert_eq!(snapshot.len(), 3);
+        assert!(snapshot.header_by_blockhash(&blocks[3].bitcoin_hash()).is_none());
+        assert!(rows.len() < store.rows().len());
+        assert_eq!(headers.read().unwrap().tip(), blocks[1].bitcoin_hash());
+    }
+
+    #[test]
+    fn test_header_checkpoint() {
+        let blocks = blocks(2100);
+        let filter = ScriptHashFilter::default();
+        let store = MemStore::default();
+        let index = |blocks: &[Block]| {
+            for block in blocks {
+                store.write(index_block(block, &filter)).unwrap();
+            }
+            let tip = blocks.last().unwrap().bitcoin_hash();
+            store.write(vec![last_indexed_block(&tip)]).unwrap();
+            read_indexed_headers(&store).unwrap()
+        };
+
+        // all the chunks are written at first, then only the ones that changed
+        let headers = index(&blocks[..1500]);
+        let rows = checkpoint_rows(&store, &headers).unwrap();
+        assert_eq!(rows.len(), 2);
+        store.write(rows).unwrap();
+        assert!(checkpoint_rows(&store, &headers).unwrap().is_empty());
+        let headers = index(&blocks[1500..]);
+        assert_eq!(headers.len(), 2100);
+        let rows = checkpoint_rows(&store, &headers).unwrap();
+        let keys: Vec<Bytes> = rows.iter().map(|row| row.key.clone()).collect();
+        assert_eq!(keys, vec![checkpoint_key(2), checkpoint_key(1)]);
+        store.write(rows).unwrap();
+
+        let tip = blocks[2099].bitcoin_hash();
+        let scanned = scan_indexed_headers(&store, &tip).unwrap();
+        assert_eq!(read_checkpoint_headers(&store, &tip).unwrap(), Some(scanned.clone()));
+        // a stale chunk (e.g. after a reorg to a shorter chain) is ignored
+        let value = store.get(&checkpoint_key(0)).unwrap().unwrap();
+        store.write(vec![Row { key: checkpoint_key(3), value }]).unwrap();
+        assert_eq!(read_checkpoint_headers(&store, &tip).unwrap(), Some(scanned));
+    }
+
+    #[test]
+    fn test_export_import_headers() {
+        let blocks = blocks(5);
+        let filter = ScriptHashFilter::default();
+        let indexed = MemStore::default();
+        for block in &blocks {
+            indexed.write(index_block(block, &filter)).unwrap();
+        }
+        indexed.write(vec![last_indexed_block(&blocks[4].bitcoin_hash())]).unwrap();
+
+        let path = std::env::temp_dir().join(format!("headers-{}.bin", std::process::id()));
+        assert_eq!(export_headers(&indexed, &path).unwrap(), 5);
+        assert!(import_headers(&indexed, &path).is_err()); // not an empty DB
+
+        let fresh = MemStore::default();
+        assert_eq!(import_headers(&fresh, &path).unwrap(), 5);
+        let imported = read_imported_headers(&fresh).unwrap();
+        assert_eq!(imported.tip(), blocks[4].bitcoin_hash());
+        assert!(read_indexed_headers(&fresh).unwrap().is_empty());
+
+        // the imported headers must be a chain
+        let mut value = fs::read(&path).unwrap();
+        value.drain(HEADER_LEN..2 * HEADER_LEN);
+        fs::write(&path, value).unwrap();
+        assert!(import_headers(&MemStore::default(), &path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
 }
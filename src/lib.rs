@@ -24,3 +24,7 @@ pub mod rpc;
 pub mod signal;
 pub mod store;
 pub mod util;
+pub mod verify;
+
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;
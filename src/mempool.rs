@@ -1,13 +1,15 @@
-use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use bitcoin::consensus::encode::Encodable;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
 use hex;
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::iter::FromIterator;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
 use std::ops::Bound;
 
-use crate::daemon::Daemon;
+use crate::daemon::{Daemon, MempoolEntry};
 use crate::errors::*;
-use crate::index::index_transaction;
+use crate::index::{index_transaction, ScriptHashFilter, TxInRow, TxRow};
 use crate::store::{ReadStore, Row};
 use crate::util::Bytes;
 
@@ -27,7 +29,8 @@ impl MempoolStore {
     }
 
     fn add(&mut self, tx: &Transaction) {
-        let rows = index_transaction(tx, &Sha256dHash::default());
+        let filter = ScriptHashFilter::default();
+        let rows = index_transaction(tx, &Sha256dHash::default(), &filter);
         for row in rows {
             let (key, value) = row.into_pair();
             self.map.entry(key).or_insert_with(|| vec![]).push(value);
@@ -35,7 +38,8 @@ impl MempoolStore {
     }
 
     fn remove(&mut self, tx: &Transaction) {
-        let rows = index_transaction(tx, &Sha256dHash::default());
+        let filter = ScriptHashFilter::default();
+        let rows = index_transaction(tx, &Sha256dHash::default(), &filter);
         for row in rows {
             let (key, value) = row.into_pair();
             let no_values_left = {
@@ -64,11 +68,12 @@ impl MempoolStore {
 }
 
 impl ReadStore for MempoolStore {
-    fn get(&self, key: &[u8]) -> Option<Bytes> {
-        Some(self.map.get(key)?.last()?.to_vec())
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let values = self.map.get(key);
+        Ok(values.and_then(|values| values.last()).map(|value| value.to_vec()))
     }
 
-    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Row>> {
         let range = self
             .map
             .range((Bound::Included(prefix.to_vec()), Bound::Unbounded));
@@ -84,16 +89,135 @@ impl ReadStore for MempoolStore {
                 });
             }
         }
-        rows
+        Ok(rows)
     }
 }
 
+//
+// Fee histogram of the mempool
+// (bins of at least VSIZE_BIN_WIDTH vbytes by decreasing fee rate, as in Electrum's protocol)
+//
+const VSIZE_BIN_WIDTH: u64 = 100_000;
+
+fn fee_histogram<'a>(entries: impl Iterator<Item = &'a MempoolEntry>) -> Vec<(f32, u64)> {
+    let mut entries: Vec<&MempoolEntry> = entries.collect();
+    entries.sort_unstable_by(|e1, e2| e2.fee_per_vbyte().partial_cmp(&e1.fee_per_vbyte()).unwrap());
+    let mut histogram = vec![];
+    let mut bin_size = 0;
+    let mut last_fee_rate = None;
+    for entry in entries {
+        let fee_rate = entry.fee_per_vbyte();
+        if let Some(last_fee_rate) = last_fee_rate {
+            if bin_size > VSIZE_BIN_WIDTH && last_fee_rate != fee_rate {
+                histogram.push((last_fee_rate, bin_size));
+                bin_size = 0;
+            }
+        }
+        last_fee_rate = Some(fee_rate);
+        bin_size += entry.vsize();
+    }
+    if let Some(fee_rate) = last_fee_rate {
+        histogram.push((fee_rate, bin_size));
+    }
+    histogram
+}
+
+//
+// Mempool changes fetched from the daemon
+// (may be applied to the tracker in several batches, to release its lock in between)
+//
+pub struct MempoolUpdate {
+    added: Vec<(Transaction, MempoolEntry)>,
+    removed: Vec<Sha256dHash>,
+}
+
+impl MempoolUpdate {
+    pub fn new(
+        added: Vec<(Transaction, MempoolEntry)>,
+        removed: Vec<Sha256dHash>,
+    ) -> MempoolUpdate {
+        MempoolUpdate { added, removed }
+    }
+
+    /// Changes of the daemon's mempool since `old_txids`
+    /// (None if the transactions couldn't be fetched, e.g. because of a new block or RBF)
+    pub fn fetch(
+        daemon: &Daemon,
+        old_txids: &HashSet<Sha256dHash>,
+    ) -> Result<Option<MempoolUpdate>> {
+        let new_txids = daemon
+            .getmempooltxids()
+            .chain_err(|| "failed to update mempool from daemon")?;
+        let txids: Vec<&Sha256dHash> = new_txids.difference(old_txids).collect();
+
+        let fetched = daemon
+            .gettransactions(&txids)
+            .and_then(|txs| Ok((txs, daemon.getmempoolentries(&txids)?)));
+        let (txs, entries) = match fetched {
+            Ok(fetched) => fetched,
+            Err(err) => {
+                warn!("failed to get transactions {:?}: {}", txids, err);
+                return Ok(None);
+            }
+        };
+
+        trace!("updated mempool with {} transactions from daemon", txs.len());
+        let mut txs: HashMap<Sha256dHash, Transaction> =
+            txs.into_iter().map(|tx| (tx.txid(), tx)).collect();
+        let mut added = vec![];
+        for (txid, entry) in txids.into_iter().zip(entries) {
+            match (txs.remove(txid), entry) {
+                (Some(tx), Some(entry)) => added.push((tx, entry)),
+                // e.g. evicted meanwhile (or else requested again on next update)
+                _ => warn!("daemon did not return mempool tx {}", txid),
+            }
+        }
+        let removed = old_txids.difference(&new_txids).cloned().collect();
+        Ok(Some(MempoolUpdate { added, removed }))
+    }
+
+    /// Split into updates of at most `batch_size` transactions (the additions come first, so
+    /// that a replacement is added while the transaction it replaces is still tracked)
+    pub fn into_batches(self, batch_size: usize) -> Vec<MempoolUpdate> {
+        let batch_size = batch_size.max(1);
+        let mut batches = vec![];
+        let mut added = self.added.into_iter().peekable();
+        while added.peek().is_some() {
+            batches.push(MempoolUpdate {
+                added: added.by_ref().take(batch_size).collect(),
+                removed: vec![],
+            });
+        }
+        batches.extend(self.removed.chunks(batch_size).map(|removed| MempoolUpdate {
+            added: vec![],
+            removed: removed.to_vec(),
+        }));
+        batches
+    }
+}
+
+// Number of outputs spent by the transactions that left the mempool, kept to find their
+// replacements arriving in a later update (e.g. after failing to fetch them)
+const EVICTED_SPENDS: usize = 100_000;
+
 //
 // Tracker managing mempool transactions
 //
 pub struct Tracker {
     items: HashMap<Sha256dHash, Transaction>,
     index: MempoolStore,
+    // double-spent txid -> txids of the conflicting transactions
+    conflicts: HashMap<Sha256dHash, HashSet<Sha256dHash>>,
+    // outputs spent by the latest removed transactions (oldest first)
+    evicted: HashMap<OutPoint, Sha256dHash>,
+    evicted_order: VecDeque<OutPoint>,
+    entries: HashMap<Sha256dHash, MempoolEntry>,
+    histogram: Vec<(f32, u64)>, // rebuilt on each update
+    size: usize,                // total serialized size of the transactions
+}
+
+fn serialized_size(tx: &Transaction) -> usize {
+    tx.consensus_encode(io::sink()).unwrap()
 }
 
 impl Tracker {
@@ -101,6 +225,12 @@ impl Tracker {
         Tracker {
             items: HashMap::new(),
             index: MempoolStore::new(),
+            conflicts: HashMap::new(),
+            evicted: HashMap::new(),
+            evicted_order: VecDeque::new(),
+            entries: HashMap::new(),
+            histogram: vec![],
+            size: 0,
         }
     }
 
@@ -108,42 +238,101 @@ impl Tracker {
         &self.index
     }
 
-    pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
-        let new_txids = daemon
-            .getmempooltxids()
-            .chain_err(|| "failed to update mempool from daemon")?;
+    pub fn get_txn(&self, txid: &Sha256dHash) -> Option<&Transaction> {
+        self.items.get(txid)
+    }
+
+    // Number of transactions and their total serialized size
+    pub fn info(&self) -> (usize, usize) {
+        (self.items.len(), self.size)
+    }
 
-        let old_txids = HashSet::from_iter(self.items.keys().cloned());
+    pub fn get_entry(&self, txid: &Sha256dHash) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
 
-        let txids_iter = new_txids.difference(&old_txids);
+    pub fn fee_histogram(&self) -> &[(f32, u64)] {
+        &self.histogram
+    }
 
-        let txids: Vec<&Sha256dHash> = txids_iter.collect();
+    // Transactions double-spending the given one (while they're in the mempool)
+    pub fn conflicts(&self, txid: &Sha256dHash) -> Vec<Sha256dHash> {
+        match self.conflicts.get(txid) {
+            Some(txids) => txids.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
 
-        let txs = match daemon.gettransactions(&txids) {
-            Ok(txs) => txs,
-            Err(err) => {
-                warn!("failed to get transactions {:?}: {}", txids, err); // e.g. new block or RBF
-                return Ok(()); // keep the mempool until next update()
+    // Find the tracked (or recently removed) transactions spending an input of the given one
+    fn find_conflicts(&self, tx: &Transaction) -> Result<HashSet<Sha256dHash>> {
+        let mut result = HashSet::new();
+        let own_txid = tx.txid(); // e.g. back in the mempool after a reorg
+        for input in &tx.input {
+            let outpoint = &input.previous_output;
+            match self.evicted.get(outpoint) {
+                Some(txid) if *txid != own_txid => {
+                    result.insert(*txid);
+                }
+                _ => (),
             }
-        };
+            let rows = self
+                .index
+                .scan(&TxInRow::filter(&outpoint.txid, outpoint.vout as usize))?;
+            for row in rows {
+                let txid_prefix = TxInRow::from_row(&row)?.txid_prefix;
+                for row in self.index.scan(&TxRow::filter_prefix(txid_prefix))? {
+                    let txid = Sha256dHash::from_inner(TxRow::from_row(&row)?.key.txid);
+                    if let Some(other) = self.items.get(&txid) {
+                        if other.input.iter().any(|i| i.previous_output == *outpoint) {
+                            result.insert(txid);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
 
-        trace!("updated mempool with {} transactions from daemon", txs.len());
+    pub fn txids(&self) -> HashSet<Sha256dHash> {
+        self.items.keys().cloned().collect()
+    }
 
-        for (txid, tx) in txids.into_iter().zip(txs.into_iter()) {
-            assert_eq!(tx.txid(), *txid);
-            self.add(txid, tx);
+    /// Returns true if any transaction was added to or removed from the mempool.
+    /// (the transactions already added or removed, e.g. by a concurrent update, are skipped,
+    /// and the additions come first so that the replaced transactions are found as conflicts)
+    pub fn apply(&mut self, update: MempoolUpdate) -> Result<bool> {
+        let mut changed = false;
+        for (tx, entry) in update.added {
+            let txid = tx.txid();
+            if self.items.contains_key(&txid) {
+                continue;
+            }
+            self.entries.insert(txid, entry);
+            self.add(&txid, tx)?;
+            changed = true;
         }
-
-        for txid in old_txids.difference(&new_txids) {
-            self.remove(txid);
+        for txid in &update.removed {
+            if self.items.contains_key(txid) {
+                self.remove(txid);
+                changed = true;
+            }
         }
+        Ok(changed)
+    }
 
-        Ok(())
+    pub fn update_histogram(&mut self) {
+        self.histogram = fee_histogram(self.entries.values());
     }
 
-    fn add(&mut self, txid: &Sha256dHash, tx: Transaction) {
+    fn add(&mut self, txid: &Sha256dHash, tx: Transaction) -> Result<()> {
+        for conflict in self.find_conflicts(&tx)? {
+            info!("mempool tx {} double-spends {}", txid, conflict);
+            self.conflicts.entry(conflict).or_default().insert(*txid);
+        }
         self.index.add(&tx);
+        self.size += serialized_size(&tx);
         self.items.insert(*txid, tx);
+        Ok(())
     }
 
     fn remove(&mut self, txid: &Sha256dHash) {
@@ -152,5 +341,119 @@ impl Tracker {
             .remove(txid)
             .unwrap_or_else(|| panic!("missing mempool tx {}", txid));
         self.index.remove(&tx);
+        self.size -= serialized_size(&tx);
+        self.entries.remove(txid);
+        for input in &tx.input {
+            self.evicted.insert(input.previous_output, *txid);
+            self.evicted_order.push_back(input.previous_output);
+        }
+        while self.evicted_order.len() > EVICTED_SPENDS {
+            let outpoint = self.evicted_order.pop_front().unwrap();
+            self.evicted.remove(&outpoint);
+        }
+        // forget the double-spends once the conflicting transactions are gone
+        self.conflicts.retain(|_, txids| {
+            txids.remove(txid);
+            !txids.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+
+    // Transaction spending the given outpoint (`lock_time` makes the txid unique)
+    fn spending_tx(vout: u32, lock_time: u32) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Sha256dHash::hash(b"funding"), vout),
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut::default()],
+        }
+    }
+
+    fn added(txs: &[Transaction]) -> MempoolUpdate {
+        MempoolUpdate {
+            added: txs
+                .iter()
+                .map(|tx| (tx.clone(), MempoolEntry::new(1_000, 100)))
+                .collect(),
+            removed: vec![],
+        }
+    }
+
+    #[test]
+    fn test_apply_batches() {
+        let txs = [spending_tx(0, 0), spending_tx(1, 0), spending_tx(0, 1)];
+        let batches = added(&txs).into_batches(2);
+        assert_eq!(batches.len(), 2);
+        let mut tracker = Tracker::new();
+        for batch in batches {
+            assert!(tracker.apply(batch).unwrap());
+        }
+        assert!(!tracker.apply(added(&txs[..1])).unwrap()); // already added
+        assert_eq!(tracker.info().0, 3);
+        assert_eq!(tracker.conflicts(&txs[0].txid()), vec![txs[2].txid()]);
+
+        let removed = MempoolUpdate {
+            added: vec![],
+            removed: vec![txs[2].txid()],
+        };
+        assert!(tracker.apply(removed).unwrap());
+        assert!(tracker.conflicts(&txs[0].txid()).is_empty());
+        assert_eq!(tracker.txids().len(), 2);
+    }
+
+    #[test]
+    fn test_replacement() {
+        // the replacement and the eviction of the replaced transaction come in one update
+        let (replaced, replacement) = (spending_tx(0, 0), spending_tx(0, 1));
+        for batch_size in &[1, 10] {
+            let mut tracker = Tracker::new();
+            assert!(tracker.apply(added(std::slice::from_ref(&replaced))).unwrap());
+            let mut update = added(std::slice::from_ref(&replacement));
+            update.removed.push(replaced.txid());
+            for batch in update.into_batches(*batch_size) {
+                assert!(tracker.apply(batch).unwrap());
+            }
+            assert_eq!(tracker.txids(), vec![replacement.txid()].into_iter().collect());
+            assert_eq!(tracker.conflicts(&replaced.txid()), vec![replacement.txid()]);
+        }
+
+        // the replacement comes after the eviction
+        let mut tracker = Tracker::new();
+        assert!(tracker.apply(added(std::slice::from_ref(&replaced))).unwrap());
+        let evicted = MempoolUpdate {
+            added: vec![],
+            removed: vec![replaced.txid()],
+        };
+        assert!(tracker.apply(evicted).unwrap());
+        assert!(tracker.apply(added(std::slice::from_ref(&replacement))).unwrap());
+        assert_eq!(tracker.conflicts(&replaced.txid()), vec![replacement.txid()]);
+    }
+
+    #[test]
+    fn test_fee_histogram() {
+        assert!(fee_histogram(std::iter::empty()).is_empty());
+        let entries = [
+            MempoolEntry::new(10_000, 1_000),
+            MempoolEntry::new(2_000_000, 100_000),
+            MempoolEntry::new(500, 250),
+            MempoolEntry::new(5_000_000, 100_000),
+            MempoolEntry::new(100_000, 100_000),
+        ];
+        // a bin is closed once it's larger than VSIZE_BIN_WIDTH (at the lowest fee rate in it)
+        assert_eq!(
+            fee_histogram(entries.iter()),
+            vec![(20.0, 200_000), (1.0, 101_250)]
+        );
     }
 }
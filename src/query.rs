@@ -1,13 +1,40 @@
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
 use bitcoin::consensus::encode::deserialize;
+use bitcoin::network::constants::Network;
+use bitcoin::util::hash::BitcoinHash;
+use bitcoin::util::uint::Uint256;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
-use std::sync::{Arc, RwLock};
+use bitcoin_hashes::Hash;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::app::App;
 use crate::errors::*;
-use crate::index::{TxInRow, TxOutRow, TxRow};
-use crate::mempool::Tracker;
+use crate::index::{
+    block_filter_key, compute_script_hash, has_spend_rows, output_key, read_last_indexed, spend_key,
+    witness_commitment_key, ScriptHashFilter, Spend, TxInRow, TxOutRow, TxRow,
+};
+use crate::mempool::{MempoolUpdate, Tracker};
 use crate::store::ReadStore;
-use crate::util::{HashPrefix, HeaderEntry};
+use crate::util::{
+    hash_prefix, Bytes, Cancel, FullHash, HashPrefix, HeaderEntry, Semaphore, HASH_PREFIX_LEN,
+};
+use crate::verify::{Report, Verifier};
+
+// Min delay between two mempool refreshes requested by clients
+const MEMPOOL_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// Max number of threads looking up the UTXOs of several script hashes
+const MAX_UTXOS_THREADS: usize = 8;
+
+// Height of a block of the best chain (None for the other blocks, e.g. reorged ones)
+type BlockHeight<'a> = dyn Fn(&Sha256dHash) -> Option<usize> + Sync + 'a;
 
 //
 // Output of a Transaction
@@ -35,22 +62,87 @@ pub struct TxBlockIndex {
     pub blockindex: usize
 }
 
+//
+// Lag of the index behind the daemon
+//
+pub struct IndexLag {
+    pub blocks: usize,
+    pub tip_age: u64,         // seconds since the indexed tip was mined
+    pub daemon_time_lag: u64, // seconds between the indexed tip and the daemon's best block
+}
+
+//
+// Settings of the index and the queries relevant to the clients
+//
+#[derive(Serialize)]
+pub struct Settings {
+    pub network: String,
+    pub txid_limit: usize, // 0 means no limit
+    pub min_confirmations: usize,
+    pub track_mempool: bool,
+    pub index_outputs: bool, // output values and scripts (without querying bitcoind)
+    pub index_filters: bool, // block filters
+    pub index_witness_commitments: bool,
+    pub hash_prefix_len: usize,
+}
+
 //
 // Status of an Address
 // (vectors of confirmed and unconfirmed outputs and inputs)
 //
 pub struct Status {
     confirmed: (Vec<Txo>, Vec<SpendingInput>),
+    pending: (Vec<Txo>, Vec<SpendingInput>), // confirmed, but not deep enough
     mempool: (Vec<Txo>, Vec<SpendingInput>),
 }
 
 impl Status {
     pub fn funding(&self) -> impl Iterator<Item = &Txo> {
-        self.confirmed.0.iter().chain(self.mempool.0.iter())
+        self.confirmed
+            .0
+            .iter()
+            .chain(self.pending.0.iter())
+            .chain(self.mempool.0.iter())
     }
 
     pub fn spending(&self) -> impl Iterator<Item = &SpendingInput> {
-        self.confirmed.1.iter().chain(self.mempool.1.iter())
+        self.confirmed
+            .1
+            .iter()
+            .chain(self.pending.1.iter())
+            .chain(self.mempool.1.iter())
+    }
+
+    // Confirmed outputs and inputs (including the pending ones)
+    pub fn confirmed_funding(&self) -> impl Iterator<Item = &Txo> {
+        self.confirmed.0.iter().chain(self.pending.0.iter())
+    }
+
+    pub fn confirmed_spending(&self) -> impl Iterator<Item = &SpendingInput> {
+        self.confirmed.1.iter().chain(self.pending.1.iter())
+    }
+
+    pub fn mempool_funding(&self) -> impl Iterator<Item = &Txo> {
+        self.mempool.0.iter()
+    }
+
+    pub fn mempool_spending(&self) -> impl Iterator<Item = &SpendingInput> {
+        self.mempool.1.iter()
+    }
+
+    pub fn pending_history(&self) -> Vec<TxBlockIndex> {
+        let mut txns = vec![];
+        for f in self.pending.0.iter() {
+            txns.push((f.blockindex, f.txid));
+        }
+        for s in self.pending.1.iter() {
+            txns.push((s.blockindex, s.txid));
+        }
+        txns.sort_unstable();
+        txns.dedup();
+        txns.into_iter()
+            .map(|(blockindex, txid)| TxBlockIndex { txid, blockindex })
+            .collect()
     }
 
     pub fn history(&self) -> Vec<Sha256dHash> {
@@ -65,38 +157,400 @@ impl Status {
         txns.dedup();
         txns
     }
-    
-    pub fn oldest(&self) -> Option<TxBlockIndex> {
-        let mut min_found = false;
-        let mut min_block_index = 0;
-        let mut min_tx : Option<TxBlockIndex> = None;
-            
-        for f in self.funding() {
-            if !min_found || (min_block_index == 0 && f.blockindex > 0) || f.blockindex < min_block_index {
-                min_block_index = f.blockindex;
-                min_tx = Some(TxBlockIndex{
-                        txid: f.txid,
-                        blockindex: f.blockindex
-                    }
-                );
-                min_found = true;
-            }
+
+    // History ordered by height (the mempool transactions are the most recent ones)
+    pub fn history_by_height(&self, descending: bool) -> Vec<Sha256dHash> {
+        let confirmed = self.confirmed.0.iter().chain(self.pending.0.iter());
+        let mut txns: Vec<(usize, Sha256dHash)> = confirmed
+            .map(|f| (f.blockindex, f.txid))
+            .chain(
+                self.confirmed
+                    .1
+                    .iter()
+                    .chain(self.pending.1.iter())
+                    .map(|s| (s.blockindex, s.txid)),
+            )
+            .chain(self.mempool_history().into_iter().map(|txid| (usize::MAX, txid)))
+            .collect();
+        txns.sort_unstable();
+        txns.dedup();
+        if descending {
+            txns.reverse();
         }
+        txns.into_iter().map(|(_, txid)| txid).collect()
+    }
+
+    pub fn mempool_history(&self) -> Vec<Sha256dHash> {
+        let mut txns = vec![];
+        for f in self.mempool.0.iter() {
+            txns.push(f.txid);
+        }
+        for s in self.mempool.1.iter() {
+            txns.push(s.txid);
+        }
+        txns.sort_unstable();
+        txns.dedup();
+        txns
+    }
+
+    // Hash of the unconfirmed transactions (None if there's none)
+    pub fn mempool_hash(&self) -> Option<FullHash> {
+        let txns = self.mempool_history();
+        if txns.is_empty() {
+            return None;
+        }
+        let mut hash = FullHash::default();
+        let mut sha2 = Sha256::new();
+        for txid in txns {
+            sha2.input(&txid[..]);
+        }
+        sha2.result(&mut hash);
+        Some(hash)
+    }
     
-        for f in self.spending() {
-            if !min_found || (min_block_index == 0 && f.blockindex > 0) || f.blockindex < min_block_index {
-                min_block_index = f.blockindex;
-                min_tx = Some(TxBlockIndex{
-                        txid: f.txid,
-                        blockindex: f.blockindex
-                    }
-                );
-                min_found = true;
+    // Net value change of the script hash in each of its transactions (received minus spent,
+    // so that a transaction both spending and funding it, e.g. a change output, is netted)
+    pub fn value_deltas(
+        &self,
+        mut value: impl FnMut(&Sha256dHash, usize) -> Result<u64>,
+    ) -> Result<HashMap<Sha256dHash, i64>> {
+        let mut deltas = HashMap::new();
+        for txo in self.funding() {
+            *deltas.entry(txo.txid).or_insert(0) += value(&txo.txid, txo.vout)? as i64;
+        }
+        for input in self.spending() {
+            let (txid, vout) = input.outpoint;
+            *deltas.entry(input.txid).or_insert(0) -= value(&txid, vout)? as i64;
+        }
+        Ok(deltas)
+    }
+
+    // Outputs not spent by a confirmed or mempool input
+    pub fn unspent(&self) -> Vec<&Txo> {
+        let spent: HashSet<OutPoint> = self.spending().map(|input| input.outpoint).collect();
+        self.funding()
+            .filter(|txo| !spent.contains(&(txo.txid, txo.vout)))
+            .collect()
+    }
+
+    // Confirmed transactions (including the pending ones) at or after `height`
+    pub fn confirmed_since(&self, height: usize) -> Vec<TxBlockIndex> {
+        let funding = self.confirmed.0.iter().chain(self.pending.0.iter());
+        let spending = self.confirmed.1.iter().chain(self.pending.1.iter());
+        let mut txns: Vec<(usize, Sha256dHash)> = funding
+            .map(|f| (f.blockindex, f.txid))
+            .chain(spending.map(|s| (s.blockindex, s.txid)))
+            .filter(|(blockindex, _)| *blockindex >= height)
+            .collect();
+        txns.sort_unstable();
+        txns.dedup();
+        txns.into_iter()
+            .map(|(blockindex, txid)| TxBlockIndex { txid, blockindex })
+            .collect()
+    }
+
+    // Oldest confirmed transaction (or a mempool one if there are none and `include_mempool`)
+    pub fn oldest(&self, include_mempool: bool) -> Option<TxBlockIndex> {
+        let confirmed_funding = self.confirmed.0.iter().chain(self.pending.0.iter());
+        let confirmed_spending = self.confirmed.1.iter().chain(self.pending.1.iter());
+        let oldest = confirmed_funding
+            .map(|f| (f.blockindex, f.txid))
+            .chain(confirmed_spending.map(|s| (s.blockindex, s.txid)))
+            .min_by_key(|(blockindex, _)| *blockindex);
+        let oldest = oldest.or_else(|| {
+            if !include_mempool {
+                return None;
             }
+            let mempool_funding = self.mempool.0.iter().map(|f| (f.blockindex, f.txid));
+            let mempool_spending = self.mempool.1.iter().map(|s| (s.blockindex, s.txid));
+            mempool_funding.chain(mempool_spending).next()
+        });
+        oldest.map(|(blockindex, txid)| TxBlockIndex { txid, blockindex })
+    }
+}
+
+//
+// Lookups of the rows of a script hash
+//
+fn get_txrows_by_prefix(
+    store: &dyn ReadStore,
+    prefix: HashPrefix
+) -> Result<Vec<TxRow>> {
+    store
+        .scan(&TxRow::filter_prefix(prefix))?
+        .iter()
+        .map(|row| TxRow::from_row(row))
+        .collect()
+}
+
+fn get_txoutrows_by_script_hash(
+    store: &dyn ReadStore,
+    script_hash: &[u8]
+) -> Result<Vec<TxOutRow>> {
+    store
+        .scan(&TxOutRow::filter(script_hash))?
+        .iter()
+        .map(|row| TxOutRow::from_row(row))
+        .collect()
+}
+
+fn get_prefixes_by_funding_txo(
+    store: &dyn ReadStore,
+    txid: &Sha256dHash,
+    vout: usize,
+) -> Result<Vec<HashPrefix>> {
+    store
+        .scan(&TxInRow::filter(&txid, vout))?
+        .iter()
+        .map(|row| Ok(TxInRow::from_row(row)?.txid_prefix))
+        .collect()
+}
+
+/*fn get_txids_by_prefix(
+    store: &dyn ReadStore,
+    prefixes: Vec<HashPrefix>,
+) -> Result<Vec<Sha256dHash>> {
+    let mut txns = vec![];
+    for prefix in prefixes {
+        for tx_row in get_txrows_by_prefix(store, prefix) {
+            let txid: Sha256dHash = deserialize(&tx_row.key.txid).unwrap();
+            txns.push(txid)
+        }
+    }
+    Ok(txns)
+}*/
+
+fn get_txrows_by_prefixes(
+    store: &dyn ReadStore,
+    prefixes: Vec<HashPrefix>,
+) -> Result<Vec<TxRow>> {
+    let mut txns = vec![];
+    for prefix in prefixes {
+        for tx_row in get_txrows_by_prefix(store, prefix)? {
+            txns.push(tx_row)
         }
+    }
+    Ok(txns)
+}
+
+// Height of the block of a transaction row: 0 for the mempool ones (indexed with a null block
+// hash), None for the ones of the blocks not in the best chain (i.e. reorged)
+fn txrow_height(
+    txrow: &TxRow,
+    block_height: &dyn Fn(&Sha256dHash) -> Option<usize>,
+) -> Option<usize> {
+    let blockhash = Sha256dHash::from_inner(txrow.block_hash);
+    if blockhash == Sha256dHash::default() {
+        return Some(0);
+    }
+    block_height(&blockhash)
+}
+
+// Input spending an output, and the outputs funding a script hash, up to a height
+// (`block_height` gives the height of a block of the best chain)
+pub fn lookup_spending_input(
+    store: &dyn ReadStore,
+    txo: &Txo,
+    current_block_index: usize,
+    block_height: &dyn Fn(&Sha256dHash) -> Option<usize>,
+) -> Result<Option<SpendingInput>> {
+
+    let mut spendings = vec![];
+    let prefixes = get_prefixes_by_funding_txo(store, &txo.txid, txo.vout)?;
+    //let txids = get_txids_by_prefix(store, prefixes)?;
+    let txrows = get_txrows_by_prefixes(store, prefixes)?;
+
+    for txrow in &txrows {
+        let block_index = match txrow_height(txrow, block_height) {
+            Some(height) if height <= current_block_index => height,
+            _ => continue, // reorged, or above the requested height
+        };
+        spendings.push(SpendingInput {
+            txid: Sha256dHash::from_inner(txrow.key.txid),
+            outpoint: (txo.txid, txo.vout),
+            blockindex: block_index
+        })
+    }
+
+    assert!(spendings.len() <= 1);
+
+    Ok(if spendings.len() == 1 {
+        Some(spendings.remove(0))
+    } else {
+        None
+    })
+}
+
+// Same as `lookup_spending_input`, with a single get of the output's spend row (see
+// `--index-spends`): the row of a reorged block may be stale, so the inputs are scanned then
+pub fn lookup_indexed_spending_input(
+    store: &dyn ReadStore,
+    txo: &Txo,
+    current_block_index: usize,
+    block_height: &dyn Fn(&Sha256dHash) -> Option<usize>,
+) -> Result<Option<SpendingInput>> {
+    let value = match store.get(&spend_key(&txo.txid, txo.vout))? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let spend: Spend = bincode::deserialize(&value)
+        .chain_err(|| format!("invalid spend row of {}:{}", txo.txid, txo.vout))?;
+    let blockhash = Sha256dHash::from_inner(spend.blockhash);
+    Ok(match block_height(&blockhash) {
+        Some(height) if height <= current_block_index => Some(SpendingInput {
+            txid: Sha256dHash::from_inner(spend.txid),
+            outpoint: (txo.txid, txo.vout),
+            blockindex: height,
+        }),
+        Some(_) => None, // above the requested height
+        None => return lookup_spending_input(store, txo, current_block_index, block_height),
+    })
+}
+
+pub fn lookup_funding_outputs(
+    store: &dyn ReadStore,
+    script_hash: &[u8],
+    current_block_index: usize,
+    block_height: &dyn Fn(&Sha256dHash) -> Option<usize>,
+) -> Result<Vec<Txo>> {
+    let txout_rows = get_txoutrows_by_script_hash(store, script_hash)?;
+
+    let mut result = vec![];
+
+    for row in &txout_rows {
+        //let txids = get_txids_by_prefix(store, vec![row.txid_prefix])?;
+        let txrows = get_txrows_by_prefixes(store, vec![row.txid_prefix])?;
         
-        min_tx
+        for txrow in &txrows {
+            let block_index = match txrow_height(txrow, block_height) {
+                Some(height) if height <= current_block_index => height,
+                _ => continue, // reorged, or above the requested height
+            };
+            result.push(Txo {
+                txid: Sha256dHash::from_inner(txrow.key.txid),
+                vout: row.vout as usize,
+                blockindex: block_index
+            })
+        }
+    }
+
+    Ok(result)
+}
+
+// Funding outputs spent by an input, with a single scan of the inputs per funding transaction
+// (instead of one per output, as `lookup_spending_input`), or a get per output if the spend
+// rows are complete
+pub fn lookup_spent_outputs(
+    store: &dyn ReadStore,
+    txos: &[Txo],
+    block_height: &dyn Fn(&Sha256dHash) -> Option<usize>,
+) -> Result<HashSet<OutPoint>> {
+    if has_spend_rows(store)? {
+        let mut spent = HashSet::new();
+        for txo in txos {
+            let input = lookup_indexed_spending_input(store, txo, usize::MAX, block_height)?;
+            spent.extend(input.map(|input| input.outpoint));
+        }
+        return Ok(spent);
+    }
+    let mut txos_by_prefix: HashMap<HashPrefix, Vec<&Txo>> = HashMap::new();
+    for txo in txos {
+        txos_by_prefix.entry(hash_prefix(&txo.txid[..])).or_default().push(txo);
+    }
+    let mut spent = HashSet::new();
+    for (prefix, txos) in txos_by_prefix {
+        for row in store.scan(&TxInRow::filter_txid_prefix(prefix))? {
+            let row = TxInRow::from_row(&row)?;
+            let vout = row.key.prev_vout as usize;
+            if !txos.iter().any(|txo| txo.vout == vout) {
+                continue;
+            }
+            for txrow in get_txrows_by_prefix(store, row.txid_prefix)? {
+                if txrow_height(&txrow, block_height).is_some() {
+                    let outpoints = txos.iter().filter(|txo| txo.vout == vout);
+                    spent.extend(outpoints.map(|txo| (txo.txid, vout)));
+                    break;
+                }
+            }
+        }
+    }
+    Ok(spent)
+}
+
+//
+// Fail if too many transactions were found (0 means no limit)
+//
+fn check_txid_limit(count: usize, txid_limit: usize) -> Result<()> {
+    if txid_limit > 0 && count > txid_limit {
+        bail!("{}+ transactions found, query may take a long time", count);
+    }
+    Ok(())
+}
+
+// Input spending a confirmed output, from its spend row if the spends are indexed (see
+// `--index-spends`)
+pub fn lookup_confirmed_spending_input(
+    store: &dyn ReadStore,
+    txo: &Txo,
+    current_block_index: usize,
+    block_height: &dyn Fn(&Sha256dHash) -> Option<usize>,
+    indexed_spends: bool,
+) -> Result<Option<SpendingInput>> {
+    if indexed_spends {
+        lookup_indexed_spending_input(store, txo, current_block_index, block_height)
+    } else {
+        lookup_spending_input(store, txo, current_block_index, block_height)
+    }
+}
+
+// Confirmed outputs funding a script hash, and the inputs spending them (the confirmed part
+// of `Query::status`, with a `txid_limit` of 0 for no limit): `cancel` is checked between the
+// lookups of the spending inputs
+pub fn lookup_confirmed_status(
+    store: &dyn ReadStore,
+    script_hash: &[u8],
+    current_block_index: usize,
+    block_height: &dyn Fn(&Sha256dHash) -> Option<usize>,
+    indexed_spends: bool,
+    txid_limit: usize,
+    cancel: &Cancel,
+) -> Result<(Vec<Txo>, Vec<SpendingInput>)> {
+    let current = current_block_index;
+    let funding = lookup_funding_outputs(store, script_hash, current, block_height)?;
+    check_txid_limit(funding.len(), txid_limit)?;
+    let mut spending = vec![];
+    for txo in &funding {
+        cancel.check()?;
+        let input =
+            lookup_confirmed_spending_input(store, txo, current, block_height, indexed_spends)?;
+        spending.extend(input);
     }
+    Ok((funding, spending))
+}
+
+//
+// Keep the `limit` oldest outputs (0 means no limit)
+//
+fn keep_oldest(txos: &mut Vec<Txo>, limit: usize) {
+    if limit > 0 && txos.len() > limit {
+        txos.sort_unstable_by_key(|txo| (txo.blockindex, txo.txid, txo.vout));
+        txos.truncate(limit);
+    }
+}
+
+//
+// Whether an output can ever be spent: zero-value outputs and provably unspendable
+// scripts (e.g. OP_RETURN data carriers) can't, while dust outputs (of any positive value) can.
+//
+pub fn is_spendable(output: &TxOut) -> bool {
+    output.value > 0 && !output.script_pubkey.is_provably_unspendable()
+}
+
+//
+// Hash of the genesis block of a network
+//
+pub fn genesis_hash(network: Network) -> Sha256dHash {
+    genesis_block(network).bitcoin_hash()
 }
 
 //
@@ -106,154 +560,115 @@ pub struct Query {
     app: Arc<App>,
     tracker: RwLock<Tracker>,
     txid_limit: usize,
+    min_confirmations: usize,
+    served_script_hashes: ScriptHashFilter,
+    track_mempool: bool,
+    mempool_batch_size: usize, // transactions applied per write lock of the tracker
+    mempool_update: Mutex<()>, // serializes the mempool updates
+    snapshot_reads: bool,      // multi-step queries read a snapshot of the DB
+    query_slots: Option<Semaphore>, // limits the concurrent status queries
+    last_refresh: Mutex<Option<Instant>>,
+    refreshed: AtomicBool, // mempool changed by a refresh
+    verifier: Arc<Verifier>,
 }
 
 impl Query {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         app: Arc<App>,
         txid_limit: usize,
+        min_confirmations: usize,
+        served_script_hashes: ScriptHashFilter,
+        track_mempool: bool,
+        mempool_batch_size: usize,
+        max_concurrent_queries: Option<usize>,
+        snapshot_reads: bool,
     ) -> Arc<Query> {
         Arc::new(Query {
             app,
             tracker: RwLock::new(Tracker::new()),
             txid_limit,
+            min_confirmations,
+            served_script_hashes,
+            track_mempool,
+            mempool_batch_size,
+            mempool_update: Mutex::new(()),
+            snapshot_reads,
+            query_slots: max_concurrent_queries.map(Semaphore::new),
+            last_refresh: Mutex::new(None),
+            refreshed: AtomicBool::new(false),
+            verifier: Arc::new(Verifier::default()),
         })
     }
 
-    fn get_txrows_by_prefix(
-        &self,
-        store: &dyn ReadStore,
-        prefix: HashPrefix
-    ) -> Vec<TxRow> {
-        store
-            .scan(&TxRow::filter_prefix(prefix))
-            .iter()
-            .map(|row| TxRow::from_row(row))
-            .collect()
+    // Run `f` on the DB and the best chain, or on a snapshot of both (so that all its reads see
+    // the same rows and the same headers)
+    fn with_read_store<T>(&self, f: impl FnOnce(&dyn ReadStore, &BlockHeight) -> T) -> T {
+        let read_store = self.app.read_store();
+        if !self.snapshot_reads {
+            return f(&*read_store, &|blockhash| self.block_height(blockhash));
+        }
+        let (headers, snapshot) = self.app.index().headers_snapshot(|| read_store.snapshot());
+        let block_height =
+            |blockhash: &Sha256dHash| headers.header_by_blockhash(blockhash).map(|h| h.height());
+        f(&snapshot, &block_height)
     }
 
-    fn get_txoutrows_by_script_hash(
-        &self,
-        store: &dyn ReadStore,
-        script_hash: &[u8]
-    ) -> Vec<TxOutRow> {
-        store
-            .scan(&TxOutRow::filter(script_hash))
-            .iter()
-            .map(|row| TxOutRow::from_row(row))
-            .collect()
+    // Height of a block of the best chain (None for the other blocks, e.g. reorged ones)
+    fn block_height(&self, blockhash: &Sha256dHash) -> Option<usize> {
+        let header = self.get_header_by_block_hash(blockhash);
+        header.map(|header| header.height())
     }
 
-    fn get_prefixes_by_funding_txo(
-        &self,
-        store: &dyn ReadStore,
-        txid: &Sha256dHash,
-        vout: usize,
-    ) -> Vec<HashPrefix> {
-        store
-            .scan(&TxInRow::filter(&txid, vout))
-            .iter()
-            .map(|row| TxInRow::from_row(row).txid_prefix)
-            .collect()
-    }
-
-    /*fn get_txids_by_prefix(
-        &self,
-        store: &dyn ReadStore,
-        prefixes: Vec<HashPrefix>,
-    ) -> Result<Vec<Sha256dHash>> {
-        let mut txns = vec![];
-        for prefix in prefixes {
-            for tx_row in self.get_txrows_by_prefix(store, prefix) {
-                let txid: Sha256dHash = deserialize(&tx_row.key.txid).unwrap();
-                txns.push(txid)
-            }
-        }
-        Ok(txns)
-    }*/
-
-    fn get_txrows_by_prefixes(
+    fn find_spending_input(
         &self,
         store: &dyn ReadStore,
-        prefixes: Vec<HashPrefix>,
-    ) -> Result<Vec<TxRow>> {
-        let mut txns = vec![];
-        for prefix in prefixes {
-            for tx_row in self.get_txrows_by_prefix(store, prefix) {
-                txns.push(tx_row)
-            }
-        }
-        Ok(txns)
+        block_height: &BlockHeight,
+        txo: &Txo,
+        current_block_index: usize,
+    ) -> Result<Option<SpendingInput>> {
+        lookup_spending_input(store, txo, current_block_index, block_height)
     }
 
-
-    fn find_spending_input(
+    // Same as `find_spending_input` for the indexed blocks (not the mempool ones), using the
+    // spend rows if they are indexed
+    fn find_confirmed_spending_input(
         &self,
-        store: &dyn ReadStore,
+        read_store: &dyn ReadStore,
+        block_height: &BlockHeight,
         txo: &Txo,
-        current_block_index: usize
+        current_block_index: usize,
     ) -> Result<Option<SpendingInput>> {
-
-        let mut spendings = vec![];
-        let prefixes = self.get_prefixes_by_funding_txo(store, &txo.txid, txo.vout);
-        //let txids = self.get_txids_by_prefix(store, prefixes)?;
-        let txrows = self.get_txrows_by_prefixes(store, prefixes)?;
-
-        for txrow in &txrows {
-            let block_index = match self.get_block_index(deserialize(&txrow.block_hash).unwrap()){
-                Ok(header) => header.height(),
-                Err(_error) => 0
-            };
-            if block_index > current_block_index {
-                continue;
-            }
-            spendings.push(SpendingInput {
-                txid: deserialize(&txrow.key.txid).unwrap(),
-                outpoint: (txo.txid, txo.vout),
-                blockindex: block_index
-            })
-        }
-
-        assert!(spendings.len() <= 1);
-
-        Ok(if spendings.len() == 1 {
-            Some(spendings.remove(0))
-        } else {
-            None
-        })
+        let indexed_spends = self.app.index().index_spends();
+        let current = current_block_index;
+        lookup_confirmed_spending_input(read_store, txo, current, block_height, indexed_spends)
     }
 
     fn find_funding_outputs(
         &self,
         store: &dyn ReadStore,
+        block_height: &BlockHeight,
         script_hash: &[u8],
-        current_block_index: usize
+        current_block_index: usize,
     ) -> Result<Vec<Txo>> {
-        let txout_rows = self.get_txoutrows_by_script_hash(store, script_hash);
-
-        let mut result = vec![];
+        lookup_funding_outputs(store, script_hash, current_block_index, block_height)
+    }
 
-        for row in &txout_rows {
-            //let txids = self.get_txids_by_prefix(store, vec![row.txid_prefix])?;
-            let txrows = self.get_txrows_by_prefixes(store, vec![row.txid_prefix])?;
-            
-            for txrow in &txrows {
-                let block_index = match self.get_block_index(deserialize(&txrow.block_hash).unwrap()){
-                    Ok(header) => header.height(),
-                    Err(_error) => 0
-                };
-                if block_index > current_block_index {
-                    continue;
-                }
-                result.push(Txo {
-                    txid: deserialize(&txrow.key.txid).unwrap(),
-                    vout: row.vout as usize,
-                    blockindex: block_index
-                })
-            }
-        }
+    // Mempool rows have no block, so their lookups never need a height
+    fn find_mempool_funding_outputs(
+        &self,
+        mempool: &dyn ReadStore,
+        script_hash: &[u8],
+    ) -> Result<Vec<Txo>> {
+        self.find_funding_outputs(mempool, &|_| None, script_hash, 9999999999)
+    }
 
-        Ok(result)
+    fn find_mempool_spending_input(
+        &self,
+        mempool: &dyn ReadStore,
+        txo: &Txo,
+    ) -> Result<Option<SpendingInput>> {
+        self.find_spending_input(mempool, &|_| None, txo, 9999999999)
     }
 
     fn confirmed_status(
@@ -261,27 +676,21 @@ impl Query {
         script_hash: &[u8],
         current_block_index: usize,
         use_txid_limit: bool,
+        cancel: &Cancel,
     ) -> Result<(Vec<Txo>, Vec<SpendingInput>)> {
-        let mut funding = vec![];
-        let mut spending = vec![];
-        let read_store = self.app.read_store();
-
-        let txos = self.find_funding_outputs(read_store, script_hash, current_block_index)?;
-        if use_txid_limit && self.txid_limit > 0 && txos.len() > self.txid_limit {
-            bail!(
-                "{}+ transactions found, query may take a long time",
-                txos.len()
-            );
-        }
-        funding.extend(txos);
-
-        for txo in &funding {
-            if let Some(spent) = self.find_spending_input(read_store, &txo, current_block_index)? {
-                spending.push(spent);
-            }
-        }
-
-        Ok((funding, spending))
+        let txid_limit = if use_txid_limit { self.txid_limit } else { 0 };
+        let indexed_spends = self.app.index().index_spends();
+        self.with_read_store(|read_store, block_height| {
+            lookup_confirmed_status(
+                read_store,
+                script_hash,
+                current_block_index,
+                block_height,
+                indexed_spends,
+                txid_limit,
+                cancel,
+            )
+        })
     }
 
     fn mempool_status(
@@ -292,20 +701,20 @@ impl Query {
     ) -> Result<(Vec<Txo>, Vec<SpendingInput>)> {
         let mut funding = vec![];
         let mut spending = vec![];
+        if !self.track_mempool {
+            return Ok((funding, spending));
+        }
 
         let tracker = self.tracker.read().unwrap();
 
-        let txos = self.find_funding_outputs(tracker.index(), script_hash, 9999999999)?;
-        if use_txid_limit && self.txid_limit > 0 && txos.len() > self.txid_limit {
-            bail!(
-                "{}+ transactions found, query may take a long time",
-                txos.len()
-            );
+        let txos = self.find_mempool_funding_outputs(tracker.index(), script_hash)?;
+        if use_txid_limit {
+            check_txid_limit(txos.len(), self.txid_limit)?;
         }
         funding.extend(txos);
 
         for txo in funding.iter().chain(confirmed_funding.iter()) {
-            if let Some(spent) = self.find_spending_input(tracker.index(), &txo, 9999999999)? {
+            if let Some(spent) = self.find_mempool_spending_input(tracker.index(), &txo)? {
                 spending.push(spent);
             }
         }
@@ -313,22 +722,223 @@ impl Query {
         Ok((funding, spending))
     }
 
-    pub fn status(&self, script_hash: &[u8], current_block_index: usize, use_txid_limit: bool) -> Result<Status> {
-        let confirmed = self
-            .confirmed_status(script_hash, current_block_index, use_txid_limit)
+    // Move the transactions with less than `min_confirmations` out of the confirmed ones
+    fn pending_status(
+        &self,
+        confirmed: &mut (Vec<Txo>, Vec<SpendingInput>),
+    ) -> Result<(Vec<Txo>, Vec<SpendingInput>)> {
+        if self.min_confirmations <= 1 {
+            return Ok((vec![], vec![]));
+        }
+        let tip_height = self.get_best_header()?.height();
+        let is_pending = |blockindex: usize| tip_height + 1 < blockindex + self.min_confirmations;
+
+        let (funding, confirmed_funding) = confirmed
+            .0
+            .drain(..)
+            .partition(|f| is_pending(f.blockindex));
+        let (spending, confirmed_spending) = confirmed
+            .1
+            .drain(..)
+            .partition(|s| is_pending(s.blockindex));
+        confirmed.0 = confirmed_funding;
+        confirmed.1 = confirmed_spending;
+        Ok((funding, spending))
+    }
+
+    // Queries of the script hashes outside of `--served-script-hash-prefixes` are rejected
+    fn check_served(&self, script_hash: &[u8]) -> Result<()> {
+        if !self.served_script_hashes.allows(script_hash) {
+            bail!(ErrorKind::InvalidRequest(format!(
+                "script hash {} is not served",
+                hex::encode(script_hash)
+            )));
+        }
+        Ok(())
+    }
+
+    // `cancel` is checked between the lookups, to stop early once the client is gone
+    pub fn status(
+        &self,
+        script_hash: &[u8],
+        current_block_index: usize,
+        use_txid_limit: bool,
+        cancel: &Cancel,
+    ) -> Result<Status> {
+        self.check_served(script_hash)?;
+        let _slot = self.query_slots.as_ref().map(Semaphore::acquire);
+        cancel.check()?;
+
+        let mut confirmed = self
+            .confirmed_status(script_hash, current_block_index, use_txid_limit, cancel)
             .chain_err(|| "failed to get confirmed status")?;
+        cancel.check()?;
 
         let mempool = self
             .mempool_status(script_hash, &confirmed.0, use_txid_limit)
             .chain_err(|| "failed to get mempool status")?;
 
-        Ok(Status { confirmed, mempool })
+        let pending = self
+            .pending_status(&mut confirmed)
+            .chain_err(|| "failed to get pending status")?;
+
+        Ok(Status { confirmed, pending, mempool })
+    }
+
+    // Number of distinct (confirmed and unconfirmed) transactions of the script hash, counted
+    // from its funding and spending rows: unlike `status`, no history is kept (so there's no
+    // txid limit)
+    pub fn tx_count(&self, script_hash: &[u8], cancel: &Cancel) -> Result<usize> {
+        self.check_served(script_hash)?;
+        let _slot = self.query_slots.as_ref().map(Semaphore::acquire);
+        let mut txids = HashSet::new();
+        let confirmed = self.with_read_store(|read_store, block_height| -> Result<Vec<Txo>> {
+            let txos =
+                self.find_funding_outputs(read_store, block_height, script_hash, 9999999999)?;
+            for txo in &txos {
+                cancel.check()?;
+                txids.insert(txo.txid);
+                if let Some(input) =
+                    self.find_confirmed_spending_input(read_store, block_height, txo, 9999999999)?
+                {
+                    txids.insert(input.txid);
+                }
+            }
+            Ok(txos)
+        })?;
+        if self.track_mempool {
+            let tracker = self.tracker.read().unwrap();
+            let txos = self.find_mempool_funding_outputs(tracker.index(), script_hash)?;
+            txids.extend(txos.iter().map(|txo| txo.txid));
+            for txo in txos.iter().chain(confirmed.iter()) {
+                if let Some(input) = self.find_mempool_spending_input(tracker.index(), txo)? {
+                    txids.insert(input.txid);
+                }
+            }
+        }
+        Ok(txids.len())
     }
     
-    pub fn oldest_tx(&self, script_hash: &[u8], current_block_index: usize) -> Result<TxBlockIndex> {
-        let all_status = self.status(script_hash, current_block_index, true).unwrap();
-        
-        all_status.oldest().chain_err(|| "no txs for address")
+    // Confirmed outputs funding the script hash within [start_height, end_height]
+    // (cheaper than `status`, since the spending inputs aren't looked up)
+    pub fn funding_outputs(
+        &self,
+        script_hash: &[u8],
+        start_height: usize,
+        end_height: usize,
+    ) -> Result<Vec<Txo>> {
+        self.check_served(script_hash)?;
+        let txos = self.with_read_store(|read_store, block_height| {
+            self.find_funding_outputs(read_store, block_height, script_hash, end_height)
+        })?;
+        let mut txos: Vec<Txo> = txos
+            .into_iter()
+            .filter(|txo| txo.blockindex >= start_height)
+            .collect();
+        check_txid_limit(txos.len(), self.txid_limit)?;
+        txos.sort_unstable_by_key(|txo| (txo.blockindex, txo.txid, txo.vout));
+        Ok(txos)
+    }
+
+    // Unspent outputs of each script hash (confirmed and mempool ones)
+    // (the script hashes are split between a few threads, all reading the same DB snapshot)
+    pub fn utxos(
+        &self,
+        script_hashes: &[Sha256dHash],
+        cancel: &Cancel,
+    ) -> Result<Vec<Vec<OutPoint>>> {
+        for script_hash in script_hashes {
+            self.check_served(&script_hash[..])?;
+        }
+        let _slot = self.query_slots.as_ref().map(Semaphore::acquire);
+
+        let threads = num_cpus::get().clamp(1, MAX_UTXOS_THREADS);
+        let chunk_size = script_hashes.len().div_ceil(threads).max(1);
+        self.with_read_store(|read_store, block_height| {
+            thread::scope(|scope| {
+                let handles: Vec<_> = script_hashes
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|script_hash| {
+                                    cancel.check()?;
+                                    self.unspent(read_store, block_height, &script_hash[..])
+                                })
+                                .collect::<Result<Vec<_>>>()
+                        })
+                    })
+                    .collect();
+                let mut result = vec![];
+                for handle in handles {
+                    result.extend(handle.join().expect("utxos thread panicked")?);
+                }
+                Ok(result)
+            })
+        })
+    }
+
+    fn unspent(
+        &self,
+        read_store: &dyn ReadStore,
+        block_height: &BlockHeight,
+        script_hash: &[u8],
+    ) -> Result<Vec<OutPoint>> {
+        let mut funding =
+            self.find_funding_outputs(read_store, block_height, script_hash, 9999999999)?;
+        let mut spent = HashSet::new();
+        for txo in &funding {
+            if let Some(input) =
+                self.find_confirmed_spending_input(read_store, block_height, txo, 9999999999)?
+            {
+                spent.insert(input.outpoint);
+            }
+        }
+        let (mempool_funding, mempool_spending) = self
+            .mempool_status(script_hash, &funding, false)
+            .chain_err(|| "failed to get mempool status")?;
+        funding.extend(mempool_funding);
+        spent.extend(mempool_spending.into_iter().map(|input| input.outpoint));
+        Ok(funding
+            .into_iter()
+            .map(|txo| (txo.txid, txo.vout))
+            .filter(|outpoint| !spent.contains(outpoint))
+            .collect())
+    }
+
+    // None if the script hash has no transactions (up to `current_block_index`)
+    pub fn oldest_tx(
+        &self,
+        script_hash: &[u8],
+        current_block_index: usize,
+        include_mempool: bool,
+        cancel: &Cancel,
+    ) -> Result<Option<TxBlockIndex>> {
+        self.check_served(script_hash)?;
+        let _slot = self.query_slots.as_ref().map(Semaphore::acquire);
+        cancel.check()?;
+        // a script hash can't be spent from before being funded: its oldest transaction is a
+        // funding one, so the spending inputs aren't looked up
+        let mut funding = self
+            .with_read_store(|read_store, block_height| {
+                let current = current_block_index;
+                self.find_funding_outputs(read_store, block_height, script_hash, current)
+            })
+            .chain_err(|| "failed to get oldest tx")?;
+        keep_oldest(&mut funding, self.txid_limit);
+        let mempool = if include_mempool && funding.is_empty() {
+            self.mempool_status(script_hash, &[], false)
+                .chain_err(|| "failed to get oldest tx")?
+        } else {
+            (vec![], vec![])
+        };
+        let status = Status {
+            confirmed: (funding, vec![]),
+            pending: (vec![], vec![]),
+            mempool,
+        };
+        Ok(status.oldest(include_mempool))
     }
     
     pub fn get_best_header(&self) -> Result<HeaderEntry> {
@@ -336,12 +946,962 @@ impl Query {
         Ok(last_header.chain_err(|| "no headers indexed")?)
     }
     
+    pub fn get_chainwork(&self) -> Result<(HeaderEntry, Uint256)> {
+        self.app.index().best_chainwork().chain_err(|| "no headers indexed")
+    }
+
+    // Block of the 'L' marker (all the blocks up to it are indexed) and its height
+    pub fn get_last_indexed(&self) -> Result<Option<HeaderEntry>> {
+        let blockhash = match read_last_indexed(&*self.app.read_store())? {
+            Some(blockhash) => blockhash,
+            None => return Ok(None),
+        };
+        let header = self.app.index().get_header_by_block_hash(blockhash);
+        Ok(Some(header.chain_err(|| {
+            format!("last indexed block {} is not in the headers list", blockhash)
+        })?))
+    }
+
+    pub fn get_index_lag(&self) -> Result<IndexLag> {
+        let tip = self.get_best_header()?;
+        let (daemon_height, daemon_header) = self.app.daemon().get_best_block()?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .chain_err(|| "invalid system time")?
+            .as_secs();
+        let tip_time = u64::from(tip.header().time);
+        Ok(IndexLag {
+            blocks: daemon_height.saturating_sub(tip.height()),
+            tip_age: now.saturating_sub(tip_time),
+            daemon_time_lag: u64::from(daemon_header.time).saturating_sub(tip_time),
+        })
+    }
+
+    // Best header and the `count - 1` headers preceding it (tip first)
+    pub fn get_tips(&self, count: usize) -> Result<Vec<HeaderEntry>> {
+        let tip_height = self.get_best_header()?.height();
+        let start = (tip_height + 1).saturating_sub(count);
+        let mut headers = self.app.index().get_headers(start, tip_height + 1);
+        headers.reverse();
+        Ok(headers)
+    }
+
+    // Headers of the [start, end] heights range (which must be indexed)
+    pub fn get_header_range(&self, start: usize, end: usize) -> Result<Vec<HeaderEntry>> {
+        let tip_height = self.get_best_header()?.height();
+        if start > end || end > tip_height {
+            bail!(ErrorKind::InvalidRequest(format!(
+                "invalid range {}..{} (tip height is {})",
+                start, end, tip_height
+            )));
+        }
+        Ok(self.app.index().get_headers(start, end + 1))
+    }
+
+    // Up to `count` headers from the `start` height (fewer of them past the tip)
+    pub fn get_headers(&self, start: usize, count: usize) -> Vec<HeaderEntry> {
+        self.app.index().get_headers(start, start.saturating_add(count))
+    }
+
+    pub fn get_header(&self, height: usize) -> Result<HeaderEntry> {
+        let header = self.app.index().get_header(height);
+        header.chain_err(|| ErrorKind::InvalidRequest(format!("no header at height {}", height)))
+    }
+
+    pub fn get_median_time_past(&self, height: usize) -> Result<u32> {
+        let mtp = self.app.index().median_time_past(height);
+        mtp.chain_err(|| ErrorKind::InvalidRequest(format!("no header at height {}", height)))
+    }
+
+    // Serialized block from the daemon (as hex)
+    pub fn get_block_raw(&self, blockhash: &Sha256dHash) -> Result<String> {
+        self.app.daemon().getblock_raw(blockhash)
+    }
+
+    pub fn settings(&self) -> Settings {
+        let index = self.app.index();
+        Settings {
+            network: self.app.daemon().network().to_string(),
+            txid_limit: self.txid_limit,
+            min_confirmations: self.min_confirmations,
+            track_mempool: self.track_mempool,
+            index_outputs: index.index_outputs(),
+            index_filters: index.index_filters(),
+            index_witness_commitments: index.index_witness_commitments(),
+            hash_prefix_len: HASH_PREFIX_LEN,
+        }
+    }
+
+    // Genesis block hash of the daemon's network
+    pub fn get_genesis_hash(&self) -> Sha256dHash {
+        genesis_hash(self.app.daemon().network())
+    }
+
+    // Distinct (served) script hashes funded by the outputs of a block
+    pub fn funded_script_hashes(&self, blockhash: &Sha256dHash) -> Result<Vec<FullHash>> {
+        let block = self.app.daemon().getblock(blockhash)?;
+        let mut script_hashes: Vec<FullHash> = block
+            .txdata
+            .iter()
+            .flat_map(|txn| txn.output.iter())
+            .map(|output| compute_script_hash(&output.script_pubkey[..]))
+            .filter(|script_hash| self.served_script_hashes.allows(script_hash))
+            .collect();
+        script_hashes.sort_unstable();
+        script_hashes.dedup();
+        Ok(script_hashes)
+    }
+
+    // Only the blocks indexed with `--index-filters` have a filter
+    pub fn get_block_filter(&self, blockhash: &Sha256dHash) -> Result<Bytes> {
+        match self.app.read_store().get(&block_filter_key(blockhash))? {
+            Some(filter) => Ok(filter),
+            None => bail!(ErrorKind::InvalidRequest(format!(
+                "no filter for block {}",
+                blockhash
+            ))),
+        }
+    }
+
+    // None if the block has no witness commitment (or wasn't indexed with them)
+    pub fn get_witness_commitment(&self, blockhash: &Sha256dHash) -> Result<Option<Bytes>> {
+        if !self.app.index().index_witness_commitments() {
+            bail!(ErrorKind::InvalidRequest(
+                "witness commitments aren't indexed (--index-witness-commitments)".to_string()
+            ));
+        }
+        self.app.read_store().get(&witness_commitment_key(blockhash))
+    }
+
+    // None if the block isn't in the best chain
+    pub fn get_header_by_block_hash(&self, blockhash: &Sha256dHash) -> Option<HeaderEntry> {
+        self.app.index().get_header_by_block_hash(*blockhash)
+    }
+
     pub fn get_block_index(&self, block_hash:Sha256dHash) -> Result<HeaderEntry> {
         let block_header = self.app.index().get_header_by_block_hash(block_hash);
-        Ok(block_header.chain_err(|| "no headers indexed")?)
+        block_header.chain_err(|| format!("block {} is not in the best chain", block_hash))
+    }
+
+    // Confirmed transactions whose txid starts with `prefix` (at most `limit` of them)
+    pub fn find_txs_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<TxBlockIndex>> {
+        if prefix.len() < HASH_PREFIX_LEN || prefix.len() > 32 {
+            bail!(ErrorKind::InvalidRequest(format!(
+                "txid prefix must have {} to 32 bytes",
+                HASH_PREFIX_LEN
+            )));
+        }
+        let mut hash_prefix = HashPrefix::default();
+        hash_prefix.copy_from_slice(&prefix[..HASH_PREFIX_LEN]);
+
+        let read_store = self.app.read_store();
+        let mut result = vec![];
+        for txrow in get_txrows_by_prefix(&*read_store, hash_prefix)? {
+            if !txrow.key.txid.starts_with(prefix) {
+                continue;
+            }
+            // ignore transactions of reorged blocks
+            let blockindex = match self.block_height(&Sha256dHash::from_inner(txrow.block_hash)) {
+                Some(height) => height,
+                None => continue,
+            };
+            result.push(TxBlockIndex {
+                txid: Sha256dHash::from_inner(txrow.key.txid),
+                blockindex,
+            });
+            if result.len() == limit {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    // Value of a transaction output (from the mempool or from the daemon)
+    pub fn get_output_value(&self, txid: &Sha256dHash, vout: usize) -> Result<u64> {
+        Ok(self.get_output(txid, vout)?.value)
+    }
+
+    // Output stored by the index (with `--index-outputs`)
+    fn stored_output(&self, txid: &Sha256dHash, vout: usize) -> Result<Option<TxOut>> {
+        if vout > u16::MAX as usize {
+            return Ok(None); // not indexed
+        }
+        match self.app.read_store().get(&output_key(txid, vout))? {
+            Some(value) => {
+                let output = deserialize(&value)
+                    .chain_err(|| format!("failed to parse stored output {}:{}", txid, vout))?;
+                Ok(Some(output))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Transaction output from the mempool or the index (None if the daemon must be queried)
+    fn cached_output(&self, txid: &Sha256dHash, vout: usize) -> Result<Option<Option<TxOut>>> {
+        if let Some(txn) = self.tracker.read().unwrap().get_txn(txid) {
+            return Ok(Some(txn.output.get(vout).cloned()));
+        }
+        Ok(self.stored_output(txid, vout)?.map(Some))
+    }
+
+    // Transaction output (from the mempool, the index or the daemon)
+    pub fn get_output(&self, txid: &Sha256dHash, vout: usize) -> Result<TxOut> {
+        let output = match self.cached_output(txid, vout)? {
+            Some(output) => output,
+            None => self
+                .app
+                .daemon()
+                .gettransaction(txid, None)?
+                .output
+                .get(vout)
+                .cloned(),
+        };
+        output.chain_err(|| format!("missing output {}:{}", txid, vout))
+    }
+
+    // Transaction outputs (from the mempool, the index or the daemon), in the given order:
+    // the transactions missing from the mempool and the index are fetched in one batch
+    pub fn get_outputs(&self, outpoints: &[(Sha256dHash, usize)]) -> Result<Vec<TxOut>> {
+        let mut cached = vec![];
+        let mut missing = HashSet::new();
+        for (txid, vout) in outpoints {
+            let output = self.cached_output(txid, *vout)?;
+            if output.is_none() {
+                missing.insert(*txid);
+            }
+            cached.push(output);
+        }
+        let mut txs = HashMap::<Sha256dHash, Transaction>::new();
+        if !missing.is_empty() {
+            let txids: Vec<&Sha256dHash> = missing.iter().collect();
+            for txn in self.app.daemon().gettransactions(&txids)? {
+                txs.insert(txn.txid(), txn);
+            }
+        }
+        outpoints
+            .iter()
+            .zip(cached)
+            .map(|((txid, vout), output)| {
+                let output = match output {
+                    Some(output) => output,
+                    None => txs.get(txid).and_then(|txn| txn.output.get(*vout).cloned()),
+                };
+                output.chain_err(|| format!("missing output {}:{}", txid, vout))
+            })
+            .collect()
+    }
+
+    fn get_output_values(&self, outpoints: &[(Sha256dHash, usize)]) -> Result<Vec<u64>> {
+        Ok(self
+            .get_outputs(outpoints)?
+            .iter()
+            .map(|output| output.value)
+            .collect())
+    }
+
+    // Net balance change (in satoshis) of the unconfirmed transactions
+    pub fn mempool_delta(&self, script_hash: &[u8], cancel: &Cancel) -> Result<i64> {
+        let status = self.status(script_hash, 9999999999, false, cancel)?;
+        let funding: Vec<(Sha256dHash, usize)> = status
+            .mempool_funding()
+            .map(|txo| (txo.txid, txo.vout))
+            .collect();
+        let spending: Vec<(Sha256dHash, usize)> = status
+            .mempool_spending()
+            .map(|input| input.outpoint)
+            .collect();
+        let received: u64 = self.get_output_values(&funding)?.iter().sum();
+        let sent: u64 = self.get_output_values(&spending)?.iter().sum();
+        Ok(received as i64 - sent as i64)
+    }
+
+    // The values of a script hash's transactions are read from the stored outputs
+    // (so `--index-outputs` is needed), and only for a limited number of transactions
+    // (the limit is applied before looking up the values: one DB read per input or output)
+    fn check_values_lookup(&self, script_hash: &[u8], txs_count: usize) -> Result<()> {
+        if !self.app.index().index_outputs() {
+            bail!(ErrorKind::InvalidRequest(
+                "values need the outputs to be indexed (--index-outputs)".to_string()
+            ));
+        }
+        check_txid_limit(txs_count, self.txid_limit).chain_err(|| {
+            ErrorKind::InvalidRequest(format!(
+                "script hash {} has too many transactions to look up their values",
+                hex::encode(script_hash)
+            ))
+        })
+    }
+
+    // Whether the script hash has (confirmed, mempool) transactions, without looking up its
+    // whole status: a script hash can't be spent from before being funded, so its outputs
+    // are enough to find the confirmed transactions (and the first confirmed one is enough)
+    pub fn activity(&self, script_hash: &[u8]) -> Result<(bool, bool)> {
+        self.check_served(script_hash)?;
+        let (txout_rows, confirmed) = self.with_read_store(|read_store, block_height| -> Result<_> {
+            let txout_rows = get_txoutrows_by_script_hash(read_store, script_hash)?;
+            let mut confirmed = false;
+            for row in &txout_rows {
+                let txrows = get_txrows_by_prefix(read_store, row.txid_prefix)?;
+                confirmed = txrows
+                    .iter()
+                    .map(|txrow| Sha256dHash::from_inner(txrow.block_hash))
+                    .any(|blockhash| block_height(&blockhash).is_some()); // skip reorged blocks
+                if confirmed {
+                    break;
+                }
+            }
+            Ok((txout_rows, confirmed))
+        })?;
+        if !self.track_mempool {
+            return Ok((confirmed, false));
+        }
+        let tracker = self.tracker.read().unwrap();
+        let mempool = tracker.index();
+        if !get_txoutrows_by_script_hash(mempool, script_hash)?.is_empty() {
+            return Ok((confirmed, true)); // funded
+        }
+        for row in &txout_rows {
+            let filter = TxInRow::filter_prefix(row.txid_prefix, row.vout as usize);
+            if !mempool.scan(&filter)?.is_empty() {
+                return Ok((confirmed, true)); // spent
+            }
+        }
+        Ok((confirmed, false))
+    }
+
+    // Lifetime (total received, total sent) values of the confirmed transactions
+    pub fn totals(&self, script_hash: &[u8], cancel: &Cancel) -> Result<(u64, u64)> {
+        let status = self.status(script_hash, 9999999999, false, cancel)?;
+        self.check_values_lookup(script_hash, status.confirmed_since(0).len())?;
+        let mut received = 0;
+        for txo in status.confirmed_funding() {
+            cancel.check()?;
+            received += self.get_output_value(&txo.txid, txo.vout)?;
+        }
+        let mut sent = 0;
+        for input in status.confirmed_spending() {
+            cancel.check()?;
+            let (txid, vout) = input.outpoint;
+            sent += self.get_output_value(&txid, vout)?;
+        }
+        Ok((received, sent))
+    }
+
+    // (confirmed, unconfirmed) balance of the script hash, from the values of its funding
+    // outputs only: unlike `status`, the spending input of each output isn't looked up
+    // (the spent outputs are found with one scan per funding transaction)
+    // None if the outputs aren't indexed
+    pub fn balance(&self, script_hash: &[u8], cancel: &Cancel) -> Result<Option<(u64, i64)>> {
+        self.check_served(script_hash)?;
+        if !self.app.index().index_outputs() {
+            return Ok(None);
+        }
+        let _slot = self.query_slots.as_ref().map(Semaphore::acquire);
+        let (txos, spent) = self.with_read_store(|read_store, block_height| -> Result<_> {
+            let txos =
+                self.find_funding_outputs(read_store, block_height, script_hash, 9999999999)?;
+            let spent = lookup_spent_outputs(read_store, &txos, block_height)?;
+            Ok((txos, spent))
+        })?;
+        let mut confirmed = 0;
+        let mut missing = vec![];
+        for txo in txos.iter().filter(|txo| !spent.contains(&(txo.txid, txo.vout))) {
+            match self.cached_output(&txo.txid, txo.vout)? {
+                Some(output) => {
+                    let output = output
+                        .chain_err(|| format!("missing output {}:{}", txo.txid, txo.vout))?;
+                    confirmed += output.value;
+                }
+                None => missing.push(txo),
+            }
+        }
+        // only the outputs missing from the index are fetched from the daemon (one RPC each)
+        check_txid_limit(missing.len(), self.txid_limit).chain_err(|| {
+            ErrorKind::InvalidRequest(format!(
+                "script hash {} has too many outputs to fetch from the daemon",
+                hex::encode(script_hash)
+            ))
+        })?;
+        for txo in missing {
+            cancel.check()?;
+            confirmed += self.get_output_value(&txo.txid, txo.vout)?;
+        }
+
+        let (funding, spending) = self
+            .mempool_status(script_hash, &txos, false)
+            .chain_err(|| "failed to get mempool status")?;
+        let mut unconfirmed: i64 = 0;
+        for txo in &funding {
+            unconfirmed += self.get_output_value(&txo.txid, txo.vout)? as i64;
+        }
+        for input in &spending {
+            let (txid, vout) = input.outpoint;
+            unconfirmed -= self.get_output_value(&txid, vout)? as i64;
+        }
+        Ok(Some((confirmed, unconfirmed)))
+    }
+
+    // (count, total value) of the unspent outputs of the script hash, confirmed and mempool
+    // ones (the outputs returned by `blockchain.scripthash.get_utxos`)
+    pub fn utxo_summary(&self, script_hash: &[u8], cancel: &Cancel) -> Result<(usize, u64)> {
+        let status = self.status(script_hash, 9999999999, false, cancel)?;
+        let unspent = status.unspent();
+        self.check_values_lookup(script_hash, unspent.len())?;
+        let mut value = 0;
+        for txo in &unspent {
+            cancel.check()?;
+            value += self.get_output_value(&txo.txid, txo.vout)?;
+        }
+        Ok((unspent.len(), value))
+    }
+
+    // Net value change of the script hash in each transaction of its status
+    pub fn value_deltas(
+        &self,
+        script_hash: &[u8],
+        status: &Status,
+        cancel: &Cancel,
+    ) -> Result<HashMap<Sha256dHash, i64>> {
+        self.check_values_lookup(script_hash, status.history().len())?;
+        status.value_deltas(|txid, vout| {
+            cancel.check()?;
+            self.get_output_value(txid, vout)
+        })
+    }
+
+    // Input spending a transaction output (confirmed, or else from the mempool)
+    pub fn get_spending_input(&self, txid: &Sha256dHash, vout: usize) -> Result<Option<SpendingInput>> {
+        let txo = Txo {
+            txid: *txid,
+            vout,
+            blockindex: 0,
+        };
+        let spent = self.with_read_store(|read_store, block_height| {
+            self.find_confirmed_spending_input(read_store, block_height, &txo, 9999999999)
+        })?;
+        if spent.is_some() {
+            return Ok(spent);
+        }
+        let tracker = self.tracker.read().unwrap();
+        self.find_mempool_spending_input(tracker.index(), &txo)
+    }
+
+    // Transaction from the daemon, hex-encoded or decoded (`height` is 0 for mempool ones)
+    pub fn get_transaction_raw(&self, txid: &Sha256dHash, height: usize, verbose: bool) -> Result<Value> {
+        let blockhash = match height {
+            0 => None,
+            height => Some(*self.get_header(height)?.hash()),
+        };
+        self.app.daemon().gettransaction_raw(txid, blockhash, verbose)
+    }
+
+    // Blocks of the indexed rows of a transaction
+    // (a duplicate txid, see BIP30, has a row per block, and a reorged block keeps its rows)
+    fn tx_blockhashes(&self, txid: &Sha256dHash) -> Result<Vec<Sha256dHash>> {
+        let rows = self.app.read_store().scan(&TxRow::filter_full(txid))?;
+        rows.iter()
+            .map(|row| Ok(Sha256dHash::from_inner(TxRow::from_row(row)?.block_hash)))
+            .collect()
+    }
+
+    // Some(true) if the transaction is confirmed, Some(false) if it's in the mempool
+    pub fn tx_status(&self, txid: &Sha256dHash) -> Result<Option<bool>> {
+        // ignore transactions of reorged blocks
+        let blockhashes = self.tx_blockhashes(txid)?;
+        if blockhashes.iter().any(|blockhash| self.block_height(blockhash).is_some()) {
+            return Ok(Some(true));
+        }
+        if self.tracker.read().unwrap().get_txn(txid).is_some() {
+            return Ok(Some(false));
+        }
+        Ok(None)
+    }
+
+    // Confirmations of a transaction (0 if it's in the mempool, or if its block was reorged)
+    pub fn get_confirmations(&self, txid: &Sha256dHash) -> Result<usize> {
+        let blockhashes = self.tx_blockhashes(txid)?;
+        let heights: Vec<usize> = blockhashes
+            .iter()
+            .filter_map(|blockhash| self.block_height(blockhash)) // None for reorged blocks
+            .collect();
+        // a duplicate txid (see BIP30) has a row per block, the first one counts
+        if let Some(height) = heights.into_iter().min() {
+            let tip_height = self.get_best_header()?.height();
+            return Ok((tip_height + 1).saturating_sub(height));
+        }
+        if blockhashes.is_empty() && self.tracker.read().unwrap().get_txn(txid).is_none() {
+            bail!(ErrorKind::InvalidRequest(format!("unknown transaction {}", txid)));
+        }
+        Ok(0)
+    }
+
+    // Block of a confirmed transaction, and its position in the block (0 for the coinbase),
+    // e.g. for the clients building their own Merkle proofs
+    pub fn get_tx_position(&self, txid: &Sha256dHash) -> Result<(HeaderEntry, usize)> {
+        // a duplicate txid (see BIP30) has a row per block, the first one counts
+        let header = self
+            .tx_blockhashes(txid)?
+            .iter()
+            .filter_map(|blockhash| self.get_header_by_block_hash(blockhash)) // None if reorged
+            .min_by_key(|header| header.height());
+        let header = match header {
+            Some(header) => header,
+            None if self.tracker.read().unwrap().get_txn(txid).is_some() => {
+                bail!(ErrorKind::InvalidRequest(format!("transaction {} is unconfirmed", txid)))
+            }
+            None => bail!(ErrorKind::InvalidRequest(format!("unknown transaction {}", txid))),
+        };
+        let txids = self.app.daemon().getblocktxids(header.hash())?;
+        let position = txids
+            .iter()
+            .position(|block_txid| block_txid == txid)
+            .chain_err(|| format!("transaction {} not found in block {}", txid, header.hash()))?;
+        Ok((header, position))
+    }
+
+    // Output of a mempool or confirmed transaction (None if there is no such output)
+    // (a confirmed transaction is fetched from its block, so bitcoind's txindex isn't needed)
+    pub fn find_output(&self, txid: &Sha256dHash, vout: usize) -> Result<Option<TxOut>> {
+        if let Some(txn) = self.tracker.read().unwrap().get_txn(txid) {
+            return Ok(txn.output.get(vout).cloned());
+        }
+        if let Some(output) = self.stored_output(txid, vout)? {
+            return Ok(Some(output));
+        }
+        let blockhash = self
+            .tx_blockhashes(txid)?
+            .into_iter()
+            .find(|blockhash| self.block_height(blockhash).is_some()); // skip reorged blocks
+        Ok(match blockhash {
+            Some(blockhash) => {
+                let txn = self.app.daemon().gettransaction(txid, Some(blockhash))?;
+                txn.output.get(vout).cloned()
+            }
+            None => None,
+        })
+    }
+
+    pub fn mempool_conflicts(&self, txid: &Sha256dHash) -> Vec<Sha256dHash> {
+        self.tracker.read().unwrap().conflicts(txid)
+    }
+
+    // Number of mempool transactions and their total serialized size
+    pub fn get_mempool_info(&self) -> (usize, usize) {
+        self.tracker.read().unwrap().info()
+    }
+
+    // (fee rate, vsize) bins of the mempool (rebuilt on each mempool update)
+    pub fn get_fee_histogram(&self) -> Vec<(f32, u64)> {
+        self.tracker.read().unwrap().fee_histogram().to_vec()
+    }
+
+    // Fee (in satoshis) and vsize of a mempool transaction
+    pub fn get_mempool_fee(&self, txid: &Sha256dHash) -> Option<(u64, u64)> {
+        let tracker = self.tracker.read().unwrap();
+        let entry = tracker.get_entry(txid)?;
+        Some((entry.fee(), entry.vsize()))
+    }
+
+    /// Returns true if the mempool has changed (since the previous update).
+    pub fn update_mempool(&self) -> Result<bool> {
+        if !self.track_mempool {
+            return Ok(false);
+        }
+        let changed = self.apply_mempool_update()?;
+        Ok(self.refreshed.swap(false, Ordering::SeqCst) || changed)
+    }
+
+    /// Updates the mempool out of the periodic updates (at most once per second).
+    /// Returns false if the refresh was skipped.
+    pub fn refresh_mempool(&self) -> Result<bool> {
+        if !self.track_mempool {
+            return Ok(false);
+        }
+        {
+            let mut last_refresh = self.last_refresh.lock().unwrap();
+            if let Some(instant) = *last_refresh {
+                if instant.elapsed() < MEMPOOL_REFRESH_INTERVAL {
+                    return Ok(false);
+                }
+            }
+            *last_refresh = Some(Instant::now());
+        }
+        if self.apply_mempool_update()? {
+            self.refreshed.store(true, Ordering::SeqCst);
+        }
+        Ok(true)
+    }
+
+    // Start a consistency check of the index in the background (see `Verifier::start`)
+    pub fn verify_index(&self) -> (bool, Option<Report>) {
+        self.verifier.start(&self.app)
+    }
+
+    // Fetch the mempool changes without locking the tracker, then apply them in batches
+    // (so that a large mempool churn doesn't block the readers for too long)
+    fn apply_mempool_update(&self) -> Result<bool> {
+        let _update = self.mempool_update.lock().unwrap();
+        let old_txids = self.tracker.read().unwrap().txids();
+        let update = match MempoolUpdate::fetch(self.app.daemon(), &old_txids)? {
+            Some(update) => update,
+            None => return Ok(false), // keep the mempool until next update
+        };
+        let mut changed = false;
+        for batch in update.into_batches(self.mempool_batch_size) {
+            changed |= self.tracker.write().unwrap().apply(batch)?;
+        }
+        if changed {
+            self.tracker.write().unwrap().update_histogram();
+        }
+        Ok(changed)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin_hashes::Hash;
+
+    use crate::fixtures::{chain, indexed_chain, transaction, MemStore};
+    use crate::index::{index_block, index_transaction, init_spend_rows, spend_rows};
+    use crate::store::{Row, WriteStore};
+
+    #[test]
+    fn test_oldest() {
+        let txo = |n: u8, blockindex| Txo {
+            txid: Sha256dHash::hash(&[n]),
+            vout: 0,
+            blockindex,
+        };
+        let status = Status {
+            confirmed: (vec![txo(1, 20), txo(2, 10)], vec![]),
+            pending: (vec![], vec![]),
+            mempool: (vec![txo(3, 0)], vec![]),
+        };
+        let oldest = status.oldest(true).unwrap();
+        assert_eq!((oldest.txid, oldest.blockindex), (Sha256dHash::hash(&[2]), 10));
+
+        let status = Status {
+            confirmed: (vec![], vec![]),
+            pending: (vec![], vec![]),
+            mempool: (vec![txo(3, 0)], vec![]),
+        };
+        assert!(status.oldest(false).is_none());
+        assert_eq!(status.oldest(true).unwrap().txid, Sha256dHash::hash(&[3]));
+    }
+
+    #[test]
+    fn test_confirmed_since() {
+        let txo = |n: u8, blockindex| Txo {
+            txid: Sha256dHash::hash(&[n]),
+            vout: 0,
+            blockindex,
+        };
+        let spending = SpendingInput {
+            txid: Sha256dHash::hash(&[4]),
+            outpoint: (Sha256dHash::hash(&[1]), 0),
+            blockindex: 30,
+        };
+        let status = Status {
+            confirmed: (vec![txo(1, 10), txo(2, 20)], vec![spending]),
+            pending: (vec![txo(5, 31)], vec![]),
+            mempool: (vec![txo(3, 0)], vec![]),
+        };
+        let since: Vec<(usize, Sha256dHash)> = status
+            .confirmed_since(20)
+            .into_iter()
+            .map(|tx| (tx.blockindex, tx.txid))
+            .collect();
+        let expected = [(20, 2), (30, 4), (31, 5)];
+        let expected: Vec<(usize, Sha256dHash)> =
+            expected.iter().map(|(height, n)| (*height, Sha256dHash::hash(&[*n]))).collect();
+        assert_eq!(since, expected);
+        assert!(status.confirmed_since(32).is_empty());
+    }
+
+    #[test]
+    fn test_genesis_hash() {
+        use bitcoin_hashes::hex::ToHex;
+        assert_eq!(
+            genesis_hash(Network::Bitcoin).to_hex(),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(
+            genesis_hash(Network::Testnet).to_hex(),
+            "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"
+        );
+        assert_eq!(
+            genesis_hash(Network::Regtest).to_hex(),
+            "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"
+        );
+    }
+
+    #[test]
+    fn test_txid_limit() {
+        assert!(check_txid_limit(100, 100).is_ok());
+        assert!(check_txid_limit(101, 0).is_ok());
+
+        let err = check_txid_limit(101, 100)
+            .chain_err(|| "failed to get oldest tx")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "failed to get oldest tx");
+        let causes: Vec<String> = err.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            causes,
+            vec![
+                "failed to get oldest tx",
+                "101+ transactions found, query may take a long time",
+            ]
+        );
+
+        // the oldest outputs are kept once the limit is hit
+        let txo = |n: u8, blockindex| Txo {
+            txid: Sha256dHash::hash(&[n]),
+            vout: 0,
+            blockindex,
+        };
+        let mut txos = vec![txo(1, 30), txo(2, 10), txo(3, 20), txo(4, 10)];
+        keep_oldest(&mut txos, 0);
+        assert_eq!(txos.len(), 4);
+        keep_oldest(&mut txos, 4);
+        assert_eq!(txos.len(), 4);
+        keep_oldest(&mut txos, 3);
+        let mut kept: Vec<(usize, Sha256dHash)> =
+            txos.iter().map(|txo| (txo.blockindex, txo.txid)).collect();
+        kept.sort();
+        let mut expected = vec![
+            (10, Sha256dHash::hash(&[2])),
+            (10, Sha256dHash::hash(&[4])),
+            (20, Sha256dHash::hash(&[3])),
+        ];
+        expected.sort();
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn test_history_by_height() {
+        let txid = |i: u8| Sha256dHash::from_slice(&[i; 32]).unwrap();
+        let txo = |i, blockindex| Txo {
+            txid: txid(i),
+            vout: 0,
+            blockindex,
+        };
+        let status = Status {
+            confirmed: (
+                vec![txo(3, 10), txo(1, 20)],
+                vec![SpendingInput {
+                    txid: txid(2),
+                    outpoint: (txid(3), 0),
+                    blockindex: 15,
+                }],
+            ),
+            pending: (vec![txo(4, 21)], vec![]),
+            mempool: (vec![txo(0, 0)], vec![]),
+        };
+        let ids = |txids: Vec<Sha256dHash>| txids.iter().map(|txid| txid[0]).collect::<Vec<u8>>();
+        assert_eq!(ids(status.history()), vec![0, 1, 2, 3, 4]);
+        assert_eq!(ids(status.history_by_height(false)), vec![3, 2, 1, 4, 0]);
+        assert_eq!(ids(status.history_by_height(true)), vec![0, 4, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_value_deltas() {
+        let txid = |i: u8| Sha256dHash::from_slice(&[i; 32]).unwrap();
+        let txo = |i, vout, blockindex| Txo {
+            txid: txid(i),
+            vout,
+            blockindex,
+        };
+        // tx 2 spends the output of tx 1, and sends the change back (a self-transfer)
+        let status = Status {
+            confirmed: (
+                vec![txo(1, 0, 10), txo(2, 1, 20)],
+                vec![SpendingInput {
+                    txid: txid(2),
+                    outpoint: (txid(1), 0),
+                    blockindex: 20,
+                }],
+            ),
+            pending: (vec![], vec![]),
+            mempool: (vec![txo(3, 0, 0)], vec![]),
+        };
+        let values: HashMap<OutPoint, u64> =
+            vec![((txid(1), 0), 1000), ((txid(2), 1), 300), ((txid(3), 0), 50)]
+                .into_iter()
+                .collect();
+        let deltas = status.value_deltas(|txid, vout| Ok(values[&(*txid, vout)])).unwrap();
+        assert_eq!(deltas.len(), 3);
+        assert_eq!(deltas[&txid(1)], 1000);
+        assert_eq!(deltas[&txid(2)], -700);
+        assert_eq!(deltas[&txid(3)], 50);
+
+        let missing = status.value_deltas(|_, _| bail!("missing output"));
+        assert!(missing.is_err());
+
+        let unspent: Vec<OutPoint> =
+            status.unspent().iter().map(|txo| (txo.txid, txo.vout)).collect();
+        assert_eq!(unspent, vec![(txid(2), 1), (txid(3), 0)]);
+    }
+
+    #[test]
+    fn test_is_spendable() {
+        let p2pkh = Script::from(hex::decode(
+            "76a91489abcdefabbaabbaabbaabbaabbaabbaabbaabba88ac"
+        ).unwrap());
+        let op_return = Script::from(hex::decode("6a0b68656c6c6f20776f726c64").unwrap());
+        let output = |value, script: &Script| TxOut {
+            value,
+            script_pubkey: script.clone(),
+        };
+
+        assert!(is_spendable(&output(100_000, &p2pkh)));
+        assert!(is_spendable(&output(546, &p2pkh))); // dust
+        assert!(is_spendable(&output(1, &p2pkh)));
+        assert!(!is_spendable(&output(0, &p2pkh)));
+        assert!(!is_spendable(&output(0, &op_return)));
+        assert!(!is_spendable(&output(1000, &op_return)));
+    }
+
+    #[test]
+    fn test_lookups() {
+        let (script, change) = (Script::from(vec![0x51]), Script::from(vec![0x52]));
+        let funding = transaction(&[], &[(&script, 1000)]);
+        let spent = bitcoin::OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        };
+        let spending = transaction(&[spent], &[(&change, 900)]);
+        let blocks = chain(vec![vec![funding.clone()], vec![spending.clone()]]);
+        let (store, heights) = indexed_chain(&blocks);
+        let block_height = |blockhash: &Sha256dHash| heights.get(blockhash).copied();
+
+        let script_hash = compute_script_hash(&script[..]);
+        let txos = lookup_funding_outputs(&store, &script_hash, 1, &block_height).unwrap();
+        assert_eq!(txos.len(), 1);
+        assert_eq!((txos[0].txid, txos[0].vout, txos[0].blockindex), (funding.txid(), 0, 0));
+
+        let input = lookup_spending_input(&store, &txos[0], 1, &block_height).unwrap();
+        let input = input.expect("missing spending input");
+        assert_eq!((input.txid, input.blockindex), (spending.txid(), 1));
+        // not spent yet at the height of the funding block
+        assert!(lookup_spending_input(&store, &txos[0], 0, &block_height).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_spent_outputs() {
+        let (script, other) = (Script::from(vec![0x51]), Script::from(vec![0x52]));
+        let outpoint = |txid, vout| bitcoin::OutPoint { txid, vout };
+        let funding = transaction(&[], &[(&script, 1000), (&other, 10), (&script, 2000)]);
+        let refunding = transaction(&[outpoint(funding.txid(), 0)], &[(&script, 900)]);
+        let spending = transaction(
+            &[outpoint(funding.txid(), 1), outpoint(refunding.txid(), 0)],
+            &[(&other, 800)],
+        );
+        let blocks = chain(vec![vec![funding], vec![refunding], vec![spending]]);
+        let (store, heights) = indexed_chain(&blocks);
+        let block_height = |blockhash: &Sha256dHash| heights.get(blockhash).copied();
+
+        // same spent outputs as the lookups of the spending input of each output
+        let script_hash = compute_script_hash(&script[..]);
+        let txos = lookup_funding_outputs(&store, &script_hash, 9, &block_height).unwrap();
+        assert_eq!(txos.len(), 3);
+        let expected: HashSet<OutPoint> = txos
+            .iter()
+            .filter_map(|txo| lookup_spending_input(&store, txo, 9, &block_height).unwrap())
+            .map(|input| input.outpoint)
+            .collect();
+        assert_eq!(expected.len(), 2);
+        assert_eq!(lookup_spent_outputs(&store, &txos, &block_height).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_intra_block_spends() {
+        let script = Script::from(vec![0x51]);
+        let outpoint = |txid, vout| bitcoin::OutPoint { txid, vout };
+        // a chain of transactions within block 1, the last output being spent by a reorged
+        // block and by the mempool
+        let a = transaction(&[], &[(&script, 1000)]);
+        let b = transaction(&[outpoint(a.txid(), 0)], &[(&script, 900)]);
+        let c = transaction(&[outpoint(b.txid(), 0)], &[(&script, 800)]);
+        let d = transaction(&[outpoint(c.txid(), 0)], &[(&script, 700)]);
+        let e = transaction(&[outpoint(c.txid(), 0)], &[(&script, 600)]);
+        let blocks = chain(vec![vec![], vec![a.clone(), b.clone(), c.clone()]]);
+        let mut reorged = chain(vec![vec![], vec![d.clone()]]).remove(1);
+        reorged.header.nonce = 99;
+
+        let (store, heights) = indexed_chain(&blocks);
+        store.write(index_block(&reorged, &ScriptHashFilter::default())).unwrap();
+        let block_height = |blockhash: &Sha256dHash| heights.get(blockhash).copied();
+
+        let script_hash = compute_script_hash(&script[..]);
+        let txos = lookup_funding_outputs(&store, &script_hash, 9, &block_height).unwrap();
+        let mut funding: Vec<(Sha256dHash, usize)> =
+            txos.iter().map(|txo| (txo.txid, txo.blockindex)).collect();
+        funding.sort_unstable();
+        let mut expected = vec![(a.txid(), 1), (b.txid(), 1), (c.txid(), 1)];
+        expected.sort_unstable();
+        assert_eq!(funding, expected); // the output of the reorged block is skipped
+
+        let spending = |txo: &Txo| lookup_spending_input(&store, txo, 9, &block_height).unwrap();
+        let txo = |txid| txos.iter().find(|txo| txo.txid == txid).unwrap();
+        let input = spending(txo(a.txid())).unwrap();
+        assert_eq!((input.txid, input.blockindex), (b.txid(), 1));
+        let input = spending(txo(b.txid())).unwrap();
+        assert_eq!((input.txid, input.blockindex), (c.txid(), 1));
+        assert!(spending(txo(c.txid())).is_none()); // only spent by the reorged block
+        assert_eq!(lookup_spent_outputs(&store, &txos, &block_height).unwrap().len(), 2);
+
+        // the mempool transactions are indexed with a null block hash
+        let mempool = MemStore::default();
+        let filter = ScriptHashFilter::default();
+        mempool.write(index_transaction(&e, &Sha256dHash::default(), &filter)).unwrap();
+        let input = lookup_spending_input(&mempool, txo(c.txid()), 9, &block_height).unwrap();
+        assert_eq!(input.map(|input| (input.txid, input.blockindex)), Some((e.txid(), 0)));
+    }
+
+    #[test]
+    fn test_indexed_spends() {
+        let script = Script::from(vec![0x51]);
+        let outpoint = |txid, vout| bitcoin::OutPoint { txid, vout };
+        // the output of `b` is spent at height 2, and the one of `c` only by a reorged block
+        let a = transaction(&[], &[(&script, 1000)]);
+        let b = transaction(&[outpoint(a.txid(), 0)], &[(&script, 900)]);
+        let c = transaction(&[outpoint(b.txid(), 0)], &[(&script, 800)]);
+        let d = transaction(&[outpoint(c.txid(), 0)], &[(&script, 700)]);
+        let blocks = chain(vec![vec![], vec![a.clone(), b.clone()], vec![c.clone()]]);
+        let mut reorged = chain(vec![vec![], vec![], vec![d]]).remove(2);
+        reorged.header.nonce = 99;
+
+        let (store, heights) = indexed_chain(&blocks);
+        store.write(index_block(&reorged, &ScriptHashFilter::default())).unwrap();
+        for block in blocks.iter().chain(std::iter::once(&reorged)) {
+            store.write(spend_rows(block)).unwrap();
+        }
+        let block_height = |blockhash: &Sha256dHash| heights.get(blockhash).copied();
+
+        let spend = |txo: &Txo, height| {
+            let input = lookup_indexed_spending_input(&store, txo, height, &block_height).unwrap();
+            let scanned = lookup_spending_input(&store, txo, height, &block_height).unwrap();
+            let pair = |input: Option<SpendingInput>| input.map(|i| (i.txid, i.blockindex));
+            let input = pair(input);
+            assert_eq!(input, pair(scanned));
+            input
+        };
+        let txo = |txid, blockindex| Txo { txid, vout: 0, blockindex };
+        assert_eq!(spend(&txo(a.txid(), 1), 9), Some((b.txid(), 1)));
+        assert_eq!(spend(&txo(b.txid(), 1), 9), Some((c.txid(), 2)));
+        assert_eq!(spend(&txo(b.txid(), 1), 1), None); // above the requested height
+        assert_eq!(spend(&txo(c.txid(), 2), 9), None); // stale row of the reorged block
 
-    pub fn update_mempool(&self) -> Result<()> {
-        self.tracker.write().unwrap().update(self.app.daemon())
+        // the same outputs are spent, with the spend rows or the scan of the inputs
+        let txos = vec![txo(a.txid(), 1), txo(b.txid(), 1), txo(c.txid(), 2)];
+        let scanned = lookup_spent_outputs(&store, &txos, &block_height).unwrap();
+        let indexed = MemStore::default();
+        assert!(init_spend_rows(&indexed, true).unwrap());
+        indexed.write(store.rows().into_iter().map(|(key, value)| Row { key, value })).unwrap();
+        assert_eq!(lookup_spent_outputs(&indexed, &txos, &block_height).unwrap(), scanned);
+        assert_eq!(scanned.len(), 2);
     }
 }
@@ -1,64 +1,432 @@
 use bitcoin::consensus::encode::serialize;
 use bitcoin_hashes::hex::{FromHex, ToHex};
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
 use error_chain::ChainedError;
 use serde_json::{from_str, Value};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::{RecvTimeoutError, SyncSender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::errors::*;
-use crate::query::Query;
-use crate::util::{spawn_thread, Channel, SyncChannel};
+use crate::query::{is_spendable, Query};
+use crate::util::{difficulty, spawn_thread, Cancel, Channel, SyncChannel, ThreadPool};
 
 // Indexer version
 const ADDRINDEXRS_VERSION: &str = env!("CARGO_PKG_VERSION");
 // Version of the simulated electrum protocol
 const PROTOCOL_VERSION: &str = "1.4";
+// Max number of tips returned by blockchain.headers.tips
+const MAX_TIPS: usize = 1000;
+// Max number of headers returned by blockchain.block.get_timestamps
+const MAX_TIMESTAMPS: usize = 10000;
+// Max number of transactions returned by blockchain.transaction.find_by_suffix
+const MAX_PARTIAL_MATCHES: usize = 100;
+// Max number of script hashes of a blockchain.scripthashes.* request
+const MAX_SCRIPT_HASHES: usize = 1000;
+// Max size of a block returned by blockchain.block.get (the max serialized size of a block)
+const MAX_BLOCK_SIZE: usize = 4_000_000;
+// Handler of a method (`get_history` replies are written as they're serialized)
+enum Handler {
+    Value(fn(&mut Connection, &[Value]) -> Result<Value>),
+    History(fn(&mut Connection, &[Value]) -> Result<History>),
+}
+
+// Methods handled by `Connection::handle_command` (their names are returned by server.get_config)
+const METHODS: &[(&str, Handler)] = &[
+    ("blockchain.block.get", Handler::Value(|c, p| c.blockchain_block_get(p))),
+    ("blockchain.block.get_filter", Handler::Value(|c, p| c.blockchain_block_get_filter(p))),
+    (
+        "blockchain.block.get_funded_scripthashes",
+        Handler::Value(|c, p| c.blockchain_block_get_funded_scripthashes(p)),
+    ),
+    (
+        "blockchain.block.get_headers_by_hash",
+        Handler::Value(|c, p| c.blockchain_block_get_headers_by_hash(p)),
+    ),
+    ("blockchain.block.get_height", Handler::Value(|c, p| c.blockchain_block_get_height(p))),
+    (
+        "blockchain.block.get_median_time_past",
+        Handler::Value(|c, p| c.blockchain_block_get_median_time_past(p)),
+    ),
+    (
+        "blockchain.block.get_timestamps",
+        Handler::Value(|c, p| c.blockchain_block_get_timestamps(p)),
+    ),
+    (
+        "blockchain.block.get_witness_commitment",
+        Handler::Value(|c, p| c.blockchain_block_get_witness_commitment(p)),
+    ),
+    ("blockchain.block.headers", Handler::Value(|c, p| c.blockchain_block_headers(p))),
+    (
+        "blockchain.headers.get_chainwork",
+        Handler::Value(|c, _| c.blockchain_headers_get_chainwork()),
+    ),
+    ("blockchain.headers.subscribe", Handler::Value(|c, _| c.blockchain_headers_subscribe())),
+    ("blockchain.headers.tips", Handler::Value(|c, p| c.blockchain_headers_tips(p))),
+    ("blockchain.index.get_lag", Handler::Value(|c, _| c.blockchain_index_get_lag())),
+    (
+        "blockchain.index.get_last_indexed",
+        Handler::Value(|c, _| c.blockchain_index_get_last_indexed()),
+    ),
+    ("blockchain.index.verify", Handler::Value(|c, _| c.blockchain_index_verify())),
+    (
+        "blockchain.scripthash.get_activity",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_activity(p)),
+    ),
+    (
+        "blockchain.scripthash.get_balance",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_balance(p)),
+    ),
+    (
+        "blockchain.scripthash.get_funding",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_funding(p)),
+    ),
+    (
+        "blockchain.scripthash.get_history",
+        Handler::History(|c, p| c.blockchain_scripthash_get_history(p)),
+    ),
+    (
+        "blockchain.scripthash.get_mempool_delta",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_mempool_delta(p)),
+    ),
+    (
+        "blockchain.scripthash.get_pending",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_pending(p)),
+    ),
+    (
+        "blockchain.scripthash.get_oldest_tx",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_oldest_tx(p)),
+    ),
+    (
+        "blockchain.scripthash.get_totals",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_totals(p)),
+    ),
+    (
+        "blockchain.scripthash.get_tx_count",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_tx_count(p)),
+    ),
+    (
+        "blockchain.scripthash.get_utxo_summary",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_utxo_summary(p)),
+    ),
+    (
+        "blockchain.scripthash.get_utxos",
+        Handler::Value(|c, p| c.blockchain_scripthash_get_utxos(p)),
+    ),
+    (
+        "blockchain.scripthash.subscribe_mempool",
+        Handler::Value(|c, p| c.blockchain_scripthash_subscribe_mempool(p)),
+    ),
+    (
+        "blockchain.scripthashes.get_confirmed_since",
+        Handler::Value(|c, p| c.blockchain_scripthashes_get_confirmed_since(p)),
+    ),
+    (
+        "blockchain.scripthashes.get_utxos",
+        Handler::Value(|c, p| c.blockchain_scripthashes_get_utxos(p)),
+    ),
+    ("blockchain.outpoint.get_script", Handler::Value(|c, p| c.blockchain_outpoint_get_script(p))),
+    (
+        "blockchain.outpoint.get_spender",
+        Handler::Value(|c, p| c.blockchain_outpoint_get_spender(p)),
+    ),
+    ("blockchain.transaction.exists", Handler::Value(|c, p| c.blockchain_transaction_exists(p))),
+    (
+        "blockchain.transaction.find_by_suffix",
+        Handler::Value(|c, p| c.blockchain_transaction_find_by_suffix(p)),
+    ),
+    (
+        "blockchain.transaction.get_conflicts",
+        Handler::Value(|c, p| c.blockchain_transaction_get_conflicts(p)),
+    ),
+    (
+        "blockchain.transaction.get_confirmations",
+        Handler::Value(|c, p| c.blockchain_transaction_get_confirmations(p)),
+    ),
+    (
+        "blockchain.transaction.get_position",
+        Handler::Value(|c, p| c.blockchain_transaction_get_position(p)),
+    ),
+    ("mempool.get_fee_histogram", Handler::Value(|c, _| c.mempool_get_fee_histogram())),
+    ("mempool.info", Handler::Value(|c, _| c.mempool_info())),
+    ("mempool.refresh", Handler::Value(|c, _| c.mempool_refresh())),
+    ("server.features", Handler::Value(|c, _| c.server_features())),
+    ("server.get_config", Handler::Value(|c, _| c.server_get_config())),
+    ("server.ping", Handler::Value(|_, _| Ok(Value::Null))),
+    ("server.version", Handler::Value(|c, _| c.server_version())),
+];
+// JSON-RPC error codes for unparsable and malformed requests, unknown methods, invalid
+// requests and server failures
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const INTERNAL_ERROR: i32 = -32603;
+// (implementation-defined) all the RPC threads are busy with other connections
+const SERVER_BUSY: i32 = -32000;
+
+fn error_reply(options: &Options, id: &Value, code: i32, message: String) -> Value {
+    let error = if options.rpc_error_codes {
+        json!({"code": code, "message": message})
+    } else {
+        json!(message)
+    };
+    json!({"jsonrpc": "2.0", "id": id, "error": error})
+}
+
+//
+// Error caused by the request itself (instead of a server failure)
+//
+fn bad_param<S: Into<String>>(msg: S) -> ErrorKind {
+    ErrorKind::InvalidRequest(msg.into())
+}
+
+fn is_invalid_request(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::InvalidRequest(_))
+}
 
 //
 // Get a script hash from a given value
 //
 fn hash_from_value(val: Option<&Value>) -> Result<Sha256dHash> {
     // TODO: Sha256dHash should be a generic hash-container (since script hash is single SHA256)
-    let script_hash = val.chain_err(|| "missing hash")?;
-    let script_hash = script_hash.as_str().chain_err(|| "non-string hash")?;
-    let script_hash = Sha256dHash::from_hex(script_hash).chain_err(|| "non-hex hash")?;
+    let script_hash = val.chain_err(|| bad_param("missing hash"))?;
+    let script_hash = script_hash.as_str().chain_err(|| bad_param("non-string hash"))?;
+    let script_hash = Sha256dHash::from_hex(script_hash).chain_err(|| bad_param("non-hex hash"))?;
     Ok(script_hash)
 }
 
+//
+// Get the (deduplicated) script hashes of a list
+//
+fn script_hashes_from_value(val: Option<&Value>) -> Result<Vec<Sha256dHash>> {
+    let values = val
+        .and_then(Value::as_array)
+        .chain_err(|| bad_param("bad script_hashes"))?;
+    if values.len() > MAX_SCRIPT_HASHES {
+        bail!(bad_param(format!(
+            "{} script hashes (max {})",
+            values.len(),
+            MAX_SCRIPT_HASHES
+        )));
+    }
+    let mut script_hashes = vec![];
+    for value in values {
+        let script_hash = hash_from_value(Some(value)).chain_err(|| bad_param("bad script_hash"))?;
+        if !script_hashes.contains(&script_hash) {
+            script_hashes.push(script_hash);
+        }
+    }
+    Ok(script_hashes)
+}
+
+//
+// Serialize a history reply incrementally
+// (large histories are written as they are serialized, instead of building the whole JSON value:
+// this only bounds the memory of the reply, the history itself is still fully looked up first)
+//
+fn write_history<W: Write>(
+    writer: &mut W,
+    id: &Value,
+    history: &[Sha256dHash],
+    deltas: Option<&HashMap<Sha256dHash, i64>>,
+) -> io::Result<()> {
+    write!(writer, "{{\"id\":{},\"jsonrpc\":\"2.0\",\"result\":[", id)?;
+    for (i, txid) in history.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        match deltas.and_then(|deltas| deltas.get(txid)) {
+            Some(delta) => write!(
+                writer,
+                "{{\"tx_hash\":\"{}\",\"delta\":{}}}",
+                txid.to_hex(),
+                delta
+            )?,
+            None => write!(writer, "{{\"tx_hash\":\"{}\"}}", txid.to_hex())?,
+        }
+    }
+    writer.write_all(b"]}\n")
+}
+
+//
+// Detect a TLS handshake record (content type 22, protocol version 3.x)
+//
+fn is_tls_handshake(data: &[u8]) -> bool {
+    match data {
+        [22, 3, minor, ..] => *minor <= 4,
+        _ => false,
+    }
+}
+
+// Fatal TLS alert record (content type 21, protocol version 3.1) with a handshake_failure (40)
+const TLS_HANDSHAKE_FAILURE: [u8; 7] = [21, 3, 1, 0, 2, 2, 40];
+
+//
+// Reply to the clients starting a TLS handshake, before closing their connection
+//
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsReply {
+    #[default]
+    None, // close the connection silently
+    Alert, // a TLS handshake_failure alert, reported by the TLS clients
+    Error, // a plaintext JSON-RPC error, readable by the users of raw TLS tools
+}
+
+impl TlsReply {
+    fn bytes(self, options: &Options) -> Option<Vec<u8>> {
+        match self {
+            TlsReply::None => None,
+            TlsReply::Alert => Some(TLS_HANDSHAKE_FAILURE.to_vec()),
+            TlsReply::Error => {
+                let message = "TLS is not supported, connect without TLS or through a TLS proxy";
+                let reply = error_reply(options, &Value::Null, INVALID_REQUEST, message.into());
+                Some(format!("{}\n", reply).into_bytes())
+            }
+        }
+    }
+}
+
+//
+// Reply to a RPC request
+//
+enum Reply {
+    Value(Value),
+    History(Value, History), // (id, history)
+}
+
+// txids, and their value deltas in verbose mode
+type History = (Vec<Sha256dHash>, Option<HashMap<Sha256dHash, i64>>);
+
+//
+// Options of the RPC server
+//
+#[derive(Clone)]
+pub struct Options {
+    pub threads: usize,
+    pub disconnect_on_unknown_method: bool,
+    pub disconnect_on_bad_request: bool, // invalid JSON or malformed requests
+    pub instance_name: Option<String>,
+    pub max_subscriptions: usize, // per connection (0 means no limit)
+    pub max_headers: usize,       // per blockchain.block.headers request
+    pub rpc_error_codes: bool,    // errors as {"code", "message"} objects (instead of strings)
+    pub error_details: ErrorDetails,
+    pub tls_reply: TlsReply,
+    pub idle_timeout: Option<Duration>, // max delay between two requests of a connection
+}
+
+//
+// Details of the server failures sent to the clients
+// (the invalid requests are always explained, and the whole failure is always logged)
+//
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorDetails {
+    Full, // the whole chain of errors
+    #[default]
+    Message, // the outermost error only
+    None,    // a generic message
+}
+
+impl ErrorDetails {
+    fn describe(self, e: &Error) -> String {
+        if is_invalid_request(e) {
+            return e.to_string();
+        }
+        match self {
+            ErrorDetails::Full => e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": "),
+            ErrorDetails::Message => e.to_string(),
+            ErrorDetails::None => "internal error".to_string(),
+        }
+    }
+}
+
+//
+// Mempool notifications of a connection
+// (coalesced while the connection is busy, instead of blocking the notifier)
+//
+#[derive(Default)]
+struct MempoolNotifications {
+    pending: AtomicBool,
+    dropped: AtomicUsize, // notifications not queued because the connection was busy
+}
+
 //
 // Connection with a RPC client
 //
 struct Connection {
     query: Arc<Query>,
+    options: Options,
     stream: TcpStream,
     addr: SocketAddr,
     chan: SyncChannel<Message>,
+    notifications: Arc<MempoolNotifications>,
+    mempool_hashes: HashMap<Sha256dHash, Value>, // subscribed script hashes
+    cancel: Arc<Cancel>, // set once the client is gone, to stop its running query
 }
 
 impl Connection {
     pub fn new(
         query: Arc<Query>,
+        options: Options,
         stream: TcpStream,
         addr: SocketAddr,
     ) -> Connection {
         Connection {
             query,
+            options,
             stream,
             addr,
             chan: SyncChannel::new(10),
+            notifications: Arc::new(MempoolNotifications::default()),
+            mempool_hashes: HashMap::new(),
+            cancel: Arc::new(Cancel::new()),
         }
     }
 
     fn server_version(&self) -> Result<Value> {
-        Ok(json!([
-            format!("addrindexrs {}", ADDRINDEXRS_VERSION),
-            PROTOCOL_VERSION
-        ]))
+        let software = match &self.options.instance_name {
+            Some(name) => format!("addrindexrs {} ({})", ADDRINDEXRS_VERSION, name),
+            None => format!("addrindexrs {}", ADDRINDEXRS_VERSION),
+        };
+        Ok(json!([software, PROTOCOL_VERSION]))
+    }
+
+    fn server_features(&self) -> Result<Value> {
+        let mut features = json!({
+            "genesis_hash": self.query.get_genesis_hash().to_hex(),
+            "server_version": format!("addrindexrs {}", ADDRINDEXRS_VERSION),
+            "protocol_min": PROTOCOL_VERSION,
+            "protocol_max": PROTOCOL_VERSION,
+            "hash_function": "sha256",
+            "pruning": null,
+            "hosts": {},
+        });
+        if let Some(name) = &self.options.instance_name {
+            features["instance_name"] = json!(name);
+        }
+        Ok(features)
+    }
+
+    // Settings of the server, so that clients can adapt to its capabilities
+    // (e.g. `blockchain.scripthash.get_totals` needs the indexed outputs)
+    fn server_get_config(&self) -> Result<Value> {
+        let mut config = serde_json::to_value(self.query.settings())
+            .chain_err(|| "failed to serialize the settings")?;
+        config["protocol_version"] = json!(PROTOCOL_VERSION);
+        config["max_subscriptions"] = json!(self.options.max_subscriptions);
+        config["max_headers"] = json!(self.options.max_headers);
+        config["max_script_hashes"] = json!(MAX_SCRIPT_HASHES);
+        let methods: Vec<&str> = METHODS.iter().map(|(name, _)| *name).collect();
+        config["methods"] = json!(methods);
+        Ok(config)
     }
 
     fn blockchain_headers_subscribe(&mut self) -> Result<Value> {
@@ -68,86 +436,708 @@ impl Connection {
         Ok(result)
     }
 
-    fn blockchain_scripthash_get_balance(&self, _params: &[Value]) -> Result<Value> {
-        Ok(
-            json!({ "confirmed": null, "unconfirmed": null }),
-        )
+    fn blockchain_headers_get_chainwork(&self) -> Result<Value> {
+        let (entry, chainwork) = self.query.get_chainwork()?;
+        Ok(json!({
+            "hash": entry.hash().to_hex(),
+            "height": entry.height(),
+            "chainwork": chainwork.to_string().trim_start_matches("0x"),
+            "difficulty": difficulty(entry.header().bits),
+        }))
     }
 
-    fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
-        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..], 9999999999, false)?;
+    fn blockchain_headers_tips(&self, params: &[Value]) -> Result<Value> {
+        let count = match params.get(0) {
+            Some(value) => value.as_u64().chain_err(|| bad_param("bad count"))? as usize,
+            None => 10,
+        };
+        if count > MAX_TIPS {
+            bail!(bad_param(format!("count {} is larger than {}", count, MAX_TIPS)));
+        }
+        let tips = self.query.get_tips(count)?;
+        Ok(json!(tips
+            .iter()
+            .map(|entry| json!({
+                "hash": entry.hash().to_hex(),
+                "height": entry.height(),
+                "prev_hash": entry.header().prev_blockhash.to_hex(),
+            }))
+            .collect::<Vec<Value>>()))
+    }
+
+    fn blockchain_index_get_lag(&self) -> Result<Value> {
+        let lag = self.query.get_index_lag()?;
+        Ok(json!({
+            "blocks": lag.blocks,
+            "tip_age": lag.tip_age,
+            "daemon_time_lag": lag.daemon_time_lag,
+        }))
+    }
+
+    fn blockchain_index_get_last_indexed(&self) -> Result<Value> {
+        Ok(match self.query.get_last_indexed()? {
+            Some(entry) => json!({"hash": entry.hash().to_hex(), "height": entry.height()}),
+            None => Value::Null,
+        })
+    }
+
+    fn blockchain_block_headers(&self, params: &[Value]) -> Result<Value> {
+        let start = params
+            .get(0)
+            .and_then(Value::as_u64)
+            .chain_err(|| bad_param("bad start_height"))? as usize;
+        let count = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .chain_err(|| bad_param("bad count"))? as usize;
+        let cp_height = match params.get(2) {
+            Some(value) => value.as_u64().chain_err(|| bad_param("bad cp_height"))?,
+            None => 0,
+        };
+        if cp_height != 0 {
+            bail!(bad_param("checkpoint proofs (cp_height) are not supported"));
+        }
+        let max = self.options.max_headers;
+        let headers = self.query.get_headers(start, count.min(max));
+        let hex: String = headers
+            .iter()
+            .map(|entry| hex::encode(serialize(entry.header())))
+            .collect();
+        Ok(json!({"count": headers.len(), "hex": hex, "max": max}))
+    }
+
+    // Headers of the given blocks, in the same order (null for the blocks not in the best chain)
+    fn blockchain_block_get_headers_by_hash(&self, params: &[Value]) -> Result<Value> {
+        let values = params
+            .get(0)
+            .and_then(Value::as_array)
+            .chain_err(|| bad_param("bad block_hashes"))?;
+        let max = self.options.max_headers;
+        if values.len() > max {
+            bail!(bad_param(format!("{} block hashes (max {})", values.len(), max)));
+        }
+        let mut headers = vec![];
+        for value in values {
+            let blockhash =
+                hash_from_value(Some(value)).chain_err(|| bad_param("bad block_hash"))?;
+            headers.push(match self.query.get_header_by_block_hash(&blockhash) {
+                Some(entry) => json!({
+                    "hex": hex::encode(serialize(entry.header())),
+                    "height": entry.height(),
+                }),
+                None => Value::Null,
+            });
+        }
+        Ok(json!(headers))
+    }
+
+    fn blockchain_block_get_timestamps(&self, params: &[Value]) -> Result<Value> {
+        let start = params
+            .get(0)
+            .and_then(Value::as_u64)
+            .chain_err(|| bad_param("bad start_height"))?;
+        let end = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .chain_err(|| bad_param("bad end_height"))?;
+        let with_hashes = match params.get(2) {
+            Some(value) => value.as_bool().chain_err(|| bad_param("bad with_hashes"))?,
+            None => false,
+        };
+        let (start, end) = (start as usize, end as usize);
+        if end.saturating_sub(start) >= MAX_TIMESTAMPS {
+            bail!(bad_param(format!(
+                "range {}..{} is larger than {}",
+                start, end, MAX_TIMESTAMPS
+            )));
+        }
+        let headers = self.query.get_header_range(start, end)?;
+        Ok(json!(headers
+            .iter()
+            .map(|entry| {
+                let mut item = json!({"height": entry.height(), "timestamp": entry.header().time});
+                if with_hashes {
+                    item["hash"] = json!(entry.hash().to_hex());
+                }
+                item
+            })
+            .collect::<Vec<Value>>()))
+    }
+
+    // A block is specified either by its height or by its hash
+    fn blockhash_from_value(&self, value: Option<&Value>) -> Result<Sha256dHash> {
+        Ok(match value {
+            Some(Value::Number(height)) => {
+                let height = height.as_u64().chain_err(|| bad_param("bad height"))? as usize;
+                *self.query.get_header(height)?.hash()
+            }
+            value => hash_from_value(value).chain_err(|| bad_param("bad block_hash"))?,
+        })
+    }
+
+    fn blockchain_block_get_funded_scripthashes(&self, params: &[Value]) -> Result<Value> {
+        let blockhash = self.blockhash_from_value(params.get(0))?;
+        let script_hashes = self.query.funded_script_hashes(&blockhash)?;
+        Ok(json!(script_hashes
+            .iter()
+            .map(|script_hash| Sha256dHash::from_slice(script_hash).unwrap().to_hex())
+            .collect::<Vec<String>>()))
+    }
+
+    fn blockchain_block_get(&self, params: &[Value]) -> Result<Value> {
+        let blockhash = self.blockhash_from_value(params.get(0))?;
+        let block_hex = self.query.get_block_raw(&blockhash)?;
+        if block_hex.len() / 2 > MAX_BLOCK_SIZE {
+            bail!("block {} is too large ({} bytes)", blockhash, block_hex.len() / 2);
+        }
+        Ok(json!({"block_hash": blockhash.to_hex(), "hex": block_hex}))
+    }
+
+    // Height of a block of the best chain (the reorged blocks are unknown)
+    fn blockchain_block_get_height(&self, params: &[Value]) -> Result<Value> {
+        let blockhash = hash_from_value(params.get(0)).chain_err(|| bad_param("bad block_hash"))?;
+        let header = self.query.get_block_index(blockhash).chain_err(|| {
+            ErrorKind::InvalidRequest(format!("block {} is not in the best chain", blockhash))
+        })?;
+        Ok(json!(header.height()))
+    }
+
+    fn blockchain_block_get_median_time_past(&self, params: &[Value]) -> Result<Value> {
+        let height = params
+            .get(0)
+            .and_then(Value::as_u64)
+            .chain_err(|| bad_param("bad height"))? as usize;
+        Ok(json!(self.query.get_median_time_past(height)?))
+    }
+
+    fn blockchain_block_get_filter(&self, params: &[Value]) -> Result<Value> {
+        let blockhash = self.blockhash_from_value(params.get(0))?;
+        let filter = self.query.get_block_filter(&blockhash)?;
+        Ok(json!({"block_hash": blockhash.to_hex(), "filter": hex::encode(filter)}))
+    }
+
+    fn blockchain_block_get_witness_commitment(&self, params: &[Value]) -> Result<Value> {
+        let blockhash = self.blockhash_from_value(params.get(0))?;
+        let commitment = self.query.get_witness_commitment(&blockhash)?;
+        Ok(json!({
+            "block_hash": blockhash.to_hex(),
+            "commitment": commitment.map(hex::encode),
+        }))
+    }
+
+    fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        Ok(match self.query.balance(&script_hash[..], &self.cancel)? {
+            Some((confirmed, unconfirmed)) => {
+                json!({ "confirmed": confirmed, "unconfirmed": unconfirmed })
+            }
+            None => json!({ "confirmed": null, "unconfirmed": null }),
+        })
+    }
+
+    fn blockchain_scripthash_get_activity(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        let (confirmed, mempool) = self.query.activity(&script_hash[..])?;
+        Ok(json!({ "confirmed": confirmed, "mempool": mempool }))
+    }
+
+    fn blockchain_scripthash_get_totals(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        let (received, sent) = self.query.totals(&script_hash[..], &self.cancel)?;
+        Ok(json!({ "received": received, "sent": sent }))
+    }
+
+    fn blockchain_scripthash_get_mempool_delta(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        let delta = self.query.mempool_delta(&script_hash[..], &self.cancel)?;
+        Ok(json!({ "unconfirmed": delta }))
+    }
+
+    fn blockchain_scripthash_get_funding(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        let start_height = match params.get(1) {
+            Some(value) => value.as_u64().chain_err(|| bad_param("bad start_height"))? as usize,
+            None => 0,
+        };
+        let end_height = match params.get(2) {
+            Some(value) => value.as_u64().chain_err(|| bad_param("bad end_height"))? as usize,
+            None => self.query.get_best_header()?.height(),
+        };
+        let txos = self.query.funding_outputs(&script_hash[..], start_height, end_height)?;
+        let mut result = vec![];
+        for txo in txos {
+            result.push(json!({
+                "tx_hash": txo.txid.to_hex(),
+                "vout": txo.vout,
+                "height": txo.blockindex,
+                "value": self.query.get_output_value(&txo.txid, txo.vout)?,
+            }));
+        }
+        Ok(json!(result))
+    }
+
+    fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<History> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        // the verbose entries have the value delta of their transaction
+        let verbose = match params.get(2) {
+            Some(value) => value.as_bool().chain_err(|| bad_param("bad verbose"))?,
+            None => false,
+        };
+        let status = self.query.status(&script_hash[..], 9999999999, false, &self.cancel)?;
+        // ordered by txid unless an explicit height ordering is requested
+        let history = match params.get(1) {
+            None | Some(Value::Null) => status.history(),
+            Some(order) => match order.as_str() {
+                Some("asc") => status.history_by_height(false),
+                Some("desc") => status.history_by_height(true),
+                _ => bail!(bad_param(format!(
+                    "bad order {} (expected \"asc\" or \"desc\")",
+                    order
+                ))),
+            },
+        };
+        let deltas = if verbose {
+            Some(self.query.value_deltas(&script_hash[..], &status, &self.cancel)?)
+        } else {
+            None
+        };
+        Ok((history, deltas))
+    }
+
+    fn blockchain_scripthash_get_tx_count(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        // not limited by txid_limit, since the history isn't sent back
+        Ok(json!(self.query.tx_count(&script_hash[..], &self.cancel)?))
+    }
+
+    fn blockchain_scripthash_get_pending(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        let status = self.query.status(&script_hash[..], 9999999999, false, &self.cancel)?;
         Ok(json!(Value::Array(
             status
-                .history()
+                .pending_history()
                 .into_iter()
-                .map(|item| json!({"tx_hash": item.to_hex()}))
+                .map(|item| json!({"tx_hash": item.txid.to_hex(), "block_index": item.blockindex}))
                 .collect()
         )))
     }
 
     fn blockchain_scripthash_get_oldest_tx(&self, params: &[Value]) -> Result<Value> {
-        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
         let current_block_index = match params.get(1) {
             Some(value) => {
                 if let Some(int_value) = value.as_u64() {
                     int_value as usize
                 } else {
-                    return Err("bad block_index".into());
+                    return Err(bad_param("bad block_index").into());
                 }
             },
             None => 9999999999,
         };
-        let oldest_tx = self.query.oldest_tx(&script_hash[..], current_block_index)?;
-        Ok(json!({"tx_hash":oldest_tx.txid.to_hex(),"block_index":oldest_tx.blockindex}))
+        // "oldest" is about the confirmed history by default
+        let include_mempool = match params.get(2) {
+            Some(value) => value
+                .as_bool()
+                .chain_err(|| bad_param("bad include_mempool"))?,
+            None => false,
+        };
+        let oldest_tx = self
+            .query
+            .oldest_tx(&script_hash[..], current_block_index, include_mempool, &self.cancel)?;
+        // an unused address isn't an error: both fields are null
+        Ok(match oldest_tx {
+            Some(oldest_tx) => {
+                json!({"tx_hash":oldest_tx.txid.to_hex(),"block_index":oldest_tx.blockindex})
+            }
+            None => json!({"tx_hash": null, "block_index": null}),
+        })
+    }
+
+    fn blockchain_scripthashes_get_utxos(&self, params: &[Value]) -> Result<Value> {
+        let script_hashes = script_hashes_from_value(params.get(0))?;
+        let include_unspendable = match params.get(1) {
+            Some(value) => value
+                .as_bool()
+                .chain_err(|| bad_param("bad include_unspendable"))?,
+            None => true,
+        };
+        let utxos = self.query.utxos(&script_hashes, &self.cancel)?;
+
+        let mut seen = HashSet::new();
+        let mut found = vec![];
+        for (script_hash, outpoints) in script_hashes.iter().zip(utxos) {
+            for outpoint in outpoints {
+                if !seen.insert(outpoint) {
+                    continue; // e.g. funding several script hashes with the same index prefix
+                }
+                found.push((script_hash, outpoint));
+            }
+        }
+        if !include_unspendable {
+            let outpoints: Vec<(Sha256dHash, usize)> =
+                found.iter().map(|(_, outpoint)| *outpoint).collect();
+            let outputs = self.query.get_outputs(&outpoints)?;
+            found = found
+                .into_iter()
+                .zip(outputs)
+                .filter(|(_, output)| is_spendable(output))
+                .map(|(item, _)| item)
+                .collect();
+        }
+        let result: Vec<Value> = found
+            .into_iter()
+            .map(|(script_hash, (txid, vout))| {
+                json!({
+                    "scripthash": script_hash.to_hex(),
+                    "utxo": txid.to_hex() + ":" + &vout.to_string(),
+                })
+            })
+            .collect();
+        Ok(json!(result))
+    }
+
+    // Transactions confirmed at or after a height (e.g. to poll for new deposits)
+    fn blockchain_scripthashes_get_confirmed_since(&self, params: &[Value]) -> Result<Value> {
+        let script_hashes = script_hashes_from_value(params.get(0))?;
+        let since_height = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .chain_err(|| bad_param("bad since_height"))? as usize;
+        let mut result = vec![];
+        for script_hash in &script_hashes {
+            let status = self.query.status(&script_hash[..], 9999999999, false, &self.cancel)?;
+            for tx in status.confirmed_since(since_height) {
+                result.push((tx.blockindex, tx.txid, script_hash));
+            }
+        }
+        result.sort_unstable();
+        Ok(json!(result
+            .into_iter()
+            .map(|(height, txid, script_hash)| json!({
+                "scripthash": script_hash.to_hex(),
+                "tx_hash": txid.to_hex(),
+                "height": height,
+            }))
+            .collect::<Vec<Value>>()))
+    }
+
+    fn blockchain_scripthash_get_utxo_summary(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        let (count, value) = self.query.utxo_summary(&script_hash[..], &self.cancel)?;
+        Ok(json!({ "count": count, "value": value }))
     }
 
     fn blockchain_scripthash_get_utxos(&self, params: &[Value]) -> Result<Value> {
-        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..], 9999999999, false)?;
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        // zero-value and provably unspendable (e.g. OP_RETURN) outputs are returned by default
+        let include_unspendable = match params.get(1) {
+            Some(value) => value
+                .as_bool()
+                .chain_err(|| bad_param("bad include_unspendable"))?,
+            None => true,
+        };
+        // the outputs spent by mempool transactions are removed by default, or else kept
+        // (and marked as such) for an optimistic view
+        let keep_mempool_spent = match params.get(2) {
+            Some(value) => value
+                .as_bool()
+                .chain_err(|| bad_param("bad keep_mempool_spent"))?,
+            None => false,
+        };
+        let status = self.query.status(&script_hash[..], 9999999999, false, &self.cancel)?;
 
         let mut dict = HashMap::new();
         for item in status.funding().into_iter() {
-            dict.insert(item.txid.to_hex() + ":" + &item.vout.to_string(), "");
+            dict.insert((item.txid, item.vout), false);
+        }
+
+        for item in status.confirmed_spending() {
+            dict.remove(&item.outpoint);
+        }
+        for item in status.mempool_spending() {
+            if keep_mempool_spent {
+                if let Some(pending_spend) = dict.get_mut(&item.outpoint) {
+                    *pending_spend = true;
+                }
+            } else {
+                dict.remove(&item.outpoint);
+            }
         }
 
-        for item in status.spending().into_iter() {
-            dict.remove( &(item.outpoint.0.to_hex() + ":" + &item.outpoint.1.to_string()) );
+        let mut unspent: Vec<((Sha256dHash, usize), bool)> = dict.into_iter().collect();
+        if !include_unspendable {
+            let outpoints: Vec<(Sha256dHash, usize)> =
+                unspent.iter().map(|(outpoint, _)| *outpoint).collect();
+            let outputs = self.query.get_outputs(&outpoints)?;
+            unspent = unspent
+                .into_iter()
+                .zip(outputs)
+                .filter(|(_, output)| is_spendable(output))
+                .map(|(item, _)| item)
+                .collect();
         }
 
         let mut utxos = vec![];
-        for (outpoint, _drop) in &dict {
-            utxos.push(outpoint)
+        for ((txid, vout), pending_spend) in &unspent {
+            let utxo = txid.to_hex() + ":" + &vout.to_string();
+            if keep_mempool_spent {
+                utxos.push(json!({"utxo": utxo, "pending_spend": pending_spend}));
+            } else {
+                utxos.push(json!(utxo));
+            }
         }
 
         Ok(json!(utxos))
     }
 
-    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
-        let result = match method {
-            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
-            "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
-            "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
-            "blockchain.scripthash.get_oldest_tx" => self.blockchain_scripthash_get_oldest_tx(&params),
-            "blockchain.scripthash.get_utxos" => self.blockchain_scripthash_get_utxos(&params),
-            "server.ping" => Ok(Value::Null),
-            "server.version" => self.server_version(),
-            &_ => bail!("unknown method {} {:?}", method, params),
+    fn blockchain_outpoint_get_spender(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.get(0)).chain_err(|| bad_param("bad tx_hash"))?;
+        let vout = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .chain_err(|| bad_param("bad vout"))? as usize;
+        // the spending transaction is returned only if requested
+        let verbose = match params.get(2).map(Value::as_str) {
+            None | Some(Some("none")) => None,
+            Some(Some("hex")) => Some(false),
+            Some(Some("decoded")) => Some(true),
+            Some(_) => bail!(bad_param(format!(
+                "bad tx_format {} (expected \"none\", \"hex\" or \"decoded\")",
+                params[2]
+            ))),
+        };
+        let spent = match self.query.get_spending_input(&txid, vout)? {
+            Some(spent) => spent,
+            None => return Ok(json!({"spent": false})),
         };
-        // TODO: return application errors should be sent to the client
-        Ok(match result {
+        // the height of mempool transactions is 0
+        let mut result = json!({
+            "spent": true,
+            "tx_hash": spent.txid.to_hex(),
+            "height": spent.blockindex,
+        });
+        if let Some(verbose) = verbose {
+            result["tx"] = self.query.get_transaction_raw(&spent.txid, spent.blockindex, verbose)?;
+        }
+        Ok(result)
+    }
+
+    fn blockchain_outpoint_get_script(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.get(0)).chain_err(|| bad_param("bad tx_hash"))?;
+        let vout = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .chain_err(|| bad_param("bad vout"))? as usize;
+        // null if the transaction or the output doesn't exist
+        Ok(match self.query.find_output(&txid, vout)? {
+            Some(output) => json!(hex::encode(output.script_pubkey.as_bytes())),
+            None => Value::Null,
+        })
+    }
+
+    fn blockchain_transaction_exists(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.get(0)).chain_err(|| bad_param("bad tx_hash"))?;
+        let status = self.query.tx_status(&txid)?;
+        let mut result = json!({"exists": status.is_some(), "confirmed": status.unwrap_or(false)});
+        // the fee of unconfirmed transactions is known from the mempool
+        if let Some((fee, vsize)) = self.query.get_mempool_fee(&txid) {
+            result["fee"] = json!(fee);
+            result["vsize"] = json!(vsize);
+        }
+        Ok(result)
+    }
+
+    fn blockchain_transaction_find_by_suffix(&self, params: &[Value]) -> Result<Value> {
+        let suffix = params
+            .get(0)
+            .and_then(Value::as_str)
+            .chain_err(|| bad_param("missing txid suffix"))?;
+        // txids are shown in reversed hex, so their suffix is the prefix of the indexed txid
+        let mut prefix = hex::decode(suffix).chain_err(|| bad_param("non-hex txid suffix"))?;
+        prefix.reverse();
+        let txs = self.query.find_txs_by_prefix(&prefix, MAX_PARTIAL_MATCHES)?;
+        Ok(json!(txs
+            .into_iter()
+            .map(|tx| json!({"tx_hash": tx.txid.to_hex(), "height": tx.blockindex}))
+            .collect::<Vec<Value>>()))
+    }
+
+    fn blockchain_transaction_get_conflicts(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.get(0)).chain_err(|| bad_param("bad tx_hash"))?;
+        let conflicts = self.query.mempool_conflicts(&txid);
+        Ok(json!(conflicts
+            .into_iter()
+            .map(|txid| txid.to_hex())
+            .collect::<Vec<String>>()))
+    }
+
+    fn blockchain_transaction_get_confirmations(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.get(0)).chain_err(|| bad_param("bad tx_hash"))?;
+        Ok(json!(self.query.get_confirmations(&txid)?))
+    }
+
+    fn blockchain_transaction_get_position(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.get(0)).chain_err(|| bad_param("bad tx_hash"))?;
+        let (header, position) = self.query.get_tx_position(&txid)?;
+        Ok(json!({
+            "block_hash": header.hash().to_hex(),
+            "height": header.height(),
+            "position": position,
+        }))
+    }
+
+    fn mempool_get_fee_histogram(&self) -> Result<Value> {
+        Ok(json!(self.query.get_fee_histogram()))
+    }
+
+    fn mempool_info(&self) -> Result<Value> {
+        let (count, size) = self.query.get_mempool_info();
+        Ok(json!({"count": count, "bytes": size}))
+    }
+
+    fn mempool_refresh(&self) -> Result<Value> {
+        if !self.addr.ip().is_loopback() {
+            bail!(bad_param("mempool.refresh is restricted to localhost"));
+        }
+        Ok(json!(self.query.refresh_mempool()?))
+    }
+
+    fn blockchain_index_verify(&self) -> Result<Value> {
+        if !self.addr.ip().is_loopback() {
+            bail!(bad_param("blockchain.index.verify is restricted to localhost"));
+        }
+        let (running, report) = self.query.verify_index();
+        Ok(json!({"running": running, "report": report}))
+    }
+
+    fn mempool_hash(&self, script_hash: &Sha256dHash) -> Result<Value> {
+        let status = self.query.status(&script_hash[..], 9999999999, false, &self.cancel)?;
+        Ok(match status.mempool_hash() {
+            Some(hash) => json!(hex::encode(hash)),
+            None => Value::Null,
+        })
+    }
+
+    fn blockchain_scripthash_subscribe_mempool(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value(params.get(0)).chain_err(|| bad_param("bad script_hash"))?;
+        let limit = self.options.max_subscriptions;
+        if limit > 0
+            && self.mempool_hashes.len() >= limit
+            && !self.mempool_hashes.contains_key(&script_hash)
+        {
+            bail!(bad_param(format!(
+                "too many subscriptions (max {} per connection)",
+                limit
+            )));
+        }
+        let result = self.mempool_hash(&script_hash)?;
+        self.mempool_hashes.insert(script_hash, result.clone());
+        Ok(result)
+    }
+
+    fn on_mempool_update(&mut self) -> Result<Vec<Value>> {
+        let mut result = vec![];
+        let script_hashes: Vec<Sha256dHash> = self.mempool_hashes.keys().cloned().collect();
+        for script_hash in script_hashes {
+            let new_hash = self.mempool_hash(&script_hash)?;
+            if self.mempool_hashes.get(&script_hash) == Some(&new_hash) {
+                continue;
+            }
+            result.push(json!({
+                "jsonrpc": "2.0",
+                "method": "blockchain.scripthash.subscribe_mempool",
+                "params": [script_hash.to_hex(), new_hash]}));
+            self.mempool_hashes.insert(script_hash, new_hash);
+        }
+        Ok(result)
+    }
+
+    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Reply> {
+        let handler = match METHODS.iter().find(|(name, _)| *name == method) {
+            Some((_, handler)) => handler,
+            None => {
+                if self.options.disconnect_on_unknown_method {
+                    bail!("unknown method {} {:?}", method, params)
+                }
+                trace!("rpc #{} unknown method {} {:?}", id, method, params);
+                let message = format!("unknown method {}", method);
+                return Ok(Reply::Value(error_reply(&self.options, id, METHOD_NOT_FOUND, message)));
+            }
+        };
+        let result = match handler {
+            Handler::Value(handle) => handle(self, params),
+            Handler::History(handle) => match handle(self, params) {
+                Ok(history) => return Ok(Reply::History(id.clone(), history)),
+                Err(e) => Err(e),
+            },
+        };
+        Ok(Reply::Value(match result {
             Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
             Err(e) => {
-                warn!(
-                    "rpc #{} {} {:?} failed: {}",
-                    id,
-                    method,
-                    params,
-                    e.display_chain()
-                );
-                json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
+                let code = if is_invalid_request(&e) {
+                    debug!("rpc #{} {} {:?} is invalid: {}", id, method, params, e);
+                    INVALID_PARAMS
+                } else if self.cancel.is_cancelled() {
+                    debug!("rpc #{} {} {:?} cancelled: {}", id, method, params, e);
+                    INTERNAL_ERROR
+                } else {
+                    warn!(
+                        "rpc #{} {} {:?} failed: {}",
+                        id,
+                        method,
+                        params,
+                        e.display_chain()
+                    );
+                    INTERNAL_ERROR
+                };
+                let message = self.options.error_details.describe(&e);
+                error_reply(&self.options, id, code, message)
             }
-        })
+        }))
+    }
+
+    // Reply to a request line (malformed requests get an error reply, keeping the connection,
+    // unless `disconnect_on_bad_request`)
+    fn handle_request(&mut self, line: &str) -> Result<Reply> {
+        let empty_params = json!([]);
+        let cmd: Value = match from_str(line) {
+            Ok(cmd) => cmd,
+            Err(e) if self.options.disconnect_on_bad_request => bail!("invalid JSON: {}", e),
+            Err(e) => {
+                debug!("[{}] invalid JSON {:?}: {}", self.addr, line, e);
+                let message = format!("invalid JSON: {}", e);
+                let reply = error_reply(&self.options, &Value::Null, PARSE_ERROR, message);
+                return Ok(Reply::Value(reply));
+            }
+        };
+        match (
+            cmd.get("method"),
+            cmd.get("params").unwrap_or(&empty_params),
+            cmd.get("id"),
+        ) {
+            (Some(Value::String(method)), Value::Array(params), Some(id)) => {
+                self.handle_command(method, params, id)
+            }
+            _ if self.options.disconnect_on_bad_request => bail!("invalid command: {}", cmd),
+            _ => {
+                debug!("[{}] invalid command: {}", self.addr, cmd);
+                let id = cmd.get("id").unwrap_or(&Value::Null);
+                let message = format!("invalid command: {}", cmd);
+                Ok(Reply::Value(error_reply(&self.options, id, INVALID_REQUEST, message)))
+            }
+        }
     }
 
     fn send_values(&mut self, values: &[Value]) -> Result<()> {
@@ -160,34 +1150,76 @@ impl Connection {
         Ok(())
     }
 
+    fn send_reply(&mut self, reply: Reply) -> Result<()> {
+        match reply {
+            Reply::Value(value) => self.send_values(&[value]),
+            Reply::History(id, (history, deltas)) => {
+                let mut writer = BufWriter::new(&self.stream);
+                write_history(&mut writer, &id, &history, deltas.as_ref())
+                    .and_then(|()| writer.flush())
+                    .chain_err(|| format!("failed to send history of rpc #{}", id))
+            }
+        }
+    }
+
     fn handle_replies(&mut self) -> Result<()> {
-        let empty_params = json!([]);
+        let mut last_request = Instant::now();
         loop {
-            let msg = self.chan.receiver().recv().chain_err(|| "channel closed")?;
+            let msg = match self.options.idle_timeout {
+                // the mempool notifications don't keep an idle connection alive
+                Some(timeout) => {
+                    let remaining = timeout.saturating_sub(last_request.elapsed());
+                    match self.chan.receiver().recv_timeout(remaining) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => {
+                            info!("[{}] closing idle connection after {:?}", self.addr, timeout);
+                            return Ok(());
+                        }
+                        Err(RecvTimeoutError::Disconnected) => bail!("channel closed"),
+                    }
+                }
+                None => self.chan.receiver().recv().chain_err(|| "channel closed")?,
+            };
             trace!("RPC {:?}", msg);
             match msg {
                 Message::Request(line) => {
-                    let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
-                    let reply = match (
-                        cmd.get("method"),
-                        cmd.get("params").unwrap_or_else(|| &empty_params),
-                        cmd.get("id"),
-                    ) {
-                        (
-                            Some(&Value::String(ref method)),
-                            &Value::Array(ref params),
-                            Some(ref id),
-                        ) => self.handle_command(method, params, id)?,
-                        _ => bail!("invalid command: {}", cmd),
-                    };
-                    self.send_values(&[reply])?
+                    last_request = Instant::now();
+                    let reply = self.handle_request(&line)?;
+                    self.send_reply(reply)?;
+                    self.send_mempool_notifications()?
                 }
+                Message::MempoolUpdate => self.send_mempool_notifications()?,
                 Message::Done => return Ok(()),
             }
         }
     }
 
-    fn handle_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    // Send the notifications of a mempool update (if not sent yet)
+    fn send_mempool_notifications(&mut self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Ok(()); // nobody to notify
+        }
+        if self.notifications.pending.swap(false, Ordering::SeqCst) {
+            let notifications = self.on_mempool_update()?;
+            self.send_values(&notifications)?;
+        }
+        Ok(())
+    }
+
+    fn handle_requests(
+        mut reader: BufReader<TcpStream>,
+        tx: SyncSender<Message>,
+        tls_reply: Option<Vec<u8>>,
+    ) -> Result<()> {
+        // TLS clients wait for the server's reply, so don't wait for a newline to detect them
+        let received = reader.fill_buf().chain_err(|| "failed to read a request")?;
+        if is_tls_handshake(received) {
+            if let Some(reply) = tls_reply {
+                let _ = reader.get_mut().write_all(&reply);
+            }
+            let _ = tx.send(Message::Done);
+            bail!("TLS handshake received - terminate TLS in a proxy (see doc/usage.md)")
+        }
         loop {
             let mut line = Vec::<u8>::new();
             reader
@@ -197,8 +1229,7 @@ impl Connection {
                 tx.send(Message::Done).chain_err(|| "channel closed")?;
                 return Ok(());
             } else {
-                if line.starts_with(&[22, 3, 1]) {
-                    // (very) naive SSL handshake detection
+                if is_tls_handshake(&line) {
                     let _ = tx.send(Message::Done);
                     bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
                 }
@@ -218,7 +1249,13 @@ impl Connection {
     pub fn run(mut self) {
         let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
         let tx = self.chan.sender();
-        let child = spawn_thread("reader", || Connection::handle_requests(reader, tx));
+        let cancel = Arc::clone(&self.cancel);
+        let tls_reply = self.options.tls_reply.bytes(&self.options);
+        let child = spawn_thread("reader", move || {
+            let result = Connection::handle_requests(reader, tx, tls_reply);
+            cancel.cancel(); // no more requests: the client is gone (or sent garbage)
+            result
+        });
         if let Err(e) = self.handle_replies() {
             error!(
                 "[{}] connection handling failed: {}",
@@ -226,7 +1263,11 @@ impl Connection {
                 e.display_chain().to_string()
             );
         }
-        debug!("[{}] shutting down connection", self.addr);
+        debug!(
+            "[{}] shutting down connection ({} mempool notifications were coalesced)",
+            self.addr,
+            self.notifications.dropped.load(Ordering::SeqCst)
+        );
         let _ = self.stream.shutdown(Shutdown::Both);
         if let Err(err) = child.join().expect("receiver panicked") {
             error!("[{}] receiver failed: {}", self.addr, err);
@@ -240,86 +1281,97 @@ impl Connection {
 #[derive(Debug)]
 pub enum Message {
     Request(String),
+    MempoolUpdate,
     Done,
 }
 
 //
 // RPC server
 //
+type Peers = HashMap<i32, (SyncSender<Message>, Arc<MempoolNotifications>)>;
+
 pub struct RPC {
+    senders: Arc<Mutex<Peers>>,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
 }
 
 impl RPC {
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
+    // All the listeners feed the same channel of accepted connections
+    fn start_acceptors(addrs: Vec<SocketAddr>) -> Channel<Option<(TcpStream, SocketAddr)>> {
         let chan = Channel::unbounded();
-        let acceptor = chan.sender();
-        spawn_thread("acceptor", move || {
-            let listener =
-                TcpListener::bind(addr).unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
-            info!(
-                "Indexer RPC server running on {} (protocol {})",
-                addr, PROTOCOL_VERSION
-            );
-            loop {
-                let (stream, addr) = listener.accept().expect("accept failed");
-                stream
-                    .set_nonblocking(false)
-                    .expect("failed to set connection as blocking");
-                acceptor.send(Some((stream, addr))).expect("send failed");
-            }
-        });
+        for addr in addrs {
+            let acceptor = chan.sender();
+            spawn_thread("acceptor", move || {
+                let listener = TcpListener::bind(addr)
+                    .unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
+                info!(
+                    "Indexer RPC server running on {} (protocol {})",
+                    addr, PROTOCOL_VERSION
+                );
+                loop {
+                    let (stream, addr) = listener.accept().expect("accept failed");
+                    stream
+                        .set_nonblocking(false)
+                        .expect("failed to set connection as blocking");
+                    acceptor.send(Some((stream, addr))).expect("send failed");
+                }
+            });
+        }
         chan
     }
 
-    pub fn start(addr: SocketAddr, query: Arc<Query>) -> RPC {
+    // Reply with an error (without waiting for a request), and close the connection
+    fn reject(mut stream: TcpStream, options: &Options) {
+        let message = "all the RPC threads are busy, try again later".to_owned();
+        let reply = error_reply(options, &Value::Null, SERVER_BUSY, message);
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+        let _ = stream.write_all(format!("{}\n", reply).as_bytes());
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    pub fn start(addrs: Vec<SocketAddr>, query: Arc<Query>, options: Options) -> RPC {
+        let senders = Arc::new(Mutex::new(Peers::new()));
         RPC {
+            senders: Arc::clone(&senders),
             server: Some(spawn_thread("rpc", move || {
-                let senders = Arc::new(Mutex::new(HashMap::<i32, SyncSender<Message>>::new()));
-                let handles = Arc::new(Mutex::new(
-                    HashMap::<i32, std::thread::JoinHandle<()>>::new(),
-                ));
+                let pool = ThreadPool::new("peer", options.threads);
 
-                let acceptor = RPC::start_acceptor(addr);
+                let acceptor = RPC::start_acceptors(addrs);
                 let mut handle_count = 0;
 
                 while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
+                    // a connection beyond the peer threads would wait unserved (and its reader
+                    // thread would be one too many), so it's rejected instead
+                    let active = senders.lock().unwrap().len();
+                    if active >= options.threads {
+                        warn!("[{}] rejecting connection: {} peers are connected", addr, active);
+                        RPC::reject(stream, &options);
+                        continue;
+                    }
                     let handle_id = handle_count;
                     handle_count += 1;
-                    // explicitely scope the shadowed variables for the new thread
-                    let handle: thread::JoinHandle<()> = {
-                        let query = Arc::clone(&query);
-                        let senders = Arc::clone(&senders);
-                        let handles = Arc::clone(&handles);
-
-                        spawn_thread("peer", move || {
-                            info!("[{}] connected peer #{}", addr, handle_id);
-                            let conn = Connection::new(query, stream, addr);
-                            senders
-                                .lock()
-                                .unwrap()
-                                .insert(handle_id, conn.chan.sender());
-                            conn.run();
-                            info!("[{}] disconnected peer #{}", addr, handle_id);
-                            senders.lock().unwrap().remove(&handle_id);
-                            handles.lock().unwrap().remove(&handle_id);
-                        })
-                    };
-
-                    handles.lock().unwrap().insert(handle_id, handle);
+                    let conn = Connection::new(Arc::clone(&query), options.clone(), stream, addr);
+                    senders
+                        .lock()
+                        .unwrap()
+                        .insert(handle_id, (conn.chan.sender(), conn.notifications.clone()));
+                    // explicitely scope the shadowed variables for the new job
+                    let senders = Arc::clone(&senders);
+                    pool.execute(move || {
+                        info!("[{}] connected peer #{}", addr, handle_id);
+                        conn.run();
+                        info!("[{}] disconnected peer #{}", addr, handle_id);
+                        senders.lock().unwrap().remove(&handle_id);
+                    });
                 }
 
                 trace!("closing {} RPC connections", senders.lock().unwrap().len());
-                for sender in senders.lock().unwrap().values() {
+                for (sender, _) in senders.lock().unwrap().values() {
                     let _ = sender.send(Message::Done);
                 }
 
-                trace!("waiting for {} RPC handling threads", handles.lock().unwrap().len());
-                for (_, handle) in handles.lock().unwrap().drain() {
-                    if let Err(e) = handle.join() {
-                        warn!("failed to join thread: {:?}", e);
-                    }
-                }
+                trace!("waiting for RPC handling threads");
+                drop(pool);
 
                 trace!("RPC connections are closed");
             })),
@@ -327,6 +1379,19 @@ impl RPC {
     }
 }
 
+impl RPC {
+    // Notify the connections that the mempool has changed
+    pub fn notify_mempool(&self) {
+        for (sender, notifications) in self.senders.lock().unwrap().values() {
+            notifications.pending.store(true, Ordering::SeqCst);
+            // don't block on a busy connection, it's notified after its current request
+            if sender.try_send(Message::MempoolUpdate).is_err() {
+                notifications.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
 impl Drop for RPC {
     fn drop(&mut self) {
         trace!("stop accepting new RPCs");
@@ -336,3 +1401,94 @@ impl Drop for RPC {
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_methods() {
+        let names: HashSet<&str> = METHODS.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names.len(), METHODS.len(), "duplicated methods");
+    }
+
+    #[test]
+    fn test_is_tls_handshake() {
+        assert!(is_tls_handshake(&[22, 3, 1, 2, 0]));
+        assert!(is_tls_handshake(&[22, 3, 3]));
+        assert!(!is_tls_handshake(&[22, 3]));
+        assert!(!is_tls_handshake(b"{\"id\": 1}\n"));
+    }
+
+    #[test]
+    fn test_tls_reply() {
+        let options = Options {
+            threads: 1,
+            disconnect_on_unknown_method: false,
+            disconnect_on_bad_request: false,
+            instance_name: None,
+            max_subscriptions: 0,
+            max_headers: 2016,
+            rpc_error_codes: true,
+            error_details: ErrorDetails::Message,
+            tls_reply: TlsReply::None,
+            idle_timeout: None,
+        };
+        assert_eq!(TlsReply::None.bytes(&options), None);
+        assert_eq!(
+            TlsReply::Alert.bytes(&options),
+            Some(vec![21, 3, 1, 0, 2, 2, 40])
+        );
+        let error = TlsReply::Error.bytes(&options).unwrap();
+        assert_eq!(error.last(), Some(&b'\n'));
+        let error: Value = serde_json::from_slice(&error).unwrap();
+        assert_eq!(error["id"], Value::Null);
+        assert_eq!(error["error"]["code"], INVALID_REQUEST);
+    }
+
+    #[test]
+    fn test_error_details() {
+        let failure = Error::from("no such file").chain_err(|| "failed to read block");
+        assert_eq!(
+            ErrorDetails::Full.describe(&failure),
+            "failed to read block: no such file"
+        );
+        assert_eq!(ErrorDetails::Message.describe(&failure), "failed to read block");
+        assert_eq!(ErrorDetails::None.describe(&failure), "internal error");
+
+        let invalid = Error::from(bad_param("non-hex hash"));
+        assert_eq!(ErrorDetails::None.describe(&invalid), "non-hex hash");
+    }
+
+    #[test]
+    fn test_write_history() {
+        let history = vec![
+            Sha256dHash::default(),
+            Sha256dHash::from_hex(&"ab".repeat(32)).unwrap(),
+        ];
+        let id = json!(42);
+        let mut buf = vec![];
+        write_history(&mut buf, &id, &history, None).unwrap();
+        let expected = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": history
+                .iter()
+                .map(|txid| json!({"tx_hash": txid.to_hex()}))
+                .collect::<Vec<Value>>(),
+        });
+        assert_eq!(String::from_utf8(buf).unwrap(), expected.to_string() + "\n");
+
+        let mut buf = vec![];
+        write_history(&mut buf, &json!("id"), &[], None).unwrap();
+        let reply: Value = from_str(&String::from_utf8(buf).unwrap()).unwrap();
+        assert_eq!(reply["result"], json!([]));
+
+        let deltas = history.iter().cloned().zip(vec![-1500, 0]).collect();
+        let mut buf = vec![];
+        write_history(&mut buf, &id, &history, Some(&deltas)).unwrap();
+        let reply: Value = from_str(&String::from_utf8(buf).unwrap()).unwrap();
+        assert_eq!(reply["result"][0]["delta"], json!(-1500));
+        assert_eq!(reply["result"][1]["delta"], json!(0));
+    }
+}
@@ -1,6 +1,8 @@
 use rocksdb;
+use std::fs;
 use std::path::{Path, PathBuf};
 
+use crate::errors::*;
 use crate::util::Bytes;
 
 //
@@ -22,13 +24,13 @@ impl Row {
 // Traits for the Db store
 //
 pub trait ReadStore: Sync {
-    fn get(&self, key: &[u8]) -> Option<Bytes>;
-    fn scan(&self, prefix: &[u8]) -> Vec<Row>;
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>>;
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Row>>;
 }
 
 pub trait WriteStore: Sync {
-    fn write<I: IntoIterator<Item = Row>>(&self, rows: I);
-    fn flush(&self);
+    fn write<I: IntoIterator<Item = Row>>(&self, rows: I) -> Result<()>;
+    fn flush(&self) -> Result<()>;
 }
 
 //
@@ -38,7 +40,59 @@ pub trait WriteStore: Sync {
 struct Options {
     path: PathBuf,
     bulk_import: bool,
-    low_memory: bool,
+    memory_budget: Option<usize>, // in bytes
+    read_only: bool,
+}
+
+//
+// Column families of the rows, by the code of their keys
+// (the other rows, e.g. the markers, are stored in the default one)
+//
+const DEFAULT_COLUMN_FAMILY: &str = "default";
+const COLUMN_FAMILIES: &[(u8, &str)] = &[
+    (b'I', "txin"),
+    (b'O', "txout"),
+    (b'T', "tx"),
+    (b'B', "block"),
+    (b'P', "output"),
+    (b'X', "spend"),
+];
+
+// Size of the memtables of a column family (all of them use at most a quarter of the budget)
+fn write_buffer_size(memory_budget: Option<usize>) -> usize {
+    const MAX: usize = 256 << 20;
+    match memory_budget {
+        Some(budget) => (budget / 4 / (COLUMN_FAMILIES.len() + 1)).clamp(4 << 20, MAX),
+        None => MAX,
+    }
+}
+
+fn column_family_name(key: &[u8]) -> &'static str {
+    COLUMN_FAMILIES
+        .iter()
+        .find(|(code, _)| key.first() == Some(code))
+        .map_or(DEFAULT_COLUMN_FAMILY, |(_, name)| name)
+}
+
+//
+// Version of the DB schema
+// (bumped when the existing rows can't be read anymore, which requires a reindex)
+//
+// 2: rows split into column families
+// 3: outputs' column family (`--index-outputs`)
+// 4: spends' column family (`--index-spends`)
+// 5: transactions' block hash only in their key
+const DB_VERSION: u32 = 5;
+
+fn db_version_row() -> Row {
+    Row {
+        key: b"V".to_vec(),
+        value: DB_VERSION.to_le_bytes().to_vec(),
+    }
+}
+
+pub fn has_current_version(store: &dyn ReadStore) -> Result<bool> {
+    Ok(store.get(&db_version_row().key)? == Some(db_version_row().value))
 }
 
 //
@@ -50,7 +104,7 @@ pub struct DBStore {
 }
 
 impl DBStore {
-    fn open_opts(opts: Options) -> Self {
+    fn open_opts(opts: Options) -> Result<Self> {
         debug!("opening DB at {:?}", opts.path);
         let mut db_opts = rocksdb::Options::default();
         db_opts.create_if_missing(true);
@@ -59,52 +113,120 @@ impl DBStore {
         db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
         db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
         db_opts.set_target_file_size_base(256 << 20);
-        db_opts.set_write_buffer_size(256 << 20);
+        db_opts.set_write_buffer_size(write_buffer_size(opts.memory_budget));
         db_opts.set_disable_auto_compactions(opts.bulk_import); // for initial bulk load
         db_opts.set_advise_random_on_open(!opts.bulk_import); // bulk load uses sequential I/O
-        if !opts.low_memory {
-            db_opts.set_compaction_readahead_size(1 << 20);
+        match opts.memory_budget {
+            Some(budget) => db_opts.set_db_write_buffer_size(budget / 4),
+            None => db_opts.set_compaction_readahead_size(1 << 20),
         }
 
+        let low_memory = opts.memory_budget.is_some();
         let mut block_opts = rocksdb::BlockBasedOptions::default();
-        block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
-        DBStore {
-            db: rocksdb::DB::open(&db_opts, &opts.path).unwrap(),
+        block_opts.set_block_size(if low_memory { 256 << 10 } else { 1 << 20 });
+        db_opts.create_missing_column_families(true);
+        let mut names: Vec<String> = std::iter::once(DEFAULT_COLUMN_FAMILY)
+            .chain(COLUMN_FAMILIES.iter().map(|(_, name)| *name))
+            .map(str::to_owned)
+            .collect();
+        if opts.read_only {
+            // the column families can't be created: open the existing ones, so that a DB of an
+            // older schema is opened (and rejected by its version)
+            let existing = rocksdb::DB::list_cf(&db_opts, &opts.path).unwrap_or_default();
+            names.retain(|name| name == DEFAULT_COLUMN_FAMILY || existing.contains(name));
+        }
+        let cfs = names
+            .iter()
+            .map(|name| rocksdb::ColumnFamilyDescriptor::new(name, db_opts.clone()));
+        let db = if opts.read_only {
+            rocksdb::DB::open_cf_descriptors_read_only(&db_opts, &opts.path, cfs, false)
+        } else {
+            rocksdb::DB::open_cf_descriptors(&db_opts, &opts.path, cfs)
+        };
+        let store = DBStore {
+            db: db.chain_err(|| format!("failed to open DB at {:?}", opts.path))?,
             opts,
+        };
+        // a new DB has the current schema
+        let is_empty = store.db.iterator(rocksdb::IteratorMode::Start).next().is_none();
+        if is_empty && !store.opts.read_only {
+            store.write(vec![db_version_row()])?;
+            store.flush()?;
         }
+        Ok(store)
+    }
+
+    fn cf(&self, key: &[u8]) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(column_family_name(key)).unwrap()
+    }
+
+    fn column_families(&self) -> impl Iterator<Item = &rocksdb::ColumnFamily> {
+        let names = COLUMN_FAMILIES.iter().map(|(_, name)| *name);
+        std::iter::once(DEFAULT_COLUMN_FAMILY)
+            .chain(names)
+            .map(move |name| self.db.cf_handle(name).unwrap())
     }
 
     /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, low_memory: bool) -> Self {
+    /// With a memory budget (in bytes), its buffers are kept within a quarter of it.
+    pub fn open(path: &Path, memory_budget: Option<usize>) -> Result<Self> {
         DBStore::open_opts(Options {
             path: path.to_path_buf(),
             bulk_import: true,
-            low_memory,
+            memory_budget,
+            read_only: false,
+        })
+    }
+
+    /// Opens an existing DB without writing to it (e.g. a copy on read-only storage).
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        DBStore::open_opts(Options {
+            path: path.to_path_buf(),
+            bulk_import: false,
+            memory_budget: None,
+            read_only: true,
         })
     }
 
-    pub fn enable_compaction(self) -> Self {
+    pub fn path(&self) -> &Path {
+        &self.opts.path
+    }
+
+    pub fn enable_compaction(self) -> Result<Self> {
         let mut opts = self.opts.clone();
         if opts.bulk_import {
             opts.bulk_import = false;
             info!("enabling auto-compactions");
             let opts = [("disable_auto_compactions", "false")];
-            self.db.set_options(&opts).unwrap();
+            for cf in self.column_families() {
+                self.db
+                    .set_options_cf(cf, &opts)
+                    .chain_err(|| "failed to enable auto-compactions")?;
+            }
         }
-        self
+        Ok(self)
     }
 
     pub fn compact(self) -> Self {
         info!("starting full compaction");
-        self.db.compact_range(None::<&[u8]>, None::<&[u8]>); // would take a while
+        for cf in self.column_families() {
+            self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>); // would take a while
+        }
         info!("finished full compaction");
         self
     }
 
+    pub fn snapshot(&self) -> DBSnapshot<'_> {
+        DBSnapshot {
+            store: self,
+            snapshot: self.db.snapshot(),
+        }
+    }
+
     pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
         ScanIterator {
             prefix: prefix.to_vec(),
-            iter: self.db.prefix_iterator(prefix),
+            iter: self.db.prefix_iterator_cf(self.cf(prefix), prefix),
             done: false,
         }
     }
@@ -142,26 +264,52 @@ impl<'a> Iterator for ScanIterator<'a> {
 // Read functions for the Db store
 //
 impl ReadStore for DBStore {
-    fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.db.get(key).unwrap().map(|v| v.to_vec())
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let value = self.db.get_cf(self.cf(key), key).chain_err(|| "failed to read from DB")?;
+        Ok(value.map(|v| v.to_vec()))
     }
 
     // TODO: use generators
-    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
-        let mut rows = vec![];
-        for (key, value) in self.db.iterator(rocksdb::IteratorMode::From(
-            prefix,
-            rocksdb::Direction::Forward,
-        )).filter_map(Result::ok) {
-            if !key.starts_with(prefix) {
-                break;
-            }
-            rows.push(Row {
-                key: key.to_vec(),
-                value: value.to_vec(),
-            });
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Row>> {
+        let mode = rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward);
+        collect_rows(self.db.iterator_cf(self.cf(prefix), mode), prefix)
+    }
+}
+
+fn collect_rows(iter: rocksdb::DBIterator, prefix: &[u8]) -> Result<Vec<Row>> {
+    let mut rows = vec![];
+    for item in iter {
+        let (key, value) = item.chain_err(|| "failed to scan DB")?;
+        if !key.starts_with(prefix) {
+            break;
         }
-        rows
+        rows.push(Row {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+    }
+    Ok(rows)
+}
+
+//
+// Point-in-time snapshot of the Db store
+// (the rows written after its creation, e.g. by a concurrent index update, aren't visible)
+//
+pub struct DBSnapshot<'a> {
+    store: &'a DBStore,
+    snapshot: rocksdb::Snapshot<'a>,
+}
+
+impl ReadStore for DBSnapshot<'_> {
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let cf = self.store.cf(key);
+        let value = self.snapshot.get_cf(cf, key).chain_err(|| "failed to read from DB")?;
+        Ok(value.map(|v| v.to_vec()))
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Row>> {
+        let mode = rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward);
+        collect_rows(self.snapshot.iterator_cf(self.store.cf(prefix), mode), prefix)
     }
 }
 
@@ -169,19 +317,19 @@ impl ReadStore for DBStore {
 // Write functions for the Db store
 //
 impl WriteStore for DBStore {
-    fn write<I: IntoIterator<Item = Row>>(&self, rows: I) {
+    fn write<I: IntoIterator<Item = Row>>(&self, rows: I) -> Result<()> {
         let mut batch = rocksdb::WriteBatch::default();
         for row in rows {
-            batch.put(row.key.as_slice(), row.value.as_slice());
+            batch.put_cf(self.cf(&row.key), row.key.as_slice(), row.value.as_slice());
         }
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(!self.opts.bulk_import);
         opts.disable_wal(self.opts.bulk_import);
-        self.db.write_opt(batch, &opts).unwrap();
+        self.db.write_opt(batch, &opts).chain_err(|| "failed to write to DB")
     }
 
-    fn flush(&self) {
-        self.db.flush().unwrap();
+    fn flush(&self) -> Result<()> {
+        self.db.flush().chain_err(|| "failed to flush DB")
     }
 }
 
@@ -204,14 +352,64 @@ fn full_compaction_marker() -> Row {
     }
 }
 
-pub fn full_compaction(store: DBStore) -> DBStore {
-    store.write(vec![full_compaction_marker()]);
-    store.flush();
-    let store = store.compact().enable_compaction();
-    store
+pub fn full_compaction(store: DBStore) -> Result<DBStore> {
+    store.write(vec![full_compaction_marker()])?;
+    store.flush()?;
+    store.compact().enable_compaction()
+}
+
+/// Marks the initial import as complete, leaving the compaction to RocksDB.
+pub fn skip_full_compaction(store: DBStore) -> Result<DBStore> {
+    info!("skipping full compaction");
+    store.write(vec![full_compaction_marker()])?;
+    store.flush()?;
+    store.enable_compaction()
+}
+
+pub fn is_fully_compacted(store: &dyn ReadStore) -> Result<bool> {
+    let marker = store.get(&full_compaction_marker().key)?;
+    Ok(marker.is_some())
+}
+
+//
+// Reindexing
+// (a new index is built next to the served one, and moved in place once complete)
+//
+pub fn reindex_path(path: &Path) -> PathBuf {
+    path.with_extension("reindex")
+}
+
+/// Removes the RocksDB at the specified location (if any).
+pub fn destroy(path: &Path) -> Result<()> {
+    if path.exists() {
+        info!("removing DB at {:?}", path);
+        fs::remove_dir_all(path).chain_err(|| format!("failed to remove {:?}", path))?;
+    }
+    Ok(())
 }
 
-pub fn is_fully_compacted(store: &dyn ReadStore) -> bool {
-    let marker = store.get(&full_compaction_marker().key);
-    marker.is_some()
+/// Replaces the RocksDB at the specified location by a complete reindex (if any).
+/// Must be called before the DB is opened.
+/// (the replaced DB is moved aside until the reindex is in place, so that an interruption
+/// never leaves no DB at all)
+pub fn promote_reindex(path: &Path) -> Result<()> {
+    let staged_path = reindex_path(path);
+    let replaced_path = path.with_extension("replaced");
+    if !staged_path.exists() {
+        // e.g. interrupted before removing the replaced DB
+        return destroy(&replaced_path);
+    }
+    if !is_fully_compacted(&DBStore::open(&staged_path, None)?)? {
+        debug!("reindex at {:?} is incomplete", staged_path);
+        return Ok(());
+    }
+    info!("moving reindexed DB from {:?} to {:?}", staged_path, path);
+    if path.exists() {
+        destroy(&replaced_path)?;
+        fs::rename(path, &replaced_path)
+            .chain_err(|| format!("failed to move {:?} to {:?}", path, replaced_path))?;
+    }
+    fs::rename(&staged_path, path)
+        .chain_err(|| format!("failed to move {:?} to {:?}", staged_path, path))?;
+    destroy(&replaced_path)
 }
@@ -1,21 +1,28 @@
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::util::hash::BitcoinHash;
+use bitcoin::util::uint::Uint256;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::iter::FromIterator;
 use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use time;
 
+use crate::errors::*;
+
 pub type Bytes = Vec<u8>;
 pub type HeaderMap = HashMap<Sha256dHash, BlockHeader>;
 
 // TODO: consolidate serialization/deserialize code for bincode/bitcoin.
 const HASH_LEN: usize = 32;
 pub const HASH_PREFIX_LEN: usize = 8;
+// Number of blocks whose median timestamp is the median-time-past of the last one
+const MEDIAN_TIME_SPAN: usize = 11;
 
 pub type FullHash = [u8; HASH_LEN];
 pub type HashPrefix = [u8; HASH_PREFIX_LEN];
@@ -86,9 +93,36 @@ fn hash_headers(headers: Vec<BlockHeader>) -> Vec<HashedHeader> {
     hashed_headers
 }
 
+//
+// Difficulty of a header (as a multiple of the min difficulty, like bitcoind's getdifficulty)
+//
+pub fn difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = f64::from(0xffff) / f64::from(bits & 0x00ff_ffff);
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+    difficulty
+}
+
+// Expected number of hashes to mine a header (0 for an invalid null target)
+fn header_work(header: &BlockHeader) -> Uint256 {
+    if header.target() == Uint256::default() {
+        return Uint256::default();
+    }
+    header.work()
+}
+
+#[derive(Clone)]
 pub struct HeaderList {
     headers: Vec<HeaderEntry>,
     heights: HashMap<Sha256dHash, usize>,
+    chainwork: Uint256, // total work of the headers
 }
 
 impl HeaderList {
@@ -96,6 +130,7 @@ impl HeaderList {
         HeaderList {
             headers: vec![],
             heights: HashMap::new(),
+            chainwork: Uint256::default(),
         }
     }
 
@@ -125,11 +160,29 @@ impl HeaderList {
             .collect()
     }
 
+    // Whether `new_headers` (ordered by `order`) still extend the list, which may have
+    // changed meanwhile (`apply` panics otherwise)
+    pub fn connects(&self, new_headers: &[HeaderEntry]) -> bool {
+        let first = match new_headers.first() {
+            Some(first) => first,
+            None => return true,
+        };
+        let prev_blockhash = match first.height() {
+            0 => Sha256dHash::default(),
+            height => match self.headers.get(height - 1) {
+                Some(prev) => *prev.hash(),
+                None => return false,
+            },
+        };
+        first.header().prev_blockhash == prev_blockhash
+    }
+
     pub fn apply(&mut self, new_headers: Vec<HeaderEntry>, tip: Sha256dHash) {
         if tip == Sha256dHash::default() {
             assert!(new_headers.is_empty());
             self.heights.clear();
             self.headers.clear();
+            self.chainwork = Uint256::default();
             return;
         }
         // new_headers[i] -> new_headers[i - 1] (i.e. new_headers.last() is the tip)
@@ -170,11 +223,15 @@ impl HeaderList {
             new_headers.len(),
             new_height
         );
-        let _ = self.headers.split_off(new_height); // keep [0..new_height) entries
+        // keep [0..new_height) entries
+        for removed in self.headers.split_off(new_height) {
+            self.chainwork = self.chainwork - header_work(removed.header());
+        }
         assert_eq!(new_height, self.headers.len());
         for new_header in new_headers {
             assert_eq!(new_header.height(), self.headers.len());
             assert_eq!(new_header.header().prev_blockhash, self.tip());
+            self.chainwork = self.chainwork + header_work(new_header.header());
             self.heights.insert(*new_header.hash(), new_header.height());
             self.headers.push(new_header);
         }
@@ -199,6 +256,19 @@ impl HeaderList {
         })
     }
 
+    // Median of the timestamps of the block at `height` and of its (up to) 10 predecessors,
+    // as bitcoind's `mediantime` (BIP113)
+    pub fn median_time_past(&self, height: usize) -> Option<u32> {
+        self.header_by_height(height)?;
+        let start = height.saturating_sub(MEDIAN_TIME_SPAN - 1);
+        let mut times: Vec<u32> = self.headers[start..=height]
+            .iter()
+            .map(|entry| entry.header().time)
+            .collect();
+        times.sort_unstable();
+        Some(times[times.len() / 2])
+    }
+
     pub fn equals(&self, other: &HeaderList) -> bool {
         self.headers.last() == other.headers.last()
     }
@@ -207,6 +277,10 @@ impl HeaderList {
         self.headers.last().map(|h| *h.hash()).unwrap_or_default()
     }
 
+    pub fn chainwork(&self) -> Uint256 {
+        self.chainwork
+    }
+
     pub fn len(&self) -> usize {
         self.headers.len()
     }
@@ -280,8 +354,268 @@ where
         .unwrap()
 }
 
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+//
+// Bounded pool of worker threads
+// (jobs are queued while all the workers are busy)
+//
+pub struct ThreadPool {
+    jobs: Option<Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(name: &str, size: usize) -> ThreadPool {
+        assert!(size > 0, "empty {} thread pool", name);
+        let chan = Channel::<Job>::unbounded();
+        let jobs = Some(chan.sender());
+        let receiver = Arc::new(Mutex::new(chan.into_receiver()));
+        let workers = (0..size)
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                spawn_thread(&format!("{}-{}", name, i), move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break, // the pool is dropped
+                    }
+                })
+            })
+            .collect();
+        ThreadPool { jobs, workers }
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.jobs
+            .as_ref()
+            .unwrap()
+            .send(Box::new(f))
+            .expect("thread pool is closed");
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.jobs.take()); // queued jobs are still executed
+        for worker in self.workers.drain(..) {
+            if let Err(e) = worker.join() {
+                warn!("failed to join worker thread: {:?}", e);
+            }
+        }
+    }
+}
+
+//
+// Counting semaphore
+// (limits the number of threads running a section concurrently, the others wait)
+//
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        assert!(permits > 0, "semaphore without permits");
+        Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        SemaphoreGuard { semaphore: self }
+    }
+}
+
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+//
+// Cancellation flag
+// (set by another thread, checked by a long-running task between its steps)
+//
+#[derive(Default)]
+pub struct Cancel {
+    cancelled: AtomicBool,
+}
+
+impl Cancel {
+    pub fn new() -> Cancel {
+        Cancel::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            bail!(ErrorKind::Cancelled);
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn test_thread_pool() {
+        use super::ThreadPool;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let counter = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::new("test", 2);
+            for _ in 0..10 {
+                let counter = Arc::clone(&counter);
+                pool.execute(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        } // all jobs are done when the pool is dropped
+        assert_eq!(counter.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn test_cancel() {
+        use super::Cancel;
+        use crate::errors::ErrorKind;
+
+        let cancel = Cancel::new();
+        assert!(cancel.check().is_ok());
+        cancel.cancel();
+        assert!(cancel.is_cancelled());
+        match cancel.check().unwrap_err().kind() {
+            ErrorKind::Cancelled => (),
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_semaphore() {
+        use super::{spawn_thread, Semaphore};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+
+        let semaphore = Arc::new(Semaphore::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let (semaphore, running, max_running) =
+                    (semaphore.clone(), running.clone(), max_running.clone());
+                spawn_thread(&format!("test-{}", i), move || {
+                    let _guard = semaphore.acquire();
+                    let count = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(count, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_difficulty() {
+        use super::difficulty;
+
+        assert_eq!(difficulty(0x1d00ffff), 1.0);
+        assert_eq!(difficulty(0x1b0404cb), 16307.420938523983);
+        assert!((difficulty(0x207fffff) - 4.656542373906925e-10).abs() < 1e-20);
+    }
+
+    #[test]
+    fn test_chainwork() {
+        use bitcoin::util::hash::BitcoinHash;
+        use bitcoin::util::uint::Uint256;
+
+        use super::HeaderList;
+        use crate::fixtures::headers;
+
+        let headers = headers(&[0; 5], 0x207fffff); // regtest: each header has a work of 2
+        let mut header_list = HeaderList::empty();
+        let ordered = header_list.order(headers.clone());
+        header_list.apply(ordered, headers[4].bitcoin_hash());
+        assert_eq!(header_list.chainwork(), Uint256::from_u64(10).unwrap());
+
+        // the work of the removed headers is subtracted
+        header_list.apply(vec![], headers[2].bitcoin_hash());
+        assert_eq!(header_list.chainwork(), Uint256::from_u64(6).unwrap());
+    }
+
+    #[test]
+    fn test_connects() {
+        use bitcoin::util::hash::BitcoinHash;
+
+        use super::HeaderList;
+        use crate::fixtures::headers;
+
+        let headers = headers(&[0, 1, 2, 3, 4], 0x207fffff);
+        let mut header_list = HeaderList::empty();
+        let ordered = header_list.order(headers[..3].to_vec());
+        assert!(header_list.connects(&ordered[..1]));
+        assert!(!header_list.connects(&ordered[1..]));
+        header_list.apply(ordered, headers[2].bitcoin_hash());
+
+        let new_headers = header_list.order(headers[3..].to_vec());
+        assert!(header_list.connects(&new_headers));
+        assert!(header_list.connects(&[]));
+        // e.g. a concurrent reorg removed the header extended by the new ones
+        header_list.apply(vec![], headers[1].bitcoin_hash());
+        assert!(!header_list.connects(&new_headers));
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        use bitcoin::util::hash::BitcoinHash;
+
+        use super::HeaderList;
+        use crate::fixtures::headers;
+
+        // unordered timestamps (miners' clocks drift)
+        let times = [10, 30, 20, 50, 40, 60, 80, 70, 90, 110, 100, 5, 120];
+        let headers = headers(&times, 0x207fffff);
+        let mut header_list = HeaderList::empty();
+        let ordered = header_list.order(headers.clone());
+        header_list.apply(ordered, headers.last().unwrap().bitcoin_hash());
+
+        assert_eq!(header_list.median_time_past(0), Some(10));
+        assert_eq!(header_list.median_time_past(1), Some(30)); // the upper one of 2 times
+        assert_eq!(header_list.median_time_past(2), Some(20));
+        assert_eq!(header_list.median_time_past(10), Some(60)); // the 11 first blocks
+        assert_eq!(header_list.median_time_past(12), Some(70)); // without the 2 first ones
+        assert_eq!(header_list.median_time_past(13), None);
+    }
+
     #[test]
     fn test_headers() {
         use bitcoin::blockdata::block::BlockHeader;
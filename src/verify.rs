@@ -0,0 +1,190 @@
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::app::App;
+use crate::errors::*;
+use crate::index::{block_key, read_last_indexed, TxRow};
+use crate::store::ReadStore;
+use crate::util::spawn_thread;
+
+// Min delay between the starts of two checks requested by clients
+const VERIFY_INTERVAL: Duration = Duration::from_secs(600);
+// Number of blocks (spread over the chain) whose transactions are looked up in the index
+const SAMPLED_BLOCKS: usize = 10;
+
+//
+// Report of an index consistency check
+//
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub started: u64,  // UNIX time
+    pub duration: f64, // in seconds
+    pub headers: usize,
+    pub last_indexed: Option<String>,
+    pub sampled_blocks: usize,
+    pub sampled_txs: usize,
+    pub errors: Vec<String>,
+}
+
+// Heights of the sampled blocks, evenly spread up to the tip (included)
+fn sample_heights(tip_height: usize, count: usize) -> Vec<usize> {
+    let mut heights: Vec<usize> = (1..=count).map(|i| tip_height * i / count).collect();
+    heights.dedup();
+    heights
+}
+
+// Check that the `B` and `T` rows of a block (and its transactions) are indexed
+fn verify_block(app: &App, blockhash: &Sha256dHash, report: &mut Report) -> Result<()> {
+    let txids = app.daemon().getblocktxids(blockhash)?;
+    let store = app.read_store();
+    if store.get(&block_key(blockhash))?.is_none() {
+        report.errors.push(format!("block {} is not indexed", blockhash));
+    }
+    for txid in &txids {
+        let mut indexed = false;
+        for row in store.scan(&TxRow::filter_full(txid))? {
+            indexed |= TxRow::from_row(&row)?.block_hash[..] == blockhash[..];
+        }
+        if !indexed {
+            let error = format!("transaction {} of block {} is not indexed", txid, blockhash);
+            report.errors.push(error);
+        }
+    }
+    report.sampled_blocks += 1;
+    report.sampled_txs += txids.len();
+    Ok(())
+}
+
+//
+// Check the index of a running indexer (without blocking its updates):
+// - the headers form a chain from the genesis block
+// - the last indexed block (the `L` row) is one of them
+// - the transactions of a few blocks have their `B` and `T` rows
+//
+pub fn verify_index(app: &App) -> Report {
+    let start = Instant::now();
+    let mut report = Report {
+        started: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0),
+        duration: 0.0,
+        headers: 0,
+        last_indexed: None,
+        sampled_blocks: 0,
+        sampled_txs: 0,
+        errors: vec![],
+    };
+    match app.index().check_headers() {
+        Ok(count) => report.headers = count,
+        Err(e) => report.errors.push(e.to_string()),
+    }
+
+    let last_indexed = read_last_indexed(&*app.read_store());
+    match last_indexed {
+        Ok(Some(blockhash)) if app.index().get_header_by_block_hash(blockhash).is_none() => {
+            let error = format!("last indexed block {} is not in the best chain", blockhash);
+            report.errors.push(error)
+        }
+        Ok(Some(_)) => (),
+        Ok(None) => report.errors.push("no last indexed block".to_string()),
+        Err(ref e) => report.errors.push(e.to_string()),
+    }
+    let last_indexed = last_indexed.unwrap_or_default();
+    report.last_indexed = last_indexed.map(|blockhash| blockhash.to_hex());
+
+    let tip_height = app.index().best_header().map(|header| header.height()).unwrap_or(0);
+    for height in sample_heights(tip_height, SAMPLED_BLOCKS) {
+        // the headers may change meanwhile (e.g. during a reorg)
+        let header = match app.index().get_header(height) {
+            Some(header) => header,
+            None => continue,
+        };
+        if let Err(e) = verify_block(app, header.hash(), &mut report) {
+            report.errors.push(format!("failed to check block {}: {}", header.hash(), e));
+        }
+    }
+    report.duration = start.elapsed().as_secs_f64();
+    report
+}
+
+//
+// Runs the checks requested by the clients in the background, one at a time
+//
+#[derive(Default)]
+pub struct Verifier {
+    state: Mutex<VerifierState>,
+}
+
+#[derive(Default)]
+struct VerifierState {
+    running: bool,
+    last_start: Option<Instant>,
+    last_report: Option<Report>,
+}
+
+impl Verifier {
+    /// Starts a check, unless one is running (or the last one started less than
+    /// VERIFY_INTERVAL ago). Returns whether a check is running, and the last report.
+    pub fn start(self: &Arc<Self>, app: &Arc<App>) -> (bool, Option<Report>) {
+        let mut state = self.state.lock().unwrap();
+        let allowed = match state.last_start {
+            Some(instant) => instant.elapsed() >= VERIFY_INTERVAL,
+            None => true,
+        };
+        if !state.running && allowed {
+            state.running = true;
+            state.last_start = Some(Instant::now());
+            let (running, app) = (Running(Arc::clone(self)), Arc::clone(app));
+            spawn_thread("verify", move || {
+                let report = verify_index(&app);
+                if report.errors.is_empty() {
+                    info!("index verified in {:.1}s", report.duration);
+                } else {
+                    warn!("index verification failed: {:?}", report.errors);
+                }
+                running.0.state.lock().unwrap().last_report = Some(report);
+            });
+        }
+        (state.running, state.last_report.clone())
+    }
+}
+
+// Marks the check as over once dropped, even if it panicked (so that a new one can start)
+struct Running(Arc<Verifier>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.running = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sample_heights, Running, Verifier};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_sample_heights() {
+        assert_eq!(sample_heights(100, 4), vec![25, 50, 75, 100]);
+        assert_eq!(sample_heights(2, 4), vec![0, 1, 2]);
+        assert_eq!(sample_heights(0, 4), vec![0]);
+    }
+
+    #[test]
+    fn test_panicked_check() {
+        let verifier = Arc::new(Verifier::default());
+        verifier.state.lock().unwrap().running = true;
+        let running = Running(Arc::clone(&verifier));
+        let check = thread::spawn(move || {
+            let _running = running;
+            panic!("check failed");
+        });
+        assert!(check.join().is_err());
+        assert!(!verifier.state.lock().unwrap().running);
+    }
+}
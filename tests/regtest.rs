@@ -0,0 +1,628 @@
+//
+// End-to-end tests against a regtest bitcoind
+// (requires `bitcoind` and `bitcoin-cli`, run with `cargo test -- --ignored`)
+//
+extern crate addrindexrs;
+#[macro_use]
+extern crate error_chain;
+#[macro_use]
+extern crate serde_json;
+
+use bitcoin::network::constants::Network;
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use serde_json::{from_str, Value};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use addrindexrs::{
+    app::App,
+    cache::BlockTxIDsCache,
+    config::network_daemon_dir,
+    daemon::{CookieGetter, Daemon},
+    errors::*,
+    bulk::index_blk_files,
+    index::{
+        compute_script_hash, init_spend_rows, read_last_indexed, Index, OptionalRows,
+        ScriptHashFilter,
+    },
+    query::Query,
+    rpc::{ErrorDetails, Options, TlsReply, RPC},
+    signal::Waiter,
+    store::{DBStore, ReadStore},
+};
+
+fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+//
+// A regtest bitcoind running in a temporary data directory
+//
+struct Node {
+    dir: PathBuf,
+    rpc_port: u16,
+    process: Child,
+}
+
+impl Node {
+    fn start(args: &[&str]) -> Node {
+        // the tests run in parallel, each with its own node
+        static NODES: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "addrindexrs-regtest-{}-{}",
+            std::process::id(),
+            NODES.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let rpc_port = free_port();
+        let process = Command::new(env::var("BITCOIND").unwrap_or_else(|_| "bitcoind".into()))
+            .arg("-regtest")
+            .arg(format!("-datadir={}", dir.display()))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg("-listen=0")
+            .arg("-txindex")
+            .arg("-fallbackfee=0.0001")
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to run bitcoind");
+        Node {
+            dir,
+            rpc_port,
+            process,
+        }
+    }
+
+    fn cli(&self, args: &[&str]) -> Result<String> {
+        let cli = env::var("BITCOIN_CLI").unwrap_or_else(|_| "bitcoin-cli".into());
+        let output = Command::new(cli)
+            .arg("-regtest")
+            .arg("-rpcwait")
+            .arg(format!("-datadir={}", self.dir.display()))
+            .arg(format!("-rpcport={}", self.rpc_port))
+            .args(args)
+            .output()
+            .chain_err(|| "failed to run bitcoin-cli")?;
+        if !output.status.success() {
+            bail!("{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        }
+        Ok(String::from_utf8(output.stdout).unwrap().trim().to_owned())
+    }
+
+    fn rpc_addr(&self) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], self.rpc_port))
+    }
+
+    // The daemon directory is resolved as for a `--network regtest` config
+    fn daemon(&self, signal: &Waiter) -> Daemon {
+        let daemon_dir = network_daemon_dir(&self.dir, Network::Regtest);
+        let cookie = CookieFile(daemon_dir.join(".cookie"));
+        Daemon::new(
+            &daemon_dir,
+            self.rpc_addr(),
+            Arc::new(cookie),
+            Network::Regtest,
+            signal.clone(),
+            Arc::new(BlockTxIDsCache::new(0, 0)),
+        )
+        .unwrap()
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.cli(&["stop"]);
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct CookieFile(PathBuf);
+
+impl CookieGetter for CookieFile {
+    fn get(&self) -> Result<Vec<u8>> {
+        fs::read(&self.0).chain_err(|| format!("failed to read cookie from {:?}", self.0))
+    }
+}
+
+//
+// A line-based JSONRPC client of the indexer
+//
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Client {
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(addr) {
+                let reader = BufReader::new(stream.try_clone().unwrap());
+                return Client { stream, reader };
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("failed to connect to {}", addr);
+    }
+
+    // Sends a request line, and returns the whole reply
+    fn send(&mut self, request: &str) -> Value {
+        self.stream.write_all((request.to_owned() + "\n").as_bytes()).unwrap();
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        from_str(&line).unwrap()
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Value {
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+        let reply = self.send(&request.to_string());
+        assert!(reply.get("error").is_none(), "{} failed: {}", method, reply);
+        reply["result"].clone()
+    }
+}
+
+fn script_hash(node: &Node, address: &str) -> String {
+    let info: Value = from_str(&node.cli(&["getaddressinfo", address]).unwrap()).unwrap();
+    let script = hex::decode(info["scriptPubKey"].as_str().unwrap()).unwrap();
+    // script hashes are sent in reversed hex (as Electrum does)
+    Sha256dHash::from_slice(&compute_script_hash(&script))
+        .unwrap()
+        .to_hex()
+}
+
+// Indexes the node's chain and mempool, and serves them until the test process exits
+fn serve(node: &Node) -> SocketAddr {
+    let signal = Waiter::start();
+    let daemon = node.daemon(&signal);
+    let store = DBStore::open(&node.dir.join("index"), /*memory_budget=*/ None).unwrap();
+    let filter = ScriptHashFilter::default();
+    let index = Index::load(&store, &daemon, 10, None, false, true, filter)
+        .unwrap()
+        .with_witness_commitments(true)
+        .with_spends(init_spend_rows(&store, true).unwrap());
+    index.update(&store, &signal).unwrap();
+    let app = App::new(store.enable_compaction().unwrap(), index, daemon).unwrap();
+    let filter = ScriptHashFilter::default();
+    let query = Query::new(app, 100, 0, filter, true, 1000, None, /*snapshot_reads=*/ true);
+    query.update_mempool().unwrap();
+
+    let rpc_addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let options = Options {
+        threads: 2,
+        disconnect_on_unknown_method: false,
+        disconnect_on_bad_request: false,
+        instance_name: None,
+        max_subscriptions: 0,
+        max_headers: 2016,
+        rpc_error_codes: false,
+        error_details: ErrorDetails::Message,
+        tls_reply: TlsReply::None,
+        idle_timeout: None,
+    };
+    std::mem::forget(RPC::start(vec![rpc_addr], query, options));
+    rpc_addr
+}
+
+//
+// A node whose wallet address was funded by a confirmed transaction (1.5 BTC, at height 102)
+// and an unconfirmed one (0.5 BTC), indexed and served
+//
+struct Funded {
+    node: Node,
+    address: String,
+    confirmed: String,
+    unconfirmed: String,
+    rpc_addr: SocketAddr,
+}
+
+impl Funded {
+    fn start() -> Funded {
+        let node = Node::start(&[]);
+        let _ = node.cli(&["createwallet", "test"]); // older releases create a default wallet
+        let miner = node.cli(&["getnewaddress"]).unwrap();
+        node.cli(&["generatetoaddress", "101", &miner]).unwrap();
+
+        let address = node.cli(&["getnewaddress"]).unwrap();
+        let confirmed = node.cli(&["sendtoaddress", &address, "1.5"]).unwrap();
+        node.cli(&["generatetoaddress", "1", &miner]).unwrap();
+        let unconfirmed = node.cli(&["sendtoaddress", &address, "0.5"]).unwrap();
+
+        let rpc_addr = serve(&node);
+        Funded {
+            node,
+            address,
+            confirmed,
+            unconfirmed,
+            rpc_addr,
+        }
+    }
+
+    fn client(&self) -> Client {
+        Client::connect(self.rpc_addr)
+    }
+
+    fn script_hash(&self) -> String {
+        script_hash(&self.node, &self.address)
+    }
+
+    // Both funding txids, sorted
+    fn txids(&self) -> Vec<String> {
+        let mut txids = vec![self.confirmed.clone(), self.unconfirmed.clone()];
+        txids.sort();
+        txids
+    }
+}
+
+#[test]
+#[ignore]
+fn test_regtest_requests() {
+    let funded = Funded::start();
+    let mut client = funded.client();
+
+    // malformed requests get an error reply, and the connection is kept
+    let reply = client.send("{not json");
+    assert_eq!((&reply["id"], reply["error"].is_string()), (&Value::Null, true));
+    let reply = client.send(r#"{"id": 7, "method": "server.ping", "params": {}}"#);
+    assert_eq!((&reply["id"], reply["error"].is_string()), (&json!(7), true));
+    assert_eq!(client.call("server.ping", json!([])), Value::Null);
+
+    let config = client.call("server.get_config", json!([]));
+    assert_eq!(config["network"], json!("regtest"));
+    assert_eq!(config["txid_limit"], json!(100));
+    assert_eq!(config["index_outputs"], json!(true));
+    assert!(config["methods"].as_array().unwrap().contains(&json!("server.get_config")));
+}
+
+#[test]
+#[ignore]
+fn test_regtest_headers() {
+    let funded = Funded::start();
+    let node = &funded.node;
+    let mut client = funded.client();
+
+    let tip = client.call("blockchain.headers.subscribe", json!([]));
+    assert_eq!(tip["height"], json!(102));
+    let chain: Value = from_str(&node.cli(&["getblockchaininfo"]).unwrap()).unwrap();
+    let chainwork = client.call("blockchain.headers.get_chainwork", json!([]));
+    assert_eq!(chainwork["chainwork"], chain["chainwork"]);
+    assert_eq!(chainwork["difficulty"], chain["difficulty"]);
+    // fewer headers are returned past the tip
+    let headers = client.call("blockchain.block.headers", json!([100, 5]));
+    assert_eq!(headers["count"], json!(3));
+    assert_eq!(headers["hex"].as_str().unwrap().len(), 3 * 80 * 2);
+    assert_eq!(headers["max"], json!(2016));
+    let tip_hash = node.cli(&["getbestblockhash"]).unwrap();
+    assert_eq!(client.call("blockchain.block.get_height", json!([tip_hash])), json!(102));
+    let block: Value = from_str(&node.cli(&["getblock", &tip_hash]).unwrap()).unwrap();
+    let method = "blockchain.block.get_median_time_past";
+    assert_eq!(client.call(method, json!([102])), block["mediantime"]);
+    let unknown = "00".repeat(32);
+    let method = "blockchain.block.get_headers_by_hash";
+    let by_hash = client.call(method, json!([[tip_hash, unknown]]));
+    assert_eq!(by_hash, json!([{"hex": tip["hex"], "height": 102}, null]));
+    let last_indexed = client.call("blockchain.index.get_last_indexed", json!([]));
+    assert_eq!(last_indexed["height"], json!(102));
+}
+
+#[test]
+#[ignore]
+fn test_regtest_witness_commitment() {
+    let funded = Funded::start();
+    let node = &funded.node;
+    let mut client = funded.client();
+
+    // the tip's coinbase commits to the witnesses of the (SegWit) confirmed transaction
+    let tip_hash = node.cli(&["getbestblockhash"]).unwrap();
+    let block: Value = from_str(&node.cli(&["getblock", &tip_hash, "2"]).unwrap()).unwrap();
+    let commitment = block["tx"][0]["vout"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|output| output["scriptPubKey"]["hex"].as_str())
+        .rev()
+        .find(|script| script.starts_with("6a24aa21a9ed"))
+        .map(|script| json!(script[12..76]));
+    let method = "blockchain.block.get_witness_commitment";
+    let result = client.call(method, json!([102]));
+    assert_eq!(result, json!({"block_hash": tip_hash, "commitment": commitment}));
+}
+
+#[test]
+#[ignore]
+fn test_regtest_history() {
+    let funded = Funded::start();
+    let mut client = funded.client();
+    let script_hash = funded.script_hash();
+
+    let mut history: Vec<String> = client
+        .call("blockchain.scripthash.get_history", json!([script_hash]))
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["tx_hash"].as_str().unwrap().to_owned())
+        .collect();
+    history.sort();
+    assert_eq!(history, funded.txids());
+    let count = client.call("blockchain.scripthash.get_tx_count", json!([script_hash]));
+    assert_eq!(count, json!(2));
+
+    let params = json!([script_hash, "asc", /*verbose=*/ true]);
+    let verbose = client.call("blockchain.scripthash.get_history", params);
+    let deltas: Vec<&Value> = verbose.as_array().unwrap().iter().map(|tx| &tx["delta"]).collect();
+    assert_eq!(deltas, vec![&json!(150_000_000), &json!(50_000_000)]);
+}
+
+#[test]
+#[ignore]
+fn test_regtest_utxos() {
+    let funded = Funded::start();
+    let mut client = funded.client();
+    let script_hash = funded.script_hash();
+
+    let utxos = client.call("blockchain.scripthash.get_utxos", json!([script_hash]));
+    let mut utxo_txids: Vec<&str> = utxos
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|utxo| utxo.as_str().unwrap().split(':').next().unwrap())
+        .collect();
+    utxo_txids.sort();
+    assert_eq!(utxo_txids, funded.txids());
+    let summary = client.call("blockchain.scripthash.get_utxo_summary", json!([script_hash]));
+    assert_eq!(summary, json!({"count": 2, "value": 200_000_000}));
+    let kept = client.call("blockchain.scripthash.get_utxos", json!([script_hash, true, true]));
+    assert_eq!(kept.as_array().unwrap().len(), utxos.as_array().unwrap().len());
+    assert!(kept.as_array().unwrap().iter().all(|utxo| utxo["pending_spend"] == json!(false)));
+    // the funding outputs are spendable, so none of them is excluded
+    let spendable = client.call("blockchain.scripthash.get_utxos", json!([script_hash, false]));
+    assert_eq!(spendable.as_array().unwrap().len(), 2);
+
+    // duplicated script hashes are only queried once
+    let batch = client.call(
+        "blockchain.scripthashes.get_utxos",
+        json!([[script_hash, script_hash]]),
+    );
+    assert_eq!(batch.as_array().unwrap().len(), 2);
+    assert!(batch.as_array().unwrap().iter().all(|item| item["scripthash"] == json!(script_hash)));
+}
+
+#[test]
+#[ignore]
+fn test_regtest_outpoint_script() {
+    let funded = Funded::start();
+    let mut client = funded.client();
+
+    let info: Value =
+        from_str(&funded.node.cli(&["getaddressinfo", &funded.address]).unwrap()).unwrap();
+    let scripts: Vec<Value> = (0..2)
+        .map(|vout| client.call("blockchain.outpoint.get_script", json!([funded.confirmed, vout])))
+        .collect();
+    assert!(scripts.contains(&info["scriptPubKey"]));
+    let missing = client.call("blockchain.outpoint.get_script", json!([funded.confirmed, 99]));
+    assert_eq!(missing, Value::Null);
+}
+
+#[test]
+#[ignore]
+fn test_regtest_values() {
+    let funded = Funded::start();
+    let mut client = funded.client();
+    let script_hash = funded.script_hash();
+
+    let delta = client.call("blockchain.scripthash.get_mempool_delta", json!([script_hash]));
+    assert_eq!(delta["unconfirmed"], json!(50_000_000));
+    // the lifetime totals are confirmed-only
+    let totals = client.call("blockchain.scripthash.get_totals", json!([script_hash]));
+    assert_eq!(totals, json!({"received": 150_000_000, "sent": 0}));
+    let balance = client.call("blockchain.scripthash.get_balance", json!([script_hash]));
+    assert_eq!(balance, json!({"confirmed": 150_000_000, "unconfirmed": 50_000_000}));
+}
+
+#[test]
+#[ignore]
+fn test_regtest_transactions() {
+    let funded = Funded::start();
+    let mut client = funded.client();
+    let script_hash = funded.script_hash();
+    let (confirmed, unconfirmed) = (&funded.confirmed, &funded.unconfirmed);
+
+    let method = "blockchain.transaction.get_confirmations";
+    assert_eq!(client.call(method, json!([confirmed])), json!(1));
+    assert_eq!(client.call(method, json!([unconfirmed])), json!(0));
+    // after the coinbase
+    let tip_hash = funded.node.cli(&["getbestblockhash"]).unwrap();
+    let position = client.call("blockchain.transaction.get_position", json!([confirmed]));
+    assert_eq!(position, json!({"block_hash": tip_hash, "height": 102, "position": 1}));
+
+    let method = "blockchain.scripthashes.get_confirmed_since";
+    let since = client.call(method, json!([[script_hash], 102]));
+    assert_eq!(
+        since,
+        json!([{"scripthash": script_hash, "tx_hash": confirmed, "height": 102}])
+    );
+    assert_eq!(client.call(method, json!([[script_hash], 103])), json!([]));
+
+    let pending = client.call("blockchain.scripthash.get_pending", json!([script_hash]));
+    assert_eq!(pending, json!([]));
+}
+
+#[test]
+#[ignore]
+fn test_regtest_verify() {
+    let funded = Funded::start();
+    let mut client = funded.client();
+
+    // the index check runs in the background, its report is returned once done
+    let report = loop {
+        let verify = client.call("blockchain.index.verify", json!([]));
+        if verify["running"] == json!(false) {
+            break verify["report"].clone();
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    assert_eq!(report["headers"], json!(103));
+    assert_eq!(report["errors"], json!([]));
+}
+
+#[test]
+#[ignore]
+fn test_regtest_activity() {
+    let funded = Funded::start();
+    let mut client = funded.client();
+    let unused = script_hash(&funded.node, &funded.node.cli(&["getnewaddress"]).unwrap());
+
+    let oldest = client.call("blockchain.scripthash.get_oldest_tx", json!([unused]));
+    assert_eq!(oldest, json!({"tx_hash": null, "block_index": null}));
+
+    let method = "blockchain.scripthash.get_activity";
+    let activity = client.call(method, json!([funded.script_hash()]));
+    assert_eq!(activity, json!({"confirmed": true, "mempool": true}));
+    let activity = client.call(method, json!([unused]));
+    assert_eq!(activity, json!({"confirmed": false, "mempool": false}));
+}
+
+#[test]
+#[ignore]
+fn test_regtest_busy() {
+    let funded = Funded::start();
+
+    // the connections beyond the 2 RPC threads are rejected
+    let _first = funded.client();
+    let _second = funded.client();
+    let mut rejected = funded.client();
+    let mut line = String::new();
+    rejected.reader.read_line(&mut line).unwrap();
+    let reply: Value = from_str(&line).unwrap();
+    assert_eq!(reply["id"], Value::Null);
+    assert!(reply["error"].as_str().unwrap().contains("busy"));
+}
+
+// Spends an output to the given address (less a fee), and returns the spending txid
+fn spend(node: &Node, txid: &str, vout: u64, address: &str, value: f64) -> String {
+    let inputs = json!([{"txid": txid, "vout": vout}]).to_string();
+    let outputs = json!({ address: value }).to_string();
+    let tx = node.cli(&["createrawtransaction", &inputs, &outputs]).unwrap();
+    let signed = node.cli(&["signrawtransactionwithwallet", &tx]).unwrap();
+    let signed: Value = from_str(&signed).unwrap();
+    node.cli(&["sendrawtransaction", signed["hex"].as_str().unwrap()]).unwrap()
+}
+
+#[test]
+#[ignore]
+fn test_regtest_spends() {
+    let node = Node::start(&[]);
+    let _ = node.cli(&["createwallet", "test"]);
+    let miner = node.cli(&["getnewaddress"]).unwrap();
+    node.cli(&["generatetoaddress", "101", &miner]).unwrap();
+
+    let address = node.cli(&["getnewaddress"]).unwrap();
+    let funding = [
+        node.cli(&["sendtoaddress", &address, "1.0"]).unwrap(),
+        node.cli(&["sendtoaddress", &address, "0.5"]).unwrap(),
+    ];
+    node.cli(&["generatetoaddress", "1", &miner]).unwrap();
+    let unspent: Value = from_str(
+        &node.cli(&["listunspent", "1", "9999", &json!([address]).to_string()]).unwrap(),
+    )
+    .unwrap();
+    let vout = |txid: &str| {
+        let output = unspent.as_array().unwrap().iter().find(|utxo| utxo["txid"] == json!(txid));
+        output.unwrap()["vout"].as_u64().unwrap()
+    };
+    let mempool_spent = format!("{}:{}", funding[1], vout(&funding[1]));
+    // the first output is spent by a confirmed transaction, the second one in the mempool
+    spend(&node, &funding[0], vout(&funding[0]), &miner, 0.999);
+    node.cli(&["generatetoaddress", "1", &miner]).unwrap();
+    spend(&node, &funding[1], vout(&funding[1]), &miner, 0.499);
+
+    let mut client = Client::connect(serve(&node));
+    let script_hash = script_hash(&node, &address);
+    let utxos = client.call("blockchain.scripthash.get_utxos", json!([script_hash]));
+    assert_eq!(utxos, json!([]));
+    let kept = client.call("blockchain.scripthash.get_utxos", json!([script_hash, true, true]));
+    assert_eq!(kept, json!([{"utxo": mempool_spent, "pending_spend": true}]));
+    // the output spent in the mempool isn't sent yet
+    let totals = client.call("blockchain.scripthash.get_totals", json!([script_hash]));
+    assert_eq!(totals, json!({"received": 150_000_000, "sent": 100_000_000}));
+}
+
+#[test]
+#[ignore]
+fn test_regtest_bulk_import() {
+    let node = Node::start(&["-fastprune"]); // small blk*.dat files
+    let _ = node.cli(&["createwallet", "test"]);
+    let miner = node.cli(&["getnewaddress"]).unwrap();
+    node.cli(&["generatetoaddress", "1000", &miner]).unwrap();
+
+    let signal = Waiter::start();
+    let daemon = node.daemon(&signal);
+    assert!(daemon.list_blk_files().unwrap().len() > 1);
+
+    // the blocks are written in the files' order, and the 'L' row is the tip
+    let store = DBStore::open(&node.dir.join("index"), /*memory_budget=*/ None).unwrap();
+    let filter = ScriptHashFilter::default();
+    let rows = OptionalRows::default();
+    let store = index_blk_files(&daemon, 4, &signal, store, filter, rows, true).unwrap();
+    let tip = node.cli(&["getbestblockhash"]).unwrap();
+    assert_eq!(read_last_indexed(&store).unwrap().unwrap().to_hex(), tip);
+}
+
+#[test]
+#[ignore]
+fn test_regtest_import_modes() {
+    let node = Node::start(&["-fastprune"]);
+    let _ = node.cli(&["createwallet", "test"]);
+    let miner = node.cli(&["getnewaddress"]).unwrap();
+    node.cli(&["generatetoaddress", "101", &miner]).unwrap();
+    for _ in 0..10 {
+        let address = node.cli(&["getnewaddress"]).unwrap();
+        node.cli(&["sendtoaddress", &address, "0.5"]).unwrap();
+        node.cli(&["generatetoaddress", "1", &miner]).unwrap();
+    }
+
+    let signal = Waiter::start();
+    let daemon = node.daemon(&signal);
+    // both imports are finished as `initial_sync` does: the headers are reloaded and
+    // checkpointed at the imported tip
+    let finish = |store: &DBStore| {
+        let filter = ScriptHashFilter::default();
+        let index = Index::load(store, &daemon, 10, None, false, false, filter).unwrap();
+        index.write_checkpoint(store).unwrap();
+    };
+
+    let store = DBStore::open(&node.dir.join("jsonrpc"), /*memory_budget=*/ None).unwrap();
+    let filter = ScriptHashFilter::default();
+    let index = Index::load(&store, &daemon, 10, None, false, false, filter).unwrap();
+    index.update(&store, &signal).unwrap();
+    finish(&store);
+    let jsonrpc = store;
+
+    let store = DBStore::open(&node.dir.join("bulk"), /*memory_budget=*/ None).unwrap();
+    let filter = ScriptHashFilter::default();
+    let rows = OptionalRows::default();
+    let bulk = index_blk_files(&daemon, 4, &signal, store, filter, rows, true).unwrap();
+    finish(&bulk);
+
+    let tip = node.cli(&["getbestblockhash"]).unwrap();
+    assert_eq!(read_last_indexed(&jsonrpc).unwrap().unwrap().to_hex(), tip);
+    // rows of the inputs, outputs, transactions, blocks, last indexed block and checkpoint
+    for prefix in [b"I", b"O", b"T", b"B", b"L", b"C"] {
+        let rows = |store: &DBStore| -> Vec<(Vec<u8>, Vec<u8>)> {
+            store.scan(prefix).unwrap().into_iter().map(|row| row.into_pair()).collect()
+        };
+        let expected = rows(&jsonrpc);
+        assert!(!expected.is_empty());
+        assert_eq!(rows(&bulk), expected, "{} rows differ", prefix[0] as char);
+    }
+}